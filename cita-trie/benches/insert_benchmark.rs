@@ -49,6 +49,38 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
             }
         });
     });
+
+    // sequential inserts and insert_batch over the same data, both into a new
+    // trie and including the root, so they are comparable. Run with and
+    // without `--features parallel` to compare the root hashing too
+    for n in [1000, 10000] {
+        let (keys, values) = random_data(n);
+        let items: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().zip(values).collect();
+
+        c.bench_function(&format!("cita-trie sequential insert {}k", n / 1000), |b| {
+            b.iter(|| {
+                let mut trie = PatriciaTrie::new(
+                    Arc::new(MemoryDB::new(false)),
+                    Arc::new(HasherKeccak::new()),
+                );
+                for (key, value) in items.iter() {
+                    trie.insert(key.clone(), value.clone()).unwrap()
+                }
+                trie.root().unwrap()
+            })
+        });
+
+        c.bench_function(&format!("cita-trie insert_batch {}k", n / 1000), |b| {
+            b.iter(|| {
+                let mut trie = PatriciaTrie::new(
+                    Arc::new(MemoryDB::new(false)),
+                    Arc::new(HasherKeccak::new()),
+                );
+                trie.insert_batch(items.clone()).unwrap();
+                trie.root().unwrap()
+            })
+        });
+    }
 }
 
 fn random_data(n: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
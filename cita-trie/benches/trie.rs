@@ -102,6 +102,20 @@ fn insert_worse_case_benchmark(c: &mut Criterion) {
             }
         });
     });
+
+    // run with `--features parallel` to compare against the sequential hashing
+    c.bench_function("root 10k", |b| {
+        let (keys, values) = random_data(10000);
+        let items: Vec<(Vec<u8>, Vec<u8>)> = keys.into_iter().zip(values).collect();
+        b.iter(|| {
+            let mut trie = PatriciaTrie::new(
+                Arc::new(MemoryDB::new(false)),
+                Arc::new(HasherKeccak::new()),
+            );
+            trie.insert_batch(items.clone()).unwrap();
+            trie.root().unwrap()
+        });
+    });
 }
 
 fn random_data(n: usize) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
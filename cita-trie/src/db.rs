@@ -2,13 +2,14 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::sync::Arc;
 
-use serde::{Serialize, Deserialize};
-use serde_with::serde_as;
-use serde_with::hex::Hex;
 use parking_lot::RwLock;
+use rlp::{Rlp, RlpStream};
 
 use crate::errors::MemDBError;
 
+/// Key and value of every node, as stored in the database.
+pub type Nodes = Vec<(Vec<u8>, Vec<u8>)>;
+
 /// "DB" defines the "trait" of trie and database interaction.
 /// You should first write the data to the cache and write the data
 /// to the database in bulk after the end of a set of operations.
@@ -59,13 +60,6 @@ pub struct MemoryDB {
     storage: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
 }
 
-#[serde_as]
-#[derive(Serialize, Deserialize)]
-struct MemoryData {
-    #[serde_as(as = "HashMap<Hex, Hex>")]
-    data: HashMap<Vec<u8>, Vec<u8>>,
-}
-
 impl MemoryDB {
     pub fn new<A>(args: A) -> MemoryDB
         where A: Into<MemoryDB>
@@ -73,29 +67,59 @@ impl MemoryDB {
         args.into()
     }
 
+    /// Encodes every stored node as an RLP list of `[key, value]` pairs,
+    /// sorted by key.
     pub fn serialize(&self) -> Vec<u8> {
-        let storage = self.storage.read().clone();
-        let data = MemoryData { data: storage };
-        serde_json::to_vec(&data).unwrap()
+        let mut nodes: Vec<(Vec<u8>, Vec<u8>)> = self
+            .storage
+            .read()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        nodes.sort();
+        MemoryDB::serialize_nodes(&nodes)
+    }
+
+    /// Encodes the given nodes in the same format as `serialize`, keeping their order.
+    pub fn serialize_nodes(nodes: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(nodes.len());
+        for (key, value) in nodes {
+            stream.begin_list(2);
+            stream.append(key);
+            stream.append(value);
+        }
+        stream.out().to_vec()
+    }
+
+    /// Decodes the nodes encoded by `serialize` or `serialize_nodes`.
+    pub fn deserialize_nodes(data: &[u8]) -> Result<Nodes, MemDBError> {
+        let r = Rlp::new(data);
+        let mut nodes = Vec::with_capacity(r.item_count()?);
+        for item in r.iter() {
+            if item.item_count()? != 2 {
+                return Err(MemDBError::Decoder(rlp::DecoderError::RlpIncorrectListLen));
+            }
+            nodes.push((item.val_at(0)?, item.val_at(1)?));
+        }
+        Ok(nodes)
     }
-}
 
-impl From<bool> for MemoryDB {
-    fn from(light: bool) -> MemoryDB {
-        MemoryDB {
+    /// Builds a MemoryDB from the nodes encoded by `serialize` or `serialize_nodes`.
+    pub fn deserialize(light: bool, data: &[u8]) -> Result<MemoryDB, MemDBError> {
+        let storage: HashMap<Vec<u8>, Vec<u8>> =
+            MemoryDB::deserialize_nodes(data)?.into_iter().collect();
+        Ok(MemoryDB {
             light,
-            storage: Arc::new(RwLock::new(HashMap::new())),
-        }
+            storage: Arc::new(RwLock::new(storage)),
+        })
     }
 }
 
-impl From<(bool, Vec<u8>)> for MemoryDB {
-    fn from((light, storage): (bool, Vec<u8>)) -> MemoryDB {
-        let data: MemoryData = serde_json::from_slice(storage.as_slice()).unwrap();
-        let storage = data.data;
+impl From<bool> for MemoryDB {
+    fn from(light: bool) -> MemoryDB {
         MemoryDB {
             light,
-            storage: Arc::new(RwLock::new(storage)),
+            storage: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 }
@@ -165,6 +189,24 @@ mod tests {
         assert!(contains)
     }
 
+    #[test]
+    fn test_memdb_serialize() {
+        let memdb = MemoryDB::new(true);
+        memdb.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        memdb.insert(b"test1".to_vec(), b"test1".to_vec()).unwrap();
+
+        let restored = MemoryDB::deserialize(true, &memdb.serialize()).unwrap();
+        assert_eq!(restored.get(b"test").unwrap().unwrap(), b"test");
+        assert_eq!(restored.get(b"test1").unwrap().unwrap(), b"test1");
+        assert_eq!(restored.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_memdb_deserialize_invalid() {
+        assert!(MemoryDB::deserialize(true, b"not rlp").is_err());
+        assert!(MemoryDB::deserialize(true, &[0xc3, 0x82, 0x01, 0x02]).is_err());
+    }
+
     #[test]
     fn test_memdb_remove() {
         let memdb = MemoryDB::new(true);
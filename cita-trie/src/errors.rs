@@ -34,12 +34,23 @@ impl From<DecoderError> for TrieError {
 }
 
 #[derive(Debug)]
-pub enum MemDBError {}
+pub enum MemDBError {
+    Decoder(DecoderError),
+}
 
 impl Error for MemDBError {}
 
 impl fmt::Display for MemDBError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "error")
+        let printable = match *self {
+            MemDBError::Decoder(ref err) => format!("memory db error: {:?}", err),
+        };
+        write!(f, "{}", printable)
+    }
+}
+
+impl From<DecoderError> for MemDBError {
+    fn from(error: DecoderError) -> Self {
+        MemDBError::Decoder(error)
     }
 }
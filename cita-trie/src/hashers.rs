@@ -0,0 +1,116 @@
+//! `Hasher` implementations besides the keccak one of the `hasher` crate, for
+//! devices with hardware support for other hash functions. All of them produce
+//! 32 bytes digests, like `HasherKeccak`.
+
+use blake2::{Blake2s256, Digest};
+use hasher::Hasher;
+use sha2::Sha256;
+
+#[derive(Default, Debug, Clone)]
+pub struct HasherSha256;
+
+impl HasherSha256 {
+    pub fn new() -> Self {
+        HasherSha256
+    }
+}
+
+impl Hasher for HasherSha256 {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct HasherBlake2s;
+
+impl HasherBlake2s {
+    pub fn new() -> Self {
+        HasherBlake2s
+    }
+}
+
+impl Hasher for HasherBlake2s {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        Blake2s256::digest(data).to_vec()
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct HasherBlake3;
+
+impl HasherBlake3 {
+    pub fn new() -> Self {
+        HasherBlake3
+    }
+}
+
+impl Hasher for HasherBlake3 {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        blake3::hash(data).as_bytes().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hasher::Hasher;
+
+    use super::{HasherBlake2s, HasherBlake3, HasherSha256};
+    use crate::db::MemoryDB;
+    use crate::trie::{NodeHasher, PatriciaTrie, Trie};
+
+    #[test]
+    fn test_empty_digests() {
+        assert_eq!(
+            hex::encode(HasherSha256::new().digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(HasherBlake2s::new().digest(b"")),
+            "69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9"
+        );
+        assert_eq!(
+            hex::encode(HasherBlake3::new().digest(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+    }
+
+    fn check_trie<H: NodeHasher>(hasher: H) {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let hasher = Arc::new(hasher);
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        for i in 0..100u32 {
+            trie.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec())
+                .unwrap();
+        }
+        let root = trie.root().unwrap();
+        assert_eq!(root.len(), H::LENGTH);
+
+        let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
+        for i in 0..100u32 {
+            assert_eq!(
+                trie.get(&i.to_be_bytes()).unwrap(),
+                Some(i.to_le_bytes().to_vec())
+            );
+        }
+
+        let proof = trie.get_proof(&7u32.to_be_bytes()).unwrap();
+        let value = trie.verify_proof(root, &7u32.to_be_bytes(), proof).unwrap();
+        assert_eq!(value, Some(7u32.to_le_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_trie_with_hashers() {
+        check_trie(HasherSha256::new());
+        check_trie(HasherBlake2s::new());
+        check_trie(HasherBlake3::new());
+    }
+}
@@ -42,8 +42,10 @@ mod tests;
 
 mod db;
 mod errors;
+mod hashers;
 mod trie;
 
-pub use db::{MemoryDB, DB};
+pub use db::{MemoryDB, Nodes, DB};
 pub use errors::{MemDBError, TrieError};
-pub use trie::{PatriciaTrie, Trie};
+pub use hashers::{HasherBlake2s, HasherBlake3, HasherSha256};
+pub use trie::{NodeHasher, PatriciaTrie, Trie, TrieChange};
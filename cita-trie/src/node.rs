@@ -1,42 +1,40 @@
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::sync::Arc;
+
+use parking_lot::RwLock;
 
 use crate::nibbles::Nibbles;
 
 #[derive(Debug, Clone)]
 pub enum Node {
     Empty,
-    Leaf(Rc<RefCell<LeafNode>>),
-    Extension(Rc<RefCell<ExtensionNode>>),
-    Branch(Rc<RefCell<BranchNode>>),
-    Hash(Rc<RefCell<HashNode>>),
+    Leaf(Arc<RwLock<LeafNode>>),
+    Extension(Arc<RwLock<ExtensionNode>>),
+    Branch(Arc<RwLock<BranchNode>>),
+    Hash(Arc<RwLock<HashNode>>),
 }
 
 impl Node {
     pub fn from_leaf(key: Nibbles, value: Vec<u8>) -> Self {
-        let leaf = Rc::new(RefCell::new(LeafNode { key, value }));
+        let leaf = Arc::new(RwLock::new(LeafNode { key, value }));
         Node::Leaf(leaf)
     }
 
     pub fn from_branch(children: [Node; 16], value: Option<Vec<u8>>) -> Self {
-        let branch = Rc::new(RefCell::new(BranchNode { children, value }));
+        let branch = Arc::new(RwLock::new(BranchNode { children, value }));
         Node::Branch(branch)
     }
 
     pub fn from_extension(prefix: Nibbles, node: Node) -> Self {
-        let ext = Rc::new(RefCell::new(ExtensionNode { prefix, node }));
+        let ext = Arc::new(RwLock::new(ExtensionNode { prefix, node }));
         Node::Extension(ext)
     }
 
     pub fn from_hash(hash: Vec<u8>) -> Self {
-        let hash_node = Rc::new(RefCell::new(HashNode { hash }));
+        let hash_node = Arc::new(RwLock::new(HashNode { hash }));
         Node::Hash(hash_node)
     }
 }
 
-unsafe impl Send for Node {}
-unsafe impl Sync for Node {}
-
 #[derive(Debug)]
 pub struct LeafNode {
     pub key: Nibbles,
@@ -54,7 +52,7 @@ impl BranchNode {
         if i == 16 {
             match n {
                 Node::Leaf(leaf) => {
-                    self.value = Some(leaf.borrow().value.clone());
+                    self.value = Some(leaf.read().value.clone());
                 }
                 _ => panic!("The n must be leaf node"),
             }
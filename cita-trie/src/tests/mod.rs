@@ -4,7 +4,7 @@ mod trie_tests {
     use rand::Rng;
     use std::sync::Arc;
 
-    use hasher::HasherKeccak;
+    use hasher::{Hasher, HasherKeccak};
 
     use crate::db::MemoryDB;
     use crate::trie::{PatriciaTrie, Trie};
@@ -663,4 +663,298 @@ mod trie_tests {
         let value = trie.verify_proof(root, b"k", proof).unwrap();
         assert_eq!(value, Some(b"v".to_vec()));
     }
+
+    #[test]
+    fn test_export_deterministic() {
+        let data: Vec<(&[u8], &[u8])> = vec![
+            (b"do", b"verb"),
+            (b"horse", b"stallion"),
+            (b"doge", b"coin"),
+            (b"dog", b"puppy"),
+        ];
+
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for (k, v) in data.iter() {
+            trie.insert(k.to_vec(), v.to_vec()).unwrap();
+        }
+        // leaves stale nodes behind in the non-light db
+        trie.insert(b"dogglesworth".to_vec(), b"cat".to_vec())
+            .unwrap();
+        trie.root().unwrap();
+        trie.remove(b"dogglesworth").unwrap();
+        let root1 = trie.root().unwrap();
+        let export1 = trie.reachable_nodes().unwrap();
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for (k, v) in data.iter().rev() {
+            trie.insert(k.to_vec(), v.to_vec()).unwrap();
+        }
+        let root2 = trie.root().unwrap();
+        let export2 = trie.reachable_nodes().unwrap();
+
+        assert_eq!(root1, root2);
+        assert_eq!(export1, export2);
+    }
+
+    #[test]
+    fn test_from_exported() {
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        trie.insert(b"doe".to_vec(), b"reindeer".to_vec()).unwrap();
+        trie.insert(b"dog".to_vec(), b"puppy".to_vec()).unwrap();
+        trie.insert(b"dogglesworth".to_vec(), b"cat".to_vec())
+            .unwrap();
+        let root = trie.root().unwrap();
+        let nodes = trie.reachable_nodes().unwrap();
+
+        let imported =
+            PatriciaTrie::from_exported(Arc::new(HasherKeccak::new()), &root, nodes.clone())
+                .unwrap();
+        assert_eq!(imported.get(b"dog").unwrap(), Some(b"puppy".to_vec()));
+
+        // extra node
+        let hasher = HasherKeccak::new();
+        let extra = rlp::encode_list::<Vec<u8>, _>(&[b"extra".to_vec(), b"node".to_vec()]).to_vec();
+        let mut with_extra = nodes.clone();
+        with_extra.push((hasher.digest(&extra), extra));
+        assert!(
+            PatriciaTrie::from_exported(Arc::new(HasherKeccak::new()), &root, with_extra).is_err()
+        );
+
+        // reordered nodes
+        let mut reordered = nodes.clone();
+        reordered.reverse();
+        assert!(
+            PatriciaTrie::from_exported(Arc::new(HasherKeccak::new()), &root, reordered).is_err()
+        );
+
+        // node whose key is not its hash
+        let mut tampered = nodes.clone();
+        tampered[0].1.push(0);
+        assert!(
+            PatriciaTrie::from_exported(Arc::new(HasherKeccak::new()), &root, tampered).is_err()
+        );
+
+        // missing node
+        let missing = nodes[..nodes.len() - 1].to_vec();
+        assert!(
+            PatriciaTrie::from_exported(Arc::new(HasherKeccak::new()), &root, missing).is_err()
+        );
+    }
+
+    #[test]
+    fn test_multi_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        let mut rng = rand::thread_rng();
+        let mut keys = vec![];
+        for _ in 0..100 {
+            let random_bytes: Vec<u8> = (0..rng.gen_range(2, 30))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(random_bytes.to_vec(), random_bytes.clone())
+                .unwrap();
+            keys.push(random_bytes);
+        }
+        let root = trie.root().unwrap();
+
+        let mut queried: Vec<Vec<u8>> = keys[..10].to_vec();
+        queried.push(b"not-exist".to_vec());
+        queried.push(vec![]);
+        let proof = trie.get_multi_proof(&queried).unwrap();
+
+        // shared nodes are included once
+        let single_proofs_len: usize = queried
+            .iter()
+            .map(|k| trie.get_proof(k).unwrap().len())
+            .sum();
+        assert!(proof.len() < single_proofs_len);
+
+        let values = PatriciaTrie::verify_multi_proof(
+            Arc::new(HasherKeccak::new()),
+            &root,
+            &queried,
+            proof.clone(),
+        )
+        .unwrap();
+        for (k, v) in queried.iter().zip(values) {
+            assert_eq!(v, trie.get(k).unwrap());
+        }
+
+        // every node is needed to reach some key
+        for i in 0..proof.len() {
+            let mut missing = proof.clone();
+            missing.remove(i);
+            assert!(PatriciaTrie::verify_multi_proof(
+                Arc::new(HasherKeccak::new()),
+                &root,
+                &queried,
+                missing,
+            )
+            .is_err());
+        }
+
+        // wrong root
+        assert!(PatriciaTrie::verify_multi_proof(
+            Arc::new(HasherKeccak::new()),
+            &[0u8; 32],
+            &queried,
+            proof,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_range_proof() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        for device in 0u8..4 {
+            for timestamp in 0u64..40 {
+                let mut key = vec![device];
+                key.extend_from_slice(&(timestamp * 3).to_be_bytes());
+                trie.insert(key, timestamp.to_be_bytes().to_vec()).unwrap();
+            }
+        }
+        let root = trie.root().unwrap();
+
+        let mut start = vec![2u8];
+        start.extend_from_slice(&10u64.to_be_bytes());
+        let mut end = vec![2u8];
+        end.extend_from_slice(&60u64.to_be_bytes());
+        let proof = trie.get_range_proof(&start, &end).unwrap();
+
+        let items = PatriciaTrie::verify_range_proof(
+            Arc::new(HasherKeccak::new()),
+            &root,
+            &start,
+            &end,
+            proof.clone(),
+        )
+        .unwrap();
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = trie
+            .iter()
+            .filter(|(k, _)| *k >= start && *k <= end)
+            .collect();
+        assert_eq!(items.len(), 17);
+        assert_eq!(items, expected);
+
+        // dropping any node could hide part of the range
+        for i in 0..proof.len() {
+            let mut missing = proof.clone();
+            missing.remove(i);
+            assert!(PatriciaTrie::verify_range_proof(
+                Arc::new(HasherKeccak::new()),
+                &root,
+                &start,
+                &end,
+                missing,
+            )
+            .is_err());
+        }
+
+        // a proof for a smaller range does not cover a larger one
+        let mut wider_end = vec![3u8];
+        wider_end.extend_from_slice(&0u64.to_be_bytes());
+        assert!(PatriciaTrie::verify_range_proof(
+            Arc::new(HasherKeccak::new()),
+            &root,
+            &start,
+            &wider_end,
+            proof,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_range_proof_empty_trie() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        let root = trie.root().unwrap();
+        let proof = trie.get_range_proof(b"a", b"z").unwrap();
+        let items = PatriciaTrie::verify_range_proof(
+            Arc::new(HasherKeccak::new()),
+            &root,
+            b"a",
+            b"z",
+            proof,
+        )
+        .unwrap();
+        assert!(items.is_empty());
+    }
+
+    #[test]
+    fn test_diff() {
+        use std::collections::BTreeMap;
+
+        use crate::trie::TrieChange;
+
+        // not light, so the nodes of the old root stay in the db
+        let memdb = Arc::new(MemoryDB::new(false));
+        let hasher = Arc::new(HasherKeccak::new());
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
+        let mut rng = rand::thread_rng();
+        let mut old = BTreeMap::new();
+        for _ in 0..200 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 8))
+                .map(|_| rng.gen_range(0, 8) as u8 * 0x21)
+                .collect();
+            let value: Vec<u8> = (0..rng.gen_range(1, 40))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            trie.insert(key.clone(), value.clone()).unwrap();
+            old.insert(key, value);
+        }
+        let root_a = trie.root().unwrap();
+
+        let mut new = old.clone();
+        let keys: Vec<Vec<u8>> = old.keys().cloned().collect();
+        for key in keys.iter().step_by(7) {
+            trie.remove(key).unwrap();
+            new.remove(key);
+        }
+        for key in keys.iter().skip(3).step_by(11) {
+            trie.insert(key.clone(), b"modified".to_vec()).unwrap();
+            new.insert(key.clone(), b"modified".to_vec());
+        }
+        for i in 0..20u8 {
+            let key = vec![0xff, i];
+            trie.insert(key.clone(), vec![i]).unwrap();
+            new.insert(key, vec![i]);
+        }
+        let root_b = trie.root().unwrap();
+
+        let mut expected = vec![];
+        let all_keys: std::collections::BTreeSet<&Vec<u8>> = old.keys().chain(new.keys()).collect();
+        for key in all_keys {
+            match (old.get(key), new.get(key)) {
+                (Some(o), Some(n)) if o != n => {
+                    expected.push(TrieChange::Modified(key.clone(), o.clone(), n.clone()))
+                }
+                (Some(o), None) => expected.push(TrieChange::Removed(key.clone(), o.clone())),
+                (None, Some(n)) => expected.push(TrieChange::Added(key.clone(), n.clone())),
+                _ => {}
+            }
+        }
+
+        let trie_a = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root_a).unwrap();
+        assert_eq!(trie_a.diff(&root_b).unwrap(), expected);
+        assert!(trie_a.diff(&root_a).unwrap().is_empty());
+
+        // the reverse diff swaps added and removed keys
+        let reversed: Vec<TrieChange> = expected
+            .into_iter()
+            .map(|change| match change {
+                TrieChange::Added(k, v) => TrieChange::Removed(k, v),
+                TrieChange::Removed(k, v) => TrieChange::Added(k, v),
+                TrieChange::Modified(k, o, n) => TrieChange::Modified(k, n, o),
+            })
+            .collect();
+        let trie_b = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root_b).unwrap();
+        assert_eq!(trie_b.diff(&root_a).unwrap(), reversed);
+
+        // unknown root
+        assert!(trie_a.diff(&[0u8; 32]).is_err());
+    }
 }
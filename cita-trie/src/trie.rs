@@ -1,18 +1,32 @@
-use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use std::rc::Rc;
 use std::sync::Arc;
 
-use hasher::{Hasher, HasherKeccak};
+use hasher::Hasher;
+use parking_lot::{Mutex, RwLock};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use rlp::{Prototype, Rlp, RlpStream};
 
-use crate::db::{MemoryDB, DB};
+use crate::db::{MemoryDB, Nodes, DB};
 use crate::errors::TrieError;
 use crate::nibbles::Nibbles;
 use crate::node::{empty_children, BranchNode, Node};
 
 pub type TrieResult<T> = Result<T, TrieError>;
 
+/// Bound on the hasher of the methods that encode and hash nodes. With the
+/// `parallel` feature the children of a branch are hashed on the rayon thread
+/// pool, so the hasher must also be `Send + Sync`.
+#[cfg(feature = "parallel")]
+pub trait NodeHasher: Hasher + Send + Sync {}
+#[cfg(feature = "parallel")]
+impl<H: Hasher + Send + Sync> NodeHasher for H {}
+
+#[cfg(not(feature = "parallel"))]
+pub trait NodeHasher: Hasher {}
+#[cfg(not(feature = "parallel"))]
+impl<H: Hasher> NodeHasher for H {}
+
 pub trait Trie<D: DB, H: Hasher> {
     /// Returns the value for key stored in the trie.
     fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>>;
@@ -48,7 +62,18 @@ pub trait Trie<D: DB, H: Hasher> {
     ) -> TrieResult<Option<Vec<u8>>>;
 }
 
-#[derive(Debug, Clone)]
+/// A difference between two tries, as returned by `PatriciaTrie::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrieChange {
+    /// Key only present in the new trie, with its value.
+    Added(Vec<u8>, Vec<u8>),
+    /// Key only present in the old trie, with its value.
+    Removed(Vec<u8>, Vec<u8>),
+    /// Key present in both tries, with its old and new value.
+    Modified(Vec<u8>, Vec<u8>, Vec<u8>),
+}
+
+#[derive(Debug)]
 pub struct PatriciaTrie<D, H>
 where
     D: DB,
@@ -61,13 +86,31 @@ where
     hasher: Arc<H>,
     backup_db: Option<Arc<D>>,
 
-    cache: RefCell<HashMap<Vec<u8>, Vec<u8>>>,
-    passing_keys: RefCell<HashSet<Vec<u8>>>,
-    gen_keys: RefCell<HashSet<Vec<u8>>>,
+    cache: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+    passing_keys: Mutex<HashSet<Vec<u8>>>,
+    gen_keys: Mutex<HashSet<Vec<u8>>>,
 }
 
-unsafe impl Sync for PatriciaTrie<MemoryDB, HasherKeccak> {}
-unsafe impl Send for PatriciaTrie<MemoryDB, HasherKeccak> {}
+impl<D, H> Clone for PatriciaTrie<D, H>
+where
+    D: DB,
+    H: Hasher,
+{
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            root_hash: self.root_hash.clone(),
+
+            db: Arc::clone(&self.db),
+            hasher: Arc::clone(&self.hasher),
+            backup_db: self.backup_db.clone(),
+
+            cache: Mutex::new(self.cache.lock().clone()),
+            passing_keys: Mutex::new(self.passing_keys.lock().clone()),
+            gen_keys: Mutex::new(self.gen_keys.lock().clone()),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 enum TraceStatus {
@@ -119,7 +162,7 @@ where
 impl<'a, D, H> Iterator for TrieIterator<'a, D, H>
 where
     D: DB,
-    H: Hasher,
+    H: NodeHasher,
 {
     type Item = (Vec<u8>, Vec<u8>);
 
@@ -134,12 +177,12 @@ where
                         match *node {
                             Node::Leaf(ref leaf) => {
                                 let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - leaf.borrow().key.len());
+                                self.nibble.truncate(cur_len - leaf.read().key.len());
                             }
 
                             Node::Extension(ref ext) => {
                                 let cur_len = self.nibble.len();
-                                self.nibble.truncate(cur_len - ext.borrow().prefix.len());
+                                self.nibble.truncate(cur_len - ext.read().prefix.len());
                             }
 
                             Node::Branch(_) => {
@@ -151,17 +194,17 @@ where
                     }
 
                     (TraceStatus::Doing, Node::Extension(ref ext)) => {
-                        self.nibble.extend(&ext.borrow().prefix);
-                        self.nodes.push((ext.borrow().node.clone()).into());
+                        self.nibble.extend(&ext.read().prefix);
+                        self.nodes.push((ext.read().node.clone()).into());
                     }
 
                     (TraceStatus::Doing, Node::Leaf(ref leaf)) => {
-                        self.nibble.extend(&leaf.borrow().key);
-                        return Some((self.nibble.encode_raw().0, leaf.borrow().value.clone()));
+                        self.nibble.extend(&leaf.read().key);
+                        return Some((self.nibble.encode_raw().0, leaf.read().value.clone()));
                     }
 
                     (TraceStatus::Doing, Node::Branch(ref branch)) => {
-                        let value = branch.borrow().value.clone();
+                        let value = branch.read().value.clone();
                         if let Some(data) = value {
                             return Some((self.nibble.encode_raw().0, data));
                         } else {
@@ -170,7 +213,7 @@ where
                     }
 
                     (TraceStatus::Doing, Node::Hash(ref hash_node)) => {
-                        if let Ok(n) = self.trie.recover_from_db(&hash_node.borrow().hash.clone()) {
+                        if let Ok(n) = self.trie.recover_from_db(&hash_node.read().hash.clone()) {
                             self.nodes.pop();
                             self.nodes.push(n.into());
                         } else {
@@ -187,7 +230,7 @@ where
                             self.nibble.push(i);
                         }
                         self.nodes
-                            .push((branch.borrow().children[i as usize].clone()).into());
+                            .push((branch.read().children[i as usize].clone()).into());
                     }
 
                     (_, Node::Empty) => {
@@ -205,9 +248,41 @@ where
 impl<D, H> PatriciaTrie<D, H>
 where
     D: DB,
-    H: Hasher,
+    H: NodeHasher,
 {
-    pub fn iter(&self) -> TrieIterator<D, H> {
+    /// Inserts many pairs at once. The pairs are sorted by key first, and on an
+    /// empty trie the nodes are then built bottom-up in a single pass instead of
+    /// being rewritten by every insert; otherwise they are inserted in key order.
+    /// As with `insert`, an empty value removes the key, and when a key is given
+    /// several times the last value wins.
+    pub fn insert_batch(&mut self, mut items: Vec<(Vec<u8>, Vec<u8>)>) -> TrieResult<()> {
+        // stable, so the pairs of a duplicated key keep their order
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut unique: Vec<(Vec<u8>, Vec<u8>)> = Vec::with_capacity(items.len());
+        for item in items {
+            match unique.last_mut() {
+                Some(last) if last.0 == item.0 => *last = item,
+                _ => unique.push(item),
+            }
+        }
+
+        if let Node::Empty = self.root {
+            let leaves: Vec<(Nibbles, Vec<u8>)> = unique
+                .into_iter()
+                .filter(|(_, value)| !value.is_empty())
+                .map(|(key, value)| (Nibbles::from_raw(key, true), value))
+                .collect();
+            self.root = Self::build_sorted(&leaves, 0);
+            return Ok(());
+        }
+
+        for (key, value) in unique {
+            self.insert(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> TrieIterator<'_, D, H> {
         let nodes = vec![self.root.clone().into()];
         TrieIterator {
             trie: self,
@@ -215,14 +290,140 @@ where
             nodes,
         }
     }
+
+    /// Returns an iterator over the pairs whose key is greater than or equal to
+    /// `start`, in key order. Subtrees before `start` are skipped, not walked.
+    pub fn iter_from(&self, start: &[u8]) -> TrieResult<TrieIterator<'_, D, H>> {
+        let mut nodes: Vec<TraceNode> = vec![];
+        let mut nibble = Nibbles::from_raw(vec![], false);
+        let mut partial = Nibbles::from_raw(start.to_vec(), false);
+        let mut node = self.root.clone();
+
+        // Builds the stack the iterator would have right before yielding the
+        // first key >= start: nodes on the path to start are resumed past the
+        // children that come before it.
+        loop {
+            match node.clone() {
+                Node::Empty => break,
+                Node::Hash(hash_node) => {
+                    node = self.recover_from_db(&hash_node.read().hash)?;
+                }
+                Node::Leaf(leaf) => {
+                    let borrow_leaf = leaf.read();
+                    // without the leaf terminator
+                    let rest = &borrow_leaf.key.get_data()[..borrow_leaf.key.len() - 1];
+                    if rest >= partial.get_data() {
+                        nodes.push(node.into());
+                    }
+                    break;
+                }
+                Node::Branch(branch) => {
+                    if partial.is_empty() {
+                        nodes.push(node.into());
+                        break;
+                    }
+                    let index = partial.at(0);
+                    let status = if index < 15 {
+                        TraceStatus::Child(index as u8 + 1)
+                    } else {
+                        TraceStatus::End
+                    };
+                    nodes.push(TraceNode {
+                        node: node.clone(),
+                        status,
+                    });
+                    nibble.push(index as u8);
+                    partial = partial.offset(1);
+                    node = branch.read().children[index].clone();
+                }
+                Node::Extension(ext) => {
+                    let borrow_ext = ext.read();
+                    let match_len = partial.common_prefix(&borrow_ext.prefix);
+                    if match_len == borrow_ext.prefix.len() {
+                        nodes.push(TraceNode {
+                            node: node.clone(),
+                            status: TraceStatus::End,
+                        });
+                        nibble.extend(&borrow_ext.prefix);
+                        partial = partial.offset(match_len);
+                        node = borrow_ext.node.clone();
+                    } else {
+                        if match_len == partial.len()
+                            || borrow_ext.prefix.at(match_len) > partial.at(match_len)
+                        {
+                            nodes.push(node.clone().into());
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(TrieIterator {
+            trie: self,
+            nibble,
+            nodes,
+        })
+    }
+
+    /// Returns an iterator over the pairs whose key starts with `prefix`, in key
+    /// order. Only the subtree holding the prefix is walked.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TrieResult<TrieIterator<'_, D, H>> {
+        let mut nibble = Nibbles::from_raw(vec![], false);
+        let mut partial = Nibbles::from_raw(prefix.to_vec(), false);
+        let mut node = self.root.clone();
+
+        let nodes = loop {
+            if partial.is_empty() {
+                break vec![node.into()];
+            }
+            match node.clone() {
+                Node::Empty => break vec![],
+                Node::Hash(hash_node) => {
+                    node = self.recover_from_db(&hash_node.read().hash)?;
+                }
+                Node::Leaf(leaf) => {
+                    if leaf.read().key.common_prefix(&partial) == partial.len() {
+                        break vec![node.into()];
+                    }
+                    break vec![];
+                }
+                Node::Branch(branch) => {
+                    let index = partial.at(0);
+                    nibble.push(index as u8);
+                    partial = partial.offset(1);
+                    node = branch.read().children[index].clone();
+                }
+                Node::Extension(ext) => {
+                    let borrow_ext = ext.read();
+                    let match_len = partial.common_prefix(&borrow_ext.prefix);
+                    if match_len == partial.len() {
+                        break vec![node.clone().into()];
+                    }
+                    if match_len < borrow_ext.prefix.len() {
+                        break vec![];
+                    }
+                    nibble.extend(&borrow_ext.prefix);
+                    partial = partial.offset(match_len);
+                    node = borrow_ext.node.clone();
+                }
+            }
+        };
+
+        Ok(TrieIterator {
+            trie: self,
+            nibble,
+            nodes,
+        })
+    }
     pub fn new(db: Arc<D>, hasher: Arc<H>) -> Self {
         Self {
             root: Node::Empty,
             root_hash: hasher.digest(rlp::NULL_RLP.as_ref()),
 
-            cache: RefCell::new(HashMap::new()),
-            passing_keys: RefCell::new(HashSet::new()),
-            gen_keys: RefCell::new(HashSet::new()),
+            cache: Mutex::new(HashMap::new()),
+            passing_keys: Mutex::new(HashSet::new()),
+            gen_keys: Mutex::new(HashSet::new()),
 
             db,
             hasher,
@@ -237,9 +438,9 @@ where
                     root: Node::Empty,
                     root_hash: root.to_vec(),
 
-                    cache: RefCell::new(HashMap::new()),
-                    passing_keys: RefCell::new(HashSet::new()),
-                    gen_keys: RefCell::new(HashSet::new()),
+                    cache: Mutex::new(HashMap::new()),
+                    passing_keys: Mutex::new(HashSet::new()),
+                    gen_keys: Mutex::new(HashSet::new()),
 
                     db,
                     hasher,
@@ -257,7 +458,7 @@ where
     pub fn contains(&self, key: &[u8]) -> TrieResult<bool> {
         Ok(self
             .get_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))?
-            .map_or(false, |_| true))
+            .is_some())
     }
     
     /// Returns the value for key stored in the trie.
@@ -265,6 +466,84 @@ where
         self.get_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))
     }
 
+    /// Returns the encoded nodes reachable from the committed root, keyed by
+    /// their hash. Nodes left in the db by removed keys or by other roots are
+    /// not included.
+    ///
+    /// The order is canonical: depth-first from the root, children in nibble
+    /// order, each node emitted once at its first occurrence. Tries holding the
+    /// same key/value pairs always export the same node list.
+    pub fn reachable_nodes(&self) -> TrieResult<Nodes> {
+        let mut nodes = vec![];
+        let mut visited = HashSet::new();
+        self.collect_reachable(&self.root_hash, &mut visited, &mut nodes)?;
+        Ok(nodes)
+    }
+
+    /// Returns the changes turning this trie into the trie of `other_root`,
+    /// whose nodes must be in the same db, in key order. Subtrees referenced by
+    /// the same hash on both sides are skipped without being loaded.
+    pub fn diff(&self, other_root: &[u8]) -> TrieResult<Vec<TrieChange>> {
+        let other = match self
+            .db
+            .get(other_root)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+        {
+            Some(data) => self.decode_node(&data)?,
+            None => return Err(TrieError::InvalidStateRoot),
+        };
+
+        let mut changes = vec![];
+        self.diff_at(
+            self.root.clone(),
+            other,
+            &mut Nibbles::from_raw(vec![], false),
+            &mut changes,
+        )?;
+        Ok(changes)
+    }
+
+    /// Builds one proof for several keys at once. It holds the nodes on the
+    /// paths to every key, each node included once, so it is smaller than the
+    /// concatenation of the single key proofs. The trie must be committed.
+    pub fn get_multi_proof(&self, keys: &[Vec<u8>]) -> TrieResult<Vec<Vec<u8>>> {
+        let mut proof = vec![self.encoded_root()?];
+        for key in keys {
+            let nibbles = Nibbles::from_raw(key.clone(), false);
+            self.walk_range(
+                self.root.clone(),
+                &mut Nibbles::from_raw(vec![], false),
+                nibbles.get_data(),
+                nibbles.get_data(),
+                &mut proof,
+                &mut vec![],
+            )?;
+        }
+        proof.sort();
+        proof.dedup();
+        Ok(proof)
+    }
+
+    /// Builds a proof that the pairs with `start <= key <= end` are all the
+    /// pairs of the trie in that range: it holds every node whose subtree may
+    /// contain a key of the range. The trie must be committed.
+    pub fn get_range_proof(&self, start: &[u8], end: &[u8]) -> TrieResult<Vec<Vec<u8>>> {
+        let start = Nibbles::from_raw(start.to_vec(), false);
+        let end = Nibbles::from_raw(end.to_vec(), false);
+        let mut proof = vec![self.encoded_root()?];
+        self.walk_range(
+            self.root.clone(),
+            &mut Nibbles::from_raw(vec![], false),
+            start.get_data(),
+            end.get_data(),
+            &mut proof,
+            &mut vec![],
+        )?;
+        proof.sort();
+        proof.dedup();
+        Ok(proof)
+    }
+
     // extract specified height statedb in full node mode
     pub fn extract_backup(
         db: Arc<D>,
@@ -276,9 +555,9 @@ where
             root: Node::Empty,
             root_hash: hasher.digest(rlp::NULL_RLP.as_ref()),
 
-            cache: RefCell::new(HashMap::new()),
-            passing_keys: RefCell::new(HashSet::new()),
-            gen_keys: RefCell::new(HashSet::new()),
+            cache: Mutex::new(HashMap::new()),
+            passing_keys: Mutex::new(HashSet::new()),
+            gen_keys: Mutex::new(HashSet::new()),
 
             db,
             hasher,
@@ -293,12 +572,12 @@ where
         pt.iter().for_each(|(k, _v)| addr_list.push(k));
         let encoded = pt.cache_node(root)?;
         pt.cache
-            .borrow_mut()
+            .lock()
             .insert(pt.hasher.digest(&encoded), encoded);
 
-        let mut keys = Vec::with_capacity(pt.cache.borrow().len());
-        let mut values = Vec::with_capacity(pt.cache.borrow().len());
-        for (k, v) in pt.cache.borrow_mut().drain() {
+        let mut keys = Vec::with_capacity(pt.cache.lock().len());
+        let mut values = Vec::with_capacity(pt.cache.lock().len());
+        for (k, v) in pt.cache.lock().drain() {
             keys.push(k.to_vec());
             values.push(v);
         }
@@ -318,10 +597,105 @@ where
     }
 }
 
+impl<H> PatriciaTrie<MemoryDB, H>
+where
+    H: NodeHasher,
+{
+    /// Rebuilds a trie from the nodes exported by `reachable_nodes`.
+    ///
+    /// The node list must be exactly the canonical export of `root`: nodes
+    /// whose key is not their hash, extra, missing, duplicated or reordered
+    /// nodes are rejected with `TrieError::InvalidData`.
+    pub fn from_exported(
+        hasher: Arc<H>,
+        root: &[u8],
+        nodes: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> TrieResult<Self> {
+        let memdb = Arc::new(MemoryDB::new(true));
+        for (key, value) in nodes.iter() {
+            if hasher.digest(value) != *key {
+                return Err(TrieError::InvalidData);
+            }
+            memdb
+                .insert(key.clone(), value.clone())
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+
+        let trie = PatriciaTrie::from(memdb, hasher, root)?;
+        if trie.reachable_nodes()? != nodes {
+            return Err(TrieError::InvalidData);
+        }
+        Ok(trie)
+    }
+
+    /// Checks a proof built by `get_multi_proof` against `root_hash`, without
+    /// any db. Returns the value of each key, None for the keys proven absent,
+    /// or `TrieError::InvalidProof` if a node needed to reach a key is missing.
+    pub fn verify_multi_proof(
+        hasher: Arc<H>,
+        root_hash: &[u8],
+        keys: &[Vec<u8>],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Vec<Option<Vec<u8>>>> {
+        let trie = Self::from_proof(hasher, root_hash, proof)?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            let nibbles = Nibbles::from_raw(key.clone(), false);
+            let mut items = vec![];
+            trie.walk_range(
+                trie.root.clone(),
+                &mut Nibbles::from_raw(vec![], false),
+                nibbles.get_data(),
+                nibbles.get_data(),
+                &mut vec![],
+                &mut items,
+            )?;
+            values.push(items.pop().map(|(_, value)| value));
+        }
+        Ok(values)
+    }
+
+    /// Checks a proof built by `get_range_proof` against `root_hash`, without
+    /// any db. Returns every pair with `start <= key <= end` in key order, or
+    /// `TrieError::InvalidProof` if a node that may hold a key of the range is
+    /// missing, so no pair of the range can be left out.
+    pub fn verify_range_proof(
+        hasher: Arc<H>,
+        root_hash: &[u8],
+        start: &[u8],
+        end: &[u8],
+        proof: Vec<Vec<u8>>,
+    ) -> TrieResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let trie = Self::from_proof(hasher, root_hash, proof)?;
+        let start = Nibbles::from_raw(start.to_vec(), false);
+        let end = Nibbles::from_raw(end.to_vec(), false);
+        let mut items = vec![];
+        trie.walk_range(
+            trie.root.clone(),
+            &mut Nibbles::from_raw(vec![], false),
+            start.get_data(),
+            end.get_data(),
+            &mut vec![],
+            &mut items,
+        )?;
+        Ok(items)
+    }
+
+    fn from_proof(hasher: Arc<H>, root_hash: &[u8], proof: Vec<Vec<u8>>) -> TrieResult<Self> {
+        let memdb = Arc::new(MemoryDB::new(true));
+        for node_encoded in proof.into_iter() {
+            memdb
+                .insert(hasher.digest(&node_encoded), node_encoded)
+                .map_err(|e| TrieError::DB(e.to_string()))?;
+        }
+        PatriciaTrie::from(memdb, hasher, root_hash).or(Err(TrieError::InvalidProof))
+    }
+}
+
 impl<D, H> Trie<D, H> for PatriciaTrie<D, H>
 where
     D: DB,
-    H: Hasher,
+    H: NodeHasher,
 {
     /// Returns the value for key stored in the trie.
     fn get(&self, key: &[u8]) -> TrieResult<Option<Vec<u8>>> {
@@ -332,7 +706,7 @@ where
     fn contains(&self, key: &[u8]) -> TrieResult<bool> {
         Ok(self
             .get_at(self.root.clone(), &Nibbles::from_raw(key.to_vec(), true))?
-            .map_or(false, |_| true))
+            .is_some())
     }
 
     /// Inserts value into trie and modifies it if it exists
@@ -401,13 +775,13 @@ where
 impl<D, H> PatriciaTrie<D, H>
 where
     D: DB,
-    H: Hasher,
+    H: NodeHasher,
 {
     fn get_at(&self, n: Node, partial: &Nibbles) -> TrieResult<Option<Vec<u8>>> {
         match n {
             Node::Empty => Ok(None),
             Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+                let borrow_leaf = leaf.read();
 
                 if &borrow_leaf.key == partial {
                     Ok(Some(borrow_leaf.value.clone()))
@@ -416,7 +790,7 @@ where
                 }
             }
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+                let borrow_branch = branch.read();
 
                 if partial.is_empty() || partial.at(0) == 16 {
                     Ok(borrow_branch.value.clone())
@@ -426,7 +800,7 @@ where
                 }
             }
             Node::Extension(extension) => {
-                let extension = extension.borrow();
+                let extension = extension.read();
 
                 let prefix = &extension.prefix;
                 let match_len = partial.common_prefix(prefix);
@@ -437,7 +811,7 @@ where
                 }
             }
             Node::Hash(hash_node) => {
-                let borrow_hash_node = hash_node.borrow();
+                let borrow_hash_node = hash_node.read();
                 let n = self.recover_from_db(&borrow_hash_node.hash)?;
                 self.get_at(n, partial)
             }
@@ -448,7 +822,7 @@ where
         match n {
             Node::Empty => Ok(Node::from_leaf(partial, value)),
             Node::Leaf(leaf) => {
-                let mut borrow_leaf = leaf.borrow_mut();
+                let mut borrow_leaf = leaf.write();
 
                 let old_partial = &borrow_leaf.key;
                 let match_index = partial.common_prefix(old_partial);
@@ -473,17 +847,17 @@ where
                 branch.insert(partial.at(match_index), n);
 
                 if match_index == 0 {
-                    return Ok(Node::Branch(Rc::new(RefCell::new(branch))));
+                    return Ok(Node::Branch(Arc::new(RwLock::new(branch))));
                 }
 
                 // if include a common prefix
                 Ok(Node::from_extension(
                     partial.slice(0, match_index),
-                    Node::Branch(Rc::new(RefCell::new(branch))),
+                    Node::Branch(Arc::new(RwLock::new(branch))),
                 ))
             }
             Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
+                let mut borrow_branch = branch.write();
 
                 if partial.at(0) == 0x10 {
                     borrow_branch.value = Some(value);
@@ -496,7 +870,7 @@ where
                 Ok(Node::Branch(branch.clone()))
             }
             Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
+                let mut borrow_ext = ext.write();
 
                 let prefix = &borrow_ext.prefix;
                 let sub_node = borrow_ext.node.clone();
@@ -515,7 +889,7 @@ where
                             Node::from_extension(prefix.offset(1), sub_node)
                         },
                     );
-                    let node = Node::Branch(Rc::new(RefCell::new(branch)));
+                    let node = Node::Branch(Arc::new(RwLock::new(branch)));
 
                     return self.insert_at(node, partial, value);
                 }
@@ -532,10 +906,10 @@ where
                 Ok(Node::Extension(ext.clone()))
             }
             Node::Hash(hash_node) => {
-                let borrow_hash_node = hash_node.borrow();
+                let borrow_hash_node = hash_node.read();
 
                 self.passing_keys
-                    .borrow_mut()
+                    .lock()
                     .insert(borrow_hash_node.hash.to_vec());
                 let n = self.recover_from_db(&borrow_hash_node.hash)?;
                 self.insert_at(n, partial, value)
@@ -547,7 +921,7 @@ where
         let (new_n, deleted) = match n {
             Node::Empty => Ok((Node::Empty, false)),
             Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+                let borrow_leaf = leaf.read();
 
                 if &borrow_leaf.key == partial {
                     return Ok((Node::Empty, true));
@@ -555,7 +929,7 @@ where
                 Ok((Node::Leaf(leaf.clone()), false))
             }
             Node::Branch(branch) => {
-                let mut borrow_branch = branch.borrow_mut();
+                let mut borrow_branch = branch.write();
 
                 if partial.at(0) == 0x10 {
                     borrow_branch.value = None;
@@ -573,7 +947,7 @@ where
                 Ok((Node::Branch(branch.clone()), deleted))
             }
             Node::Extension(ext) => {
-                let mut borrow_ext = ext.borrow_mut();
+                let mut borrow_ext = ext.write();
 
                 let prefix = &borrow_ext.prefix;
                 let match_len = partial.common_prefix(prefix);
@@ -592,8 +966,8 @@ where
                 }
             }
             Node::Hash(hash_node) => {
-                let hash = hash_node.borrow().hash.clone();
-                self.passing_keys.borrow_mut().insert(hash.clone());
+                let hash = hash_node.read().hash.clone();
+                self.passing_keys.lock().insert(hash.clone());
 
                 let n = self.recover_from_db(&hash)?;
                 self.delete_at(n, partial)
@@ -610,7 +984,7 @@ where
     fn degenerate(&self, n: Node) -> TrieResult<Node> {
         match n {
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+                let borrow_branch = branch.read();
 
                 let mut used_indexs = vec![];
                 for (index, node) in borrow_branch.children.iter().enumerate() {
@@ -638,27 +1012,27 @@ where
                 }
             }
             Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+                let borrow_ext = ext.read();
 
                 let prefix = &borrow_ext.prefix;
                 match borrow_ext.node.clone() {
                     Node::Extension(sub_ext) => {
-                        let borrow_sub_ext = sub_ext.borrow();
+                        let borrow_sub_ext = sub_ext.read();
 
                         let new_prefix = prefix.join(&borrow_sub_ext.prefix);
                         let new_n = Node::from_extension(new_prefix, borrow_sub_ext.node.clone());
                         self.degenerate(new_n)
                     }
                     Node::Leaf(leaf) => {
-                        let borrow_leaf = leaf.borrow();
+                        let borrow_leaf = leaf.read();
 
                         let new_prefix = prefix.join(&borrow_leaf.key);
                         Ok(Node::from_leaf(new_prefix, borrow_leaf.value.clone()))
                     }
                     // try again after recovering node from the db.
                     Node::Hash(hash_node) => {
-                        let hash = hash_node.borrow().hash.clone();
-                        self.passing_keys.borrow_mut().insert(hash.clone());
+                        let hash = hash_node.read().hash.clone();
+                        self.passing_keys.lock().insert(hash.clone());
 
                         let new_node = self.recover_from_db(&hash)?;
 
@@ -682,7 +1056,7 @@ where
         match n {
             Node::Empty | Node::Leaf(_) => Ok(vec![]),
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+                let borrow_branch = branch.read();
 
                 if partial.is_empty() || partial.at(0) == 16 {
                     Ok(vec![])
@@ -692,7 +1066,7 @@ where
                 }
             }
             Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+                let borrow_ext = ext.read();
 
                 let prefix = &borrow_ext.prefix;
                 let match_len = partial.common_prefix(prefix);
@@ -704,7 +1078,7 @@ where
                 }
             }
             Node::Hash(hash_node) => {
-                let n = self.recover_from_db(&hash_node.borrow().hash.clone())?;
+                let n = self.recover_from_db(&hash_node.read().hash.clone())?;
                 let mut rest = self.get_path_at(n.clone(), partial)?;
                 rest.push(n);
                 Ok(rest)
@@ -716,15 +1090,15 @@ where
         let encoded = self.encode_node(self.root.clone());
         let root_hash = if encoded.len() < H::LENGTH {
             let hash = self.hasher.digest(&encoded);
-            self.cache.borrow_mut().insert(hash.clone(), encoded);
+            self.cache.lock().insert(hash.clone(), encoded);
             hash
         } else {
             encoded
         };
 
-        let mut keys = Vec::with_capacity(self.cache.borrow().len());
-        let mut values = Vec::with_capacity(self.cache.borrow().len());
-        for (k, v) in self.cache.borrow_mut().drain() {
+        let mut keys = Vec::with_capacity(self.cache.lock().len());
+        let mut values = Vec::with_capacity(self.cache.lock().len());
+        for (k, v) in self.cache.lock().drain() {
             keys.push(k.to_vec());
             values.push(v);
         }
@@ -735,9 +1109,9 @@ where
 
         let removed_keys: Vec<Vec<u8>> = self
             .passing_keys
-            .borrow()
+            .lock()
             .iter()
-            .filter(|h| !self.gen_keys.borrow().contains(*h))
+            .filter(|h| !self.gen_keys.lock().contains(*h))
             .map(|h| h.to_vec())
             .collect();
 
@@ -746,16 +1120,71 @@ where
             .map_err(|e| TrieError::DB(e.to_string()))?;
 
         self.root_hash = root_hash.to_vec();
-        self.gen_keys.borrow_mut().clear();
-        self.passing_keys.borrow_mut().clear();
+        self.gen_keys.lock().clear();
+        self.passing_keys.lock().clear();
         self.root = self.recover_from_db(&root_hash)?;
         Ok(root_hash)
     }
 
+    // Builds the subtree holding `leaves`, sorted by key and without duplicates,
+    // whose first `depth` nibbles are consumed by the parent nodes.
+    fn build_sorted(leaves: &[(Nibbles, Vec<u8>)], depth: usize) -> Node {
+        match leaves {
+            [] => Node::Empty,
+            [(key, value)] => Node::from_leaf(key.offset(depth), value.clone()),
+            _ => {
+                // the keys in between share the prefix of the first and last ones
+                let first = &leaves[0].0;
+                let last = &leaves[leaves.len() - 1].0;
+                let common = first.offset(depth).common_prefix(&last.offset(depth));
+                if common > 0 {
+                    return Node::from_extension(
+                        first.slice(depth, depth + common),
+                        Self::build_sorted(leaves, depth + common),
+                    );
+                }
+
+                let mut children = empty_children();
+                let mut value = None;
+                let mut start = 0;
+                while start < leaves.len() {
+                    let index = leaves[start].0.at(depth);
+                    let count = leaves[start..]
+                        .iter()
+                        .take_while(|(key, _)| key.at(depth) == index)
+                        .count();
+                    if index == 16 {
+                        value = Some(leaves[start].1.clone());
+                    } else {
+                        children[index] =
+                            Self::build_sorted(&leaves[start..start + count], depth + 1);
+                    }
+                    start += count;
+                }
+                Node::from_branch(children, value)
+            }
+        }
+    }
+
+    // The children of a branch are independent, so with the `parallel` feature
+    // they are encoded and hashed on the rayon thread pool.
+    #[cfg(feature = "parallel")]
+    fn encode_children(&self, children: &[Node; 16]) -> Vec<Vec<u8>> {
+        children
+            .par_iter()
+            .map(|n| self.encode_node(n.clone()))
+            .collect()
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    fn encode_children(&self, children: &[Node; 16]) -> Vec<Vec<u8>> {
+        children.iter().map(|n| self.encode_node(n.clone())).collect()
+    }
+
     fn encode_node(&self, n: Node) -> Vec<u8> {
         // Returns the hash value directly to avoid double counting.
         if let Node::Hash(hash_node) = n {
-            return hash_node.borrow().hash.clone();
+            return hash_node.read().hash.clone();
         }
 
         let data = self.encode_raw(n.clone());
@@ -765,9 +1194,9 @@ where
             data
         } else {
             let hash = self.hasher.digest(&data);
-            self.cache.borrow_mut().insert(hash.clone(), data);
+            self.cache.lock().insert(hash.clone(), data);
 
-            self.gen_keys.borrow_mut().insert(hash.clone());
+            self.gen_keys.lock().insert(hash.clone());
             hash
         }
     }
@@ -776,7 +1205,7 @@ where
         match n {
             Node::Empty => rlp::NULL_RLP.to_vec(),
             Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+                let borrow_leaf = leaf.read();
 
                 let mut stream = RlpStream::new_list(2);
                 stream.append(&borrow_leaf.key.encode_compact());
@@ -784,12 +1213,10 @@ where
                 stream.out().to_vec()
             }
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+                let borrow_branch = branch.read();
 
                 let mut stream = RlpStream::new_list(17);
-                for i in 0..16 {
-                    let n = borrow_branch.children[i].clone();
-                    let data = self.encode_node(n);
+                for data in self.encode_children(&borrow_branch.children) {
                     if data.len() == H::LENGTH {
                         stream.append(&data);
                     } else {
@@ -804,7 +1231,7 @@ where
                 stream.out().to_vec()
             }
             Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+                let borrow_ext = ext.read();
 
                 let mut stream = RlpStream::new_list(2);
                 stream.append(&borrow_ext.prefix.encode_compact());
@@ -873,11 +1300,229 @@ where
         }
     }
 
+    fn collect_reachable(
+        &self,
+        hash: &[u8],
+        visited: &mut HashSet<Vec<u8>>,
+        nodes: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> TrieResult<()> {
+        if !visited.insert(hash.to_vec()) {
+            return Ok(());
+        }
+        let encoded = self
+            .db
+            .get(hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or(TrieError::InvalidStateRoot)?;
+        let node = self.decode_node(&encoded)?;
+        nodes.push((hash.to_vec(), encoded));
+
+        let mut child_hashes = vec![];
+        Self::collect_child_hashes(node, &mut child_hashes);
+        for child_hash in child_hashes {
+            self.collect_reachable(&child_hash, visited, nodes)?;
+        }
+        Ok(())
+    }
+
+    // Hashes referenced by a decoded node, looking inside the nodes embedded in it.
+    fn collect_child_hashes(n: Node, hashes: &mut Vec<Vec<u8>>) {
+        match n {
+            Node::Empty | Node::Leaf(_) => {}
+            Node::Branch(branch) => {
+                for child in branch.read().children.iter() {
+                    Self::collect_child_hashes(child.clone(), hashes);
+                }
+            }
+            Node::Extension(ext) => {
+                Self::collect_child_hashes(ext.read().node.clone(), hashes);
+            }
+            Node::Hash(hash_node) => hashes.push(hash_node.read().hash.clone()),
+        }
+    }
+
+    fn diff_at(
+        &self,
+        a: Node,
+        b: Node,
+        path: &mut Nibbles,
+        changes: &mut Vec<TrieChange>,
+    ) -> TrieResult<()> {
+        match (&a, &b) {
+            (Node::Empty, Node::Empty) => return Ok(()),
+            (Node::Hash(hash_a), Node::Hash(hash_b))
+                if hash_a.read().hash == hash_b.read().hash =>
+            {
+                return Ok(());
+            }
+            (Node::Hash(hash_node), _) => {
+                let n = self.recover_from_db(&hash_node.read().hash)?;
+                return self.diff_at(n, b.clone(), path, changes);
+            }
+            (_, Node::Hash(hash_node)) => {
+                let n = self.recover_from_db(&hash_node.read().hash)?;
+                return self.diff_at(a.clone(), n, path, changes);
+            }
+            (Node::Leaf(leaf_a), Node::Leaf(leaf_b))
+                if leaf_a.read().key.get_data() == leaf_b.read().key.get_data() =>
+            {
+                let (leaf_a, leaf_b) = (leaf_a.read(), leaf_b.read());
+                if leaf_a.value != leaf_b.value {
+                    changes.push(TrieChange::Modified(
+                        path.join(&leaf_a.key).encode_raw().0,
+                        leaf_a.value.clone(),
+                        leaf_b.value.clone(),
+                    ));
+                }
+                return Ok(());
+            }
+            (Node::Extension(ext_a), Node::Extension(ext_b))
+                if ext_a.read().prefix.get_data() == ext_b.read().prefix.get_data() =>
+            {
+                let (ext_a, ext_b) = (ext_a.read(), ext_b.read());
+                let len = path.len();
+                path.extend(&ext_a.prefix);
+                self.diff_at(ext_a.node.clone(), ext_b.node.clone(), path, changes)?;
+                path.truncate(len);
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        // Nodes of different shapes are compared one nibble at a time.
+        let (value_a, children_a) = Self::branch_view(a);
+        let (value_b, children_b) = Self::branch_view(b);
+        match (value_a, value_b) {
+            (Some(old), Some(new)) if old != new => {
+                changes.push(TrieChange::Modified(path.encode_raw().0, old, new))
+            }
+            (Some(old), None) => changes.push(TrieChange::Removed(path.encode_raw().0, old)),
+            (None, Some(new)) => changes.push(TrieChange::Added(path.encode_raw().0, new)),
+            _ => {}
+        }
+        for i in 0..16 {
+            path.push(i as u8);
+            self.diff_at(children_a[i].clone(), children_b[i].clone(), path, changes)?;
+            path.pop();
+        }
+        Ok(())
+    }
+
+    // The value and children a decoded node would have as a branch node at the
+    // same path.
+    fn branch_view(n: Node) -> (Option<Vec<u8>>, [Node; 16]) {
+        let mut children = empty_children();
+        match n {
+            Node::Empty => (None, children),
+            Node::Leaf(leaf) => {
+                let borrow_leaf = leaf.read();
+                let index = borrow_leaf.key.at(0);
+                if index == 16 {
+                    return (Some(borrow_leaf.value.clone()), children);
+                }
+                children[index] =
+                    Node::from_leaf(borrow_leaf.key.offset(1), borrow_leaf.value.clone());
+                (None, children)
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read();
+                children[borrow_ext.prefix.at(0)] = if borrow_ext.prefix.len() == 1 {
+                    borrow_ext.node.clone()
+                } else {
+                    Node::from_extension(borrow_ext.prefix.offset(1), borrow_ext.node.clone())
+                };
+                (None, children)
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read();
+                (borrow_branch.value.clone(), borrow_branch.children.clone())
+            }
+            Node::Hash(_) => unreachable!(),
+        }
+    }
+
+    fn encoded_root(&self) -> TrieResult<Vec<u8>> {
+        self.db
+            .get(&self.root_hash)
+            .map_err(|e| TrieError::DB(e.to_string()))?
+            .ok_or(TrieError::InvalidStateRoot)
+    }
+
+    // Walks the nodes whose subtree may hold a key in [start, end] (nibbles),
+    // pushing the encoding of each node reached through its hash to `proof` and
+    // the pairs in range to `items`. A node missing from the db is an error,
+    // so a walk over a proof db fails instead of skipping part of the range.
+    fn walk_range(
+        &self,
+        n: Node,
+        path: &mut Nibbles,
+        start: &[u8],
+        end: &[u8],
+        proof: &mut Vec<Vec<u8>>,
+        items: &mut Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> TrieResult<()> {
+        if !path_in_range(path.get_data(), start, end) {
+            return Ok(());
+        }
+        match n {
+            Node::Empty => {}
+            Node::Hash(hash_node) => {
+                let hash = hash_node.read().hash.clone();
+                let encoded = self
+                    .db
+                    .get(&hash)
+                    .map_err(|e| TrieError::DB(e.to_string()))?
+                    .ok_or(TrieError::InvalidProof)?;
+                let n = self.decode_node(&encoded)?;
+                proof.push(encoded);
+                self.walk_range(n, path, start, end, proof, items)?;
+            }
+            Node::Leaf(leaf) => {
+                let borrow_leaf = leaf.read();
+                let key = path.join(&borrow_leaf.key);
+                // without the leaf terminator
+                let nibbles = &key.get_data()[..key.len() - 1];
+                if nibbles >= start && nibbles <= end {
+                    items.push((key.encode_raw().0, borrow_leaf.value.clone()));
+                }
+            }
+            Node::Branch(branch) => {
+                let borrow_branch = branch.read();
+                if let Some(value) = &borrow_branch.value {
+                    let nibbles = path.get_data();
+                    if nibbles >= start && nibbles <= end {
+                        items.push((path.encode_raw().0, value.clone()));
+                    }
+                }
+                for i in 0..16 {
+                    path.push(i as u8);
+                    self.walk_range(
+                        borrow_branch.children[i].clone(),
+                        path,
+                        start,
+                        end,
+                        proof,
+                        items,
+                    )?;
+                    path.pop();
+                }
+            }
+            Node::Extension(ext) => {
+                let borrow_ext = ext.read();
+                let len = path.len();
+                path.extend(&borrow_ext.prefix);
+                self.walk_range(borrow_ext.node.clone(), path, start, end, proof, items)?;
+                path.truncate(len);
+            }
+        }
+        Ok(())
+    }
+
     fn cache_node(&self, n: Node) -> TrieResult<Vec<u8>> {
         match n {
             Node::Empty => Ok(rlp::NULL_RLP.to_vec()),
             Node::Leaf(leaf) => {
-                let borrow_leaf = leaf.borrow();
+                let borrow_leaf = leaf.read();
 
                 let mut stream = RlpStream::new_list(2);
                 stream.append(&borrow_leaf.key.encode_compact());
@@ -885,7 +1530,7 @@ where
                 Ok(stream.out().to_vec())
             }
             Node::Branch(branch) => {
-                let borrow_branch = branch.borrow();
+                let borrow_branch = branch.read();
 
                 let mut stream = RlpStream::new_list(17);
                 for i in 0..16 {
@@ -905,7 +1550,7 @@ where
                 Ok(stream.out().to_vec())
             }
             Node::Extension(ext) => {
-                let borrow_ext = ext.borrow();
+                let borrow_ext = ext.read();
 
                 let mut stream = RlpStream::new_list(2);
                 stream.append(&borrow_ext.prefix.encode_compact());
@@ -918,22 +1563,34 @@ where
                 Ok(stream.out().to_vec())
             }
             Node::Hash(hash_node) => {
-                let hash = hash_node.borrow().hash.clone();
+                let hash = hash_node.read().hash.clone();
                 let next_node = self.recover_from_db(&hash)?;
                 let data = self.cache_node(next_node)?;
-                self.cache.borrow_mut().insert(hash.clone(), data);
+                self.cache.lock().insert(hash.clone(), data);
                 Ok(hash)
             }
         }
     }
 }
 
+// Whether a key starting with `path` can lie in [start, end], all in nibbles.
+fn path_in_range(path: &[u8], start: &[u8], end: &[u8]) -> bool {
+    let len = path.len().min(start.len());
+    if path[..len] < start[..len] {
+        return false;
+    }
+    // keys extending `end` are greater than it
+    let len = path.len().min(end.len());
+    path[..len] < end[..len] || (path[..len] == end[..len] && path.len() <= end.len())
+}
+
 #[cfg(test)]
 mod tests {
     use rand::distributions::Alphanumeric;
     use rand::seq::SliceRandom;
     use rand::{thread_rng, Rng};
-    use std::collections::{HashMap, HashSet};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::convert::TryInto;
     use std::sync::Arc;
 
     use hasher::{Hasher, HasherKeccak};
@@ -1170,6 +1827,198 @@ mod tests {
         assert_eq!(Some(b"test".to_vec()), v);
     }
 
+    fn random_trie(n: usize) -> (PatriciaTrie<MemoryDB, HasherKeccak>, BTreeMap<Vec<u8>, Vec<u8>>) {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        let mut kv = BTreeMap::new();
+        let mut rng = rand::thread_rng();
+        for _ in 0..n {
+            let key: Vec<u8> = (0..rng.gen_range(1, 6))
+                .map(|_| rng.gen_range(0, 4) as u8 * 0x11)
+                .collect();
+            trie.insert(key.clone(), key.clone()).unwrap();
+            kv.insert(key.clone(), key);
+        }
+        trie.root().unwrap();
+        (trie, kv)
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let (trie, kv) = random_trie(300);
+        let starts: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00],
+            vec![0x11],
+            vec![0x12],
+            vec![0x22, 0x33],
+            vec![0x33, 0x33, 0x33],
+            vec![0xff],
+        ];
+        for start in starts.into_iter().chain(kv.keys().cloned()) {
+            let expected: Vec<(Vec<u8>, Vec<u8>)> = kv
+                .range(start.clone()..)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let got: Vec<(Vec<u8>, Vec<u8>)> = trie.iter_from(&start).unwrap().collect();
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[test]
+    fn test_iter_from_range() {
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        for device in 0u8..3 {
+            for timestamp in 0u64..50 {
+                let mut key = vec![device];
+                key.extend_from_slice(&timestamp.to_be_bytes());
+                trie.insert(key, timestamp.to_be_bytes().to_vec()).unwrap();
+            }
+        }
+        trie.root().unwrap();
+
+        let mut start = vec![1u8];
+        start.extend_from_slice(&10u64.to_be_bytes());
+        let mut end = vec![1u8];
+        end.extend_from_slice(&20u64.to_be_bytes());
+        let readings: Vec<u64> = trie
+            .iter_from(&start)
+            .unwrap()
+            .take_while(|(k, _)| *k < end)
+            .map(|(_, v)| u64::from_be_bytes(v.as_slice().try_into().unwrap()))
+            .collect();
+        assert_eq!(readings, (10..20).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let (trie, kv) = random_trie(300);
+        let prefixes: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0x00],
+            vec![0x11, 0x22],
+            vec![0x12],
+            vec![0x33, 0x33, 0x33, 0x33, 0x33, 0x33],
+        ];
+        for prefix in prefixes.into_iter().chain(kv.keys().cloned()) {
+            let expected: Vec<(Vec<u8>, Vec<u8>)> = kv
+                .iter()
+                .filter(|(k, _)| k.starts_with(&prefix))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let got: Vec<(Vec<u8>, Vec<u8>)> = trie.iter_prefix(&prefix).unwrap().collect();
+            assert_eq!(got, expected);
+        }
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let mut rng = rand::thread_rng();
+        let mut items = vec![];
+        for _ in 0..500 {
+            let key: Vec<u8> = (0..rng.gen_range(1, 6))
+                .map(|_| rng.gen_range(0, 4) as u8 * 0x11)
+                .collect();
+            let value: Vec<u8> = (0..rng.gen_range(0, 40))
+                .map(|_| rand::random::<u8>())
+                .collect();
+            items.push((key, value));
+        }
+
+        // the last value of each key wins and an empty one removes it. Removals
+        // don't always leave a canonical trie, so the reference only inserts
+        let mut kv = HashMap::new();
+        for (key, value) in items.iter() {
+            kv.insert(key.clone(), value.clone());
+        }
+        let mut trie = PatriciaTrie::new(
+            Arc::new(MemoryDB::new(true)),
+            Arc::new(HasherKeccak::new()),
+        );
+        for (key, value) in kv.into_iter().filter(|(_, v)| !v.is_empty()) {
+            trie.insert(key, value).unwrap();
+        }
+        let expected = trie.root().unwrap();
+
+        // built in one pass on an empty trie
+        let mut batch_trie = PatriciaTrie::new(
+            Arc::new(MemoryDB::new(true)),
+            Arc::new(HasherKeccak::new()),
+        );
+        batch_trie.insert_batch(items.clone()).unwrap();
+        assert_eq!(batch_trie.root().unwrap(), expected);
+
+        // inserted on top of existing nodes
+        let (first, second) = items.split_at(items.len() / 2);
+        let mut batch_trie = PatriciaTrie::new(
+            Arc::new(MemoryDB::new(true)),
+            Arc::new(HasherKeccak::new()),
+        );
+        batch_trie.insert_batch(first.to_vec()).unwrap();
+        batch_trie.root().unwrap();
+        batch_trie.insert_batch(second.to_vec()).unwrap();
+        assert_eq!(batch_trie.root().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_concurrent_get() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PatriciaTrie<MemoryDB, HasherKeccak>>();
+
+        let memdb = Arc::new(MemoryDB::new(true));
+        let mut trie = PatriciaTrie::new(memdb, Arc::new(HasherKeccak::new()));
+        for i in 0..1000u32 {
+            trie.insert(i.to_be_bytes().to_vec(), i.to_le_bytes().to_vec())
+                .unwrap();
+        }
+        trie.root().unwrap();
+
+        let trie = Arc::new(trie);
+        let handles: Vec<_> = (0..4u32)
+            .map(|t| {
+                let trie = Arc::clone(&trie);
+                std::thread::spawn(move || {
+                    for i in (t..1000).step_by(4) {
+                        assert_eq!(
+                            trie.get(&i.to_be_bytes()).unwrap(),
+                            Some(i.to_le_bytes().to_vec())
+                        );
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_reachable_nodes() {
+        let memdb = Arc::new(MemoryDB::new(false));
+        let mut trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::new(HasherKeccak::new()));
+        trie.insert(b"test".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test1".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test2".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test23".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test33".to_vec(), b"test".to_vec()).unwrap();
+        trie.insert(b"test44".to_vec(), b"test".to_vec()).unwrap();
+        trie.root().unwrap();
+        trie.remove(b"test44").unwrap();
+        let root = trie.root().unwrap();
+
+        // the non-light db still holds the nodes of the first root
+        let nodes = trie.reachable_nodes().unwrap();
+        assert!(nodes.len() < memdb.len().unwrap());
+
+        let exported = MemoryDB::serialize_nodes(&nodes);
+        let restored = Arc::new(MemoryDB::deserialize(true, &exported).unwrap());
+        let trie = PatriciaTrie::from(restored, Arc::new(HasherKeccak::new()), &root).unwrap();
+        assert_eq!(trie.get(b"test33").unwrap(), Some(b"test".to_vec()));
+        assert_eq!(trie.get(b"test44").unwrap(), None);
+        assert_eq!(trie.iter().count(), 5);
+    }
+
     #[test]
     fn iterator_trie() {
         let memdb = Arc::new(MemoryDB::new(true));
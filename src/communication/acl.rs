@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+
+use crate::storage::keyvalue;
+use crate::validation::block::Block;
+
+use super::neighbors::Neighbors;
+
+// data a peer can ask this node for
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Permission {
+    SyncBlocks,        // MessageType5, the blocks and every payload in them
+    QueryTransactions, // MessageType7, pending transactions by signature
+    ListNeighbors,     // MessageType2
+}
+
+// peers a permission is granted to
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    Anyone,
+    Validators, // issuer of the chain and members of its federation
+    // hex encoded public key
+    PublicKey(String),
+}
+
+// policy of the node, loaded with --acl from a json file such as
+// {"rules":{"SyncBlocks":["Validators",{"PublicKey":"02ab..."}]}}
+// Permissions missing from the rules are granted to anyone
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Policy {
+    pub rules: HashMap<Permission, Vec<Principal>>,
+}
+
+impl Policy {
+    pub fn allows(&self, permission: Permission, pk: Option<&[u8]>, is_validator: bool) -> bool {
+        let principals = match self.rules.get(&permission) {
+            Some(principals) => principals,
+            None => return true,
+        };
+        principals.iter().any(|principal| match principal {
+            Principal::Anyone => true,
+            Principal::Validators => is_validator,
+            Principal::PublicKey(key) => pk.is_some_and(|pk| hex::encode(pk) == key.to_lowercase()),
+        })
+    }
+}
+
+lazy_static! {
+    // policy in force, read from the keyvalue store once at startup
+    static ref POLICY: RwLock<Policy> = RwLock::new(Policy::default());
+}
+
+// the policy is kept so it survives a restart without --acl
+pub fn set_policy(policy: &Policy) -> Result<(), Error> {
+    let data = serde_json::to_vec(policy).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    keyvalue::insert(b"acl_policy", &data)?;
+    *POLICY.write().unwrap() = policy.clone();
+    Ok(())
+}
+
+// reads the policy kept from previous runs
+pub fn load() -> Result<(), Error> {
+    let policy = match keyvalue::get(b"acl_policy")? {
+        Some(policy) => serde_json::from_slice(&policy).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        None => Policy::default(),
+    };
+    *POLICY.write().unwrap() = policy;
+    Ok(())
+}
+
+pub fn load_file(path: &str) -> Result<Policy, Error> {
+    let policy: Policy = serde_json::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    set_policy(&policy)?;
+    Ok(policy)
+}
+
+// checks a request from an address, identified by the key bound to it
+pub fn is_allowed(permission: Permission, src: &str) -> bool {
+    let policy = POLICY.read().unwrap();
+    match Neighbors::get(src) {
+        Some(node) => policy.allows(permission, Some(&node.pk), Block::is_validator(&node.pk)),
+        None => policy.allows(permission, None, false),
+    }
+}
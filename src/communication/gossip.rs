@@ -0,0 +1,107 @@
+use std::collections::{HashSet, VecDeque};
+
+use lazy_static::lazy_static;
+use secp256k1::rand::{seq::SliceRandom, thread_rng};
+use tokio::sync::RwLock;
+
+use crate::{transport, NodeContext};
+
+use super::messages::{Message, Packet};
+use super::neighbors::Neighbors;
+use super::transactions::Transaction;
+
+// number of neighbors a new transaction is relayed to
+const FANOUT: usize = 3;
+// transactions up to this serialized size are pushed directly, larger ones are
+// announced and fetched by the neighbors that haven't seen them yet
+const INLINE_LIMIT: usize = 1024;
+// number of signatures remembered by the seen-set
+const SEEN_CAPACITY: usize = 10000;
+
+struct SeenSet {
+    signatures: HashSet<Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+lazy_static! {
+    static ref SEEN: RwLock<SeenSet> = RwLock::new(SeenSet {
+        signatures: HashSet::new(),
+        order: VecDeque::new(),
+    });
+}
+
+pub async fn is_seen(signature: &[u8]) -> bool {
+    SEEN.read().await.signatures.contains(signature)
+}
+
+// records the signature of an accepted transaction, returns false if it had
+// already been seen. Only the last SEEN_CAPACITY signatures are remembered
+pub async fn mark_seen(signature: &[u8]) -> bool {
+    let mut seen = SEEN.write().await;
+    if !seen.signatures.insert(signature.to_vec()) {
+        return false;
+    }
+    seen.order.push_back(signature.to_vec());
+    if seen.order.len() > SEEN_CAPACITY {
+        if let Some(oldest) = seen.order.pop_front() {
+            seen.signatures.remove(&oldest);
+        }
+    }
+    true
+}
+
+// relays a transaction to a random subset of neighbors, never back to the
+// neighbor it was received from
+pub async fn propagate(ctx: NodeContext, transaction: Transaction, src: Option<String>) {
+    let peers: Vec<String> = {
+        let neighbors: Vec<String> = Neighbors::restore().neighbors.keys()
+            .filter(|addr| Some(*addr) != src.as_ref())
+            .cloned()
+            .collect();
+        neighbors.choose_multiple(&mut thread_rng(), FANOUT).cloned().collect()
+    };
+
+    let message = if serde_json::to_vec(&transaction).unwrap().len() <= INLINE_LIMIT {
+        Message::generate_with_transaction(&ctx, 3, transaction)
+    } else {
+        Message::generate_with_signatures(&ctx, 6, vec![transaction.signature.to_vec()])
+    };
+    let serialized_message = match message {
+        Ok(message) => serde_json::to_string(&Packet::Message(message)).unwrap(),
+        Err(e) => {
+            println!("Erro ao assinar mensagem: {}", e);
+            return;
+        }
+    };
+
+    for addr in peers {
+        println!("Enviando para {}", addr);
+        if let Err(e) = transport::send(&ctx, addr, serialized_message.clone()).await {
+            println!("Erro ao propagar transação: {}", e);
+        }
+    }
+}
+
+// requests the full transactions for announced signatures not seen yet
+pub async fn fetch(ctx: NodeContext, signatures: Vec<Vec<u8>>, src: String) {
+    let mut missing = vec![];
+    for signature in signatures {
+        if !is_seen(&signature).await {
+            missing.push(signature);
+        }
+    }
+    if missing.is_empty() {
+        return;
+    }
+
+    let message = match Message::generate_with_signatures(&ctx, 7, missing) {
+        Ok(message) => Packet::Message(message),
+        Err(e) => {
+            println!("Erro ao assinar mensagem: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = transport::send(&ctx, src, serde_json::to_string(&message).unwrap()).await {
+        println!("Erro ao solicitar transações: {}", e);
+    }
+}
@@ -3,10 +3,14 @@ use std::io::{Error, ErrorKind};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
+use crate::NodeContext;
 use crate::signature;
-use crate::storage::{keyvalue, merkle};
+use crate::storage::{keyvalue, mempool};
+use crate::transport::tls;
 use crate::validation::block::{self, Block};
 
+use super::acl::{self, Permission};
+use super::gossip;
 use super::responses::Response;
 use super::neighbors::{Neighbors, Node};
 use super::transactions::Transaction;
@@ -32,20 +36,20 @@ struct DataMessageType1 {
 }
 
 impl DataMessageType1 {
-    pub fn execute(&self, src: String) -> Result<Response, Error> {
+    pub fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
         //TODO: add bussiness logic (block too many node connections, verify node type)
-        Neighbors::add(src, Node{pk: self.public_key, is_validator: false});
+        tls::check_peer_key(&src, &self.public_key)?;
+        Neighbors::add(src, Node{pk: self.public_key});
 
-        Ok(Response::generate(2).unwrap())
+        Response::generate(ctx, 2)
     }
 
-    pub fn generate() -> Self {
-        let sk = keyvalue::get(b"secret_key").unwrap().unwrap();
-        let public_key = signature::generate_public_key(sk.as_slice());
+    pub fn generate(ctx: &NodeContext) -> Result<Self, Error> {
+        let public_key = ctx.signer.public_key()?;
         let data = DataMessageType1 {
             public_key
         };
-        data
+        Ok(data)
     }
 }
 
@@ -54,13 +58,13 @@ struct DataMessageType2 {
 }
 
 impl DataMessageType2 {
-    pub fn execute(&self, _src: String) -> Result<Response, Error> {
+    pub fn execute(&self, ctx: &NodeContext, _src: String) -> Result<Response, Error> {
         //TODO: add bussiness logic (block too many node connections, verify node type)
         // let nodes = self.neighbors.neighbors;
         // for (addr, _) in nodes {
         //     transport::send(node, addr, msg);
         // }
-        Ok(Response::generate(1).unwrap())
+        Response::generate(ctx, 1)
     }
 
     // pub fn generate() -> Self {
@@ -79,19 +83,26 @@ struct DataMessageType3 {
 }
 
 impl DataMessageType3 {
-    pub async fn execute(&self) -> Result<Response, Error> {
-        // TODO: verify more transaction details (address balance)
-        match block::LocalBlock::insert_transaction(self.transaction.clone()).await {
-            Ok(()) => Ok(Response::generate(1).unwrap()),
-            Err(_) => Ok(Response::generate(500).unwrap())
+    pub async fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
+        // already accepted transactions are neither inserted nor relayed again
+        if gossip::is_seen(&self.transaction.signature).await {
+            return Response::generate(ctx, 1);
+        }
+        match block::LocalBlock::insert_transaction(ctx, self.transaction.clone()).await {
+            Ok(()) => {
+                gossip::mark_seen(&self.transaction.signature).await;
+                // relayed in background, so the sender gets its response first
+                tokio::spawn(gossip::propagate(ctx.clone(), self.transaction.clone(), Some(src)));
+                Response::generate(ctx, 1)
+            },
+            Err(_) => Response::generate(ctx, 500)
         }
     }
 
     pub fn generate(transaction: Transaction) -> Self {
-        let data = DataMessageType3 {
+        DataMessageType3 {
             transaction
-        };
-        data
+        }
     }
 
 }
@@ -102,21 +113,22 @@ struct DataMessageType4 {
 }
 
 impl DataMessageType4 {
-    pub async fn execute(&self) -> Result<Response, Error> {
+    pub async fn execute(&self, ctx: &NodeContext) -> Result<Response, Error> {
         // TODO: verify block details (transactions missing or added)
-
-        self.block.save_to_blockchain();
+        if let Err(e) = self.block.accept().await {
+            println!("Bloco rejeitado: {}", e);
+            return Response::generate(ctx, 500);
+        }
 
         // keyvalue::insert(&self.block.header, serde_json::to_vec(&self.block).unwrap().as_slice()).unwrap();
         // keyvalue::insert(b"last_block_header", &self.block.header).unwrap();
-        Ok(Response::generate(1).unwrap())
+        Response::generate(ctx, 1)
     }
 
     pub fn generate(block: Block) -> Self {
-        let data = DataMessageType4 {
+        DataMessageType4 {
             block
-        };
-        data
+        }
     }
 
 }
@@ -129,39 +141,92 @@ struct DataMessageType5 {
 impl DataMessageType5 {
 
     pub fn generate() -> Self {
-        let until_header = match keyvalue::get(b"last_block_header").unwrap() {
-            Some(header) => header,
-            None => vec![]
-        };
-        let data = DataMessageType5 {
+        let until_header = keyvalue::get(b"last_block_header").unwrap().unwrap_or_default();
+        DataMessageType5 {
             until_header
-        };
-        data
+        }
     }
 
-    pub fn execute(&self) -> Result<Response, Error> {
+    // blocks after `until_header`, newest first, with their bodies loaded from
+    // the stored tries
+    pub fn execute(&self, ctx: &NodeContext) -> Result<Response, Error> {
         let mut blocks: Vec<Block> = Vec::new();
 
-        let mut header = keyvalue::get(b"last_block_header").unwrap().unwrap();
-        let mut block_serialized = keyvalue::get(&header).unwrap().unwrap();
-        let mut block: Block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
-        blocks.push(block.clone());
-        while block.previous_block_header.len() > 0 {
+        let mut header = keyvalue::get(b"last_block_header")?.unwrap_or_default();
+        while !header.is_empty() && header != self.until_header {
+            let block = Block::load_with_body(&header)?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "Block not found"))?;
             header = block.previous_block_header.clone();
-            block_serialized = keyvalue::get(&header).unwrap().unwrap();
-            block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
-            
-            if block.header == self.until_header {
-                break;
+            blocks.push(block);
+        }
+        Response::generate_with_block_vector(ctx, 3, blocks)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DataMessageType6 {
+    signatures: Vec<Vec<u8>>
+}
+
+impl DataMessageType6 {
+    pub fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
+        tokio::spawn(gossip::fetch(ctx.clone(), self.signatures.clone(), src));
+        Response::generate(ctx, 1)
+    }
+
+    pub fn generate(signatures: Vec<Vec<u8>>) -> Self {
+        DataMessageType6 {
+            signatures
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct DataMessageType7 {
+    signatures: Vec<Vec<u8>>
+}
+
+impl DataMessageType7 {
+    pub async fn execute(&self, ctx: &NodeContext) -> Result<Response, Error> {
+        let mut transactions = vec![];
+        for signature in self.signatures.iter() {
+            if let Some(transaction) = mempool::get(signature).await {
+                transactions.push(transaction);
             }
+        }
+        Response::generate_with_transactions(ctx, 4, transactions)
+    }
 
-            blocks.push(block.clone());
+    pub fn generate(signatures: Vec<Vec<u8>>) -> Self {
+        DataMessageType7 {
+            signatures
         }
-        let response = Response::generate_with_block_vector(3, blocks).unwrap();
-        Ok(response)
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct DataMessageType8 {
+    block: Block
+}
+
+impl DataMessageType8 {
+    pub fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
+        let requester = Neighbors::get(&src).map(|node| node.pk).unwrap_or([0; 33]);
+        match self.block.sign_share(&requester) {
+            Ok(share) => Response::generate_with_share(ctx, 5, self.block.header.clone(), share),
+            Err(e) => {
+                println!("Assinatura federada recusada: {}", e);
+                Response::generate(ctx, 500)
+            },
+        }
+    }
+
+    pub fn generate(block: Block) -> Self {
+        DataMessageType8 {
+            block
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 enum Data {
@@ -170,25 +235,61 @@ enum Data {
     MessageType3(DataMessageType3), // Propagate transaction to neighbor
     MessageType4(DataMessageType4), // Propagate new block to neighbor
     MessageType5(DataMessageType5), // Request current blockchain state
+    MessageType6(DataMessageType6), // Announce transactions by signature
+    MessageType7(DataMessageType7), // Request announced transactions
+    MessageType8(DataMessageType8), // Request federation signature share for a new block
 }
 
 impl Data {
-    async fn execute(&self, src: String) -> Result<Response, Error> {
+    // permission a peer needs for the request, None when it only sends data
+    fn permission(&self) -> Option<Permission> {
+        match self {
+            Data::MessageType2(_) => Some(Permission::ListNeighbors),
+            Data::MessageType5(_) => Some(Permission::SyncBlocks),
+            Data::MessageType7(_) => Some(Permission::QueryTransactions),
+            _ => None,
+        }
+    }
+
+    // rate limit cost of handling the message, in tokens of the peer budget
+    fn cost(&self) -> f64 {
         match self {
-            Data::MessageType1(data) => data.execute(src),
-            Data::MessageType2(data) => data.execute(src),
-            Data::MessageType3(data) => data.execute().await,
-            Data::MessageType4(data) => data.execute().await,
-            Data::MessageType5(data) => data.execute(),
-            _ => Err(Error::new(ErrorKind::Unsupported, "Unsupported message type"))
+            Data::MessageType1(_) => 5.0,
+            Data::MessageType2(_) => 5.0,
+            Data::MessageType3(_) => 2.0,
+            Data::MessageType4(_) => 10.0,
+            // walks and serializes the whole chain
+            Data::MessageType5(_) => 50.0,
+            Data::MessageType6(_) => 1.0,
+            Data::MessageType7(_) => 5.0,
+            Data::MessageType8(_) => 10.0,
         }
     }
 
-    fn generate(msg_type: u32) -> Self {
+    async fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
+        if let Some(permission) = self.permission() {
+            if !acl::is_allowed(permission, &src) {
+                println!("Permissão negada para {}: {:?}", src, permission);
+                return Response::generate(ctx, 403);
+            }
+        }
+        match self {
+            Data::MessageType1(data) => data.execute(ctx, src),
+            Data::MessageType2(data) => data.execute(ctx, src),
+            Data::MessageType3(data) => data.execute(ctx, src).await,
+            Data::MessageType4(data) => data.execute(ctx).await,
+            Data::MessageType5(data) => data.execute(ctx),
+            Data::MessageType6(data) => data.execute(ctx, src),
+            Data::MessageType7(data) => data.execute(ctx).await,
+            Data::MessageType8(data) => data.execute(ctx, src),
+        }
+    }
+
+    fn generate(ctx: &NodeContext, msg_type: u32) -> Result<Self, Error> {
         match msg_type {
-            1 => Data::MessageType1(DataMessageType1::generate()),
+            1 => Ok(Data::MessageType1(DataMessageType1::generate(ctx)?)),
             // 2 => Data::MessageType2(DataMessageType2::generate()),
-            5 => Data::MessageType5(DataMessageType5::generate()),
+            5 => Ok(Data::MessageType5(DataMessageType5::generate())),
             _ => panic!("Invalid message type"),
         }
     }
@@ -200,35 +301,43 @@ impl Data {
         }
     }
 
+    fn generate_with_signatures(msg_type: u32, signatures: Vec<Vec<u8>>) -> Self {
+        match msg_type {
+            6 => Data::MessageType6(DataMessageType6::generate(signatures)),
+            7 => Data::MessageType7(DataMessageType7::generate(signatures)),
+            _ => panic!("Invalid message type"),
+        }
+    }
+
     fn generate_with_block(msg_type: u32, block: Block) -> Self {
         match msg_type {
             4 => Data::MessageType4(DataMessageType4::generate(block)),
+            8 => Data::MessageType8(DataMessageType8::generate(block)),
             _ => panic!("Invalid message type"),
         }
     }
 }
 
 impl Message {
-    pub fn generate(message_type: u32) -> Self {
+    pub fn generate(ctx: &NodeContext, message_type: u32) -> Result<Self, Error> {
         let timestamp = chrono::Utc::now().timestamp();
 
         let data: Data = match message_type {
-            1 => Data::generate(message_type),
-            5 => Data::generate(message_type),
+            1 => Data::generate(ctx, message_type)?,
+            5 => Data::generate(ctx, message_type)?,
             _ => panic!("Invalid message type")
         };
 
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            keyvalue::get(b"secret_key").unwrap().unwrap().as_slice());
-        Message {
+        let signature = ctx.signer.sign(
+            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+        Ok(Message {
             timestamp,
             data,
             signature
-        }
+        })
     }
 
-    pub fn generate_with_transaction(message_type: u32, transaction: Transaction) -> Self {
+    pub fn generate_with_transaction(ctx: &NodeContext, message_type: u32, transaction: Transaction) -> Result<Self, Error> {
         let timestamp = chrono::Utc::now().timestamp();
 
         let data: Data = match message_type {
@@ -236,46 +345,67 @@ impl Message {
             _ => panic!("Invalid message type")
         };
 
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            keyvalue::get(b"secret_key").unwrap().unwrap().as_slice());  
-        Message {
+        let signature = ctx.signer.sign(
+            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+        Ok(Message {
             timestamp,
             data,
             signature
-        }
+        })
     }
 
-    pub async fn generate_with_block(message_type: u32, block: Block) -> Self {
+    pub fn generate_with_signatures(ctx: &NodeContext, message_type: u32, signatures: Vec<Vec<u8>>) -> Result<Self, Error> {
         let timestamp = chrono::Utc::now().timestamp();
 
         let data: Data = match message_type {
-            4 => Data::generate_with_block(message_type, block),
+            6 | 7 => Data::generate_with_signatures(message_type, signatures),
             _ => panic!("Invalid message type")
         };
 
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            keyvalue::get(b"secret_key").unwrap().unwrap().as_slice());  
-        Message {
+        let signature = ctx.signer.sign(
+            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+        Ok(Message {
             timestamp,
             data,
             signature
-        }
+        })
     }
 
-    pub async fn execute(&self, src: String) -> Result<Response, Error> {
+    pub fn generate_with_block(ctx: &NodeContext, message_type: u32, block: Block) -> Result<Self, Error> {
+        let timestamp = chrono::Utc::now().timestamp();
+
+        let data: Data = match message_type {
+            4 | 8 => Data::generate_with_block(message_type, block),
+            _ => panic!("Invalid message type")
+        };
+
+        let signature = ctx.signer.sign(
+            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+        Ok(Message {
+            timestamp,
+            data,
+            signature
+        })
+    }
+
+    pub fn cost(&self) -> f64 {
+        self.data.cost()
+    }
+
+    pub async fn execute(&self, ctx: &NodeContext, src: String) -> Result<Response, Error> {
         match self.verify(src.clone()) {
             Ok(_) => {
                 println!("Verified message from {}", src);
-                let data = self.data.execute(src.clone()).await;
+                let data = self.data.execute(ctx, src.clone()).await;
                 data
             }
             Err(e) => match e.kind() {
                 ErrorKind::NotFound => {
                     match &self.data {
+                        // an unknown peer must prove it holds the key it announces
                         Data::MessageType1(data) => {
-                            return data.execute(src)
+                            self.verify_with(&data.public_key)?;
+                            data.execute(ctx, src)
                         },
                         _ => Err(e)
                     }
@@ -292,23 +422,19 @@ impl Message {
         // TODO: verify timestamp
         let has_pk = Neighbors::get(&src);
         match has_pk {
-            Some(node) => {
-                let pk = node.pk;
-                match signature::verify_signature((
-                    self.timestamp.to_string()+&serde_json::to_string(&self.data).unwrap())
-                        .as_bytes(), 
-                    &pk, &self.signature) {
-                        Ok(()) => Ok(()),
-                        Err(_) => {
-                            let e = std::io::Error::new(std::io::ErrorKind::Other, "Invalid signature");
-                            Err(e)
-                        }
-                    }
-                },
+            Some(node) => self.verify_with(&node.pk),
             None => {
                 let e = std::io::Error::new(std::io::ErrorKind::NotFound, "No public key found");
                 Err(e)
             }
         }
     }
-}
\ No newline at end of file
+
+    fn verify_with(&self, pk: &[u8]) -> Result<(), std::io::Error> {
+        signature::verify_signature((
+            self.timestamp.to_string()+&serde_json::to_string(&self.data).unwrap())
+                .as_bytes(),
+            pk, &self.signature)?;
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
+pub mod acl;
 pub mod messages;
 pub mod transactions;
 pub mod responses;
-pub mod neighbors;
\ No newline at end of file
+pub mod neighbors;
+pub mod gossip;
\ No newline at end of file
@@ -2,13 +2,12 @@ use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 use serde_json::{Map, Value};
 
-use crate::{storage::keyvalue};
+use crate::{storage::keyvalue, transport::limits};
 
 #[derive(Serialize, Deserialize)]
 pub struct Node {
     #[serde(with = "BigArray")]
     pub pk: [u8; 33],
-    pub is_validator: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -17,7 +16,7 @@ pub struct Neighbors {
 }
 
 impl Neighbors {
-    pub fn new() {
+    pub fn init() {
         let neighbors = Neighbors {
             neighbors: Map::new()
         };
@@ -31,7 +30,7 @@ impl Neighbors {
                 neighbors
             },
             None => {
-                Neighbors::new();
+                Neighbors::init();
                 let data = keyvalue::get(b"neighbors").unwrap().unwrap();
                 let neighbors: Neighbors = serde_json::from_slice(data.as_slice()).unwrap();
                 neighbors
@@ -44,6 +43,17 @@ impl Neighbors {
         let mut neighbors = Neighbors::restore();
         neighbors.neighbors.insert(src, Value::String(serde_json::to_string(&node).unwrap()));
         keyvalue::insert(b"neighbors", serde_json::to_string(&neighbors).unwrap().as_bytes()).unwrap();
+        limits::refresh_validators();
+    }
+
+    // drops every address of a host from the table
+    pub fn remove_host(ip: &str) {
+        let mut neighbors = Neighbors::restore();
+        neighbors.neighbors.retain(|src, _| {
+            src.parse::<std::net::SocketAddr>().map_or(true, |addr| addr.ip().to_string() != ip)
+        });
+        keyvalue::insert(b"neighbors", serde_json::to_string(&neighbors).unwrap().as_bytes()).unwrap();
+        limits::refresh_validators();
     }
 
     pub fn get(src: &str) -> Option<Node> {
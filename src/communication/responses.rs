@@ -1,11 +1,13 @@
-use std::io::Error;
+use std::io::{Error, ErrorKind};
 
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
 
-use crate::{storage::keyvalue, signature, validation::block::Block, transport, communication::messages::{Packet, Message}};
+use crate::{NodeContext, signature, validation::{block::{Block, LocalBlock}, federation::{self, FederationShare}}, transport::tls};
 
+use super::gossip;
 use super::neighbors::{Node, Neighbors};
+use super::transactions::Transaction;
 
 
 #[derive(Serialize, Deserialize)]
@@ -18,19 +20,18 @@ impl Type2Data {
     async fn execute(&self, src: String) -> Result<(), Error> {
         let new_node = Node {
             pk: self.pk,
-            is_validator: true, // TODO: add check after blockchain is received
         };
-        Neighbors::add(src.clone(), new_node);
+        tls::check_peer_key(&src, &self.pk)?;
+        Neighbors::add(src, new_node);
         Ok(())
     }
 
-    fn generate() -> Self {
-        let sk = keyvalue::get(b"secret_key").unwrap().unwrap();
-        let pk = signature::generate_public_key(sk.as_slice());
+    fn generate(ctx: &NodeContext) -> Result<Self, Error> {
+        let pk = ctx.signer.public_key()?;
         let data = Type2Data {
             pk,
         };
-        data
+        Ok(data)
     }
 }
 
@@ -40,18 +41,63 @@ struct Type3Data {
 }
 
 impl Type3Data {
-    fn execute(&self, _src: String) -> Result<(), Error> {
-        self.blocks.clone().into_iter().rev().for_each(|block| {
-            block.save_to_blockchain()
-        });
+    async fn execute(&self, _src: String) -> Result<(), Error> {
+        for block in self.blocks.clone().into_iter().rev() {
+            block.accept().await?;
+        }
         Ok(())
     }
 
     fn generate(blocks: Vec<Block>) -> Self {
-        let data = Type3Data {
+        Type3Data {
             blocks,
-        };
-        data
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Type4Data {
+    transactions: Vec<Transaction>,
+}
+
+impl Type4Data {
+    async fn execute(&self, ctx: &NodeContext, src: String) -> Result<(), Error> {
+        for transaction in self.transactions.clone() {
+            if gossip::is_seen(&transaction.signature).await {
+                continue;
+            }
+            if LocalBlock::insert_transaction(ctx, transaction.clone()).await.is_ok() {
+                gossip::mark_seen(&transaction.signature).await;
+                tokio::spawn(gossip::propagate(ctx.clone(), transaction, Some(src.clone())));
+            }
+        }
+        Ok(())
+    }
+
+    fn generate(transactions: Vec<Transaction>) -> Self {
+        Type4Data {
+            transactions,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Type5Data {
+    header: Vec<u8>,
+    share: FederationShare,
+}
+
+impl Type5Data {
+    async fn execute(&self, _src: String) -> Result<(), Error> {
+        federation::record_share(&self.header, self.share.clone()).await;
+        Ok(())
+    }
+
+    fn generate(header: Vec<u8>, share: FederationShare) -> Self {
+        Type5Data {
+            header,
+            share,
+        }
     }
 }
 
@@ -60,23 +106,39 @@ enum Data {
     ResponseType1, // Ok response, do nothing
     ResponseType2(Type2Data), // Added to node list, data is public key
     ResponseType3(Type3Data), // Send current blockchain state
-    ErrorResponse // Error response, do nothing
+    ResponseType4(Type4Data), // Send requested transactions
+    ResponseType5(Type5Data), // Send federation signature share of a block
+    ErrorResponse, // Error response, do nothing
+    PermissionDenied, // Request not allowed by the policy of the peer
+    Throttled, // Request over the rate limit of the peer
 }
 
 impl Data {
-    async fn execute(&self, src: String) -> Result<(), Error> {
+    async fn execute(&self, ctx: &NodeContext, src: String) -> Result<(), Error> {
         match self {
             Data::ResponseType1 => Ok(()),
             Data::ResponseType2(data) => data.execute(src).await,
-            Data::ResponseType3(data) => data.execute(src),
+            Data::ResponseType3(data) => data.execute(src).await,
+            Data::ResponseType4(data) => data.execute(ctx, src).await,
+            Data::ResponseType5(data) => data.execute(src).await,
             Data::ErrorResponse => Ok(()),
+            Data::PermissionDenied => {
+                println!("Permissão negada por {}", src);
+                Ok(())
+            },
+            Data::Throttled => {
+                println!("Limite de requisições excedido em {}", src);
+                Ok(())
+            },
         }
     }
 
-    fn generate(data_type: u32) -> Result<Self, Error> {
+    fn generate(ctx: &NodeContext, data_type: u32) -> Result<Self, Error> {
         match data_type {
             1 => Ok(Data::ResponseType1),
-            2 => Ok(Data::ResponseType2(Type2Data::generate())),
+            2 => Ok(Data::ResponseType2(Type2Data::generate(ctx)?)),
+            403 => Ok(Data::PermissionDenied),
+            429 => Ok(Data::Throttled),
             500 => Ok(Data::ErrorResponse),
             _ => panic!("Invalid message type"),
         }
@@ -88,6 +150,20 @@ impl Data {
             _ => panic!("Invalid message type"),
         }
     }
+
+    fn generate_with_transactions(data_type: u32, transactions: Vec<Transaction>) -> Result<Self, Error> {
+        match data_type {
+            4 => Ok(Data::ResponseType4(Type4Data::generate(transactions))),
+            _ => panic!("Invalid message type"),
+        }
+    }
+
+    fn generate_with_share(data_type: u32, header: Vec<u8>, share: FederationShare) -> Result<Self, Error> {
+        match data_type {
+            5 => Ok(Data::ResponseType5(Type5Data::generate(header, share))),
+            _ => panic!("Invalid message type"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -99,18 +175,35 @@ pub struct Response {
 }
 
 impl Response {
-    pub async fn execute(&self, src: String) -> Result<(), Error> {
-        self.data.execute(src).await
+    pub async fn execute(&self, ctx: &NodeContext, src: String) -> Result<(), Error> {
+        if let Data::ResponseType1 | Data::ErrorResponse | Data::PermissionDenied | Data::Throttled = self.data {
+            return Ok(());
+        }
+        self.verify(&src)?;
+        self.data.execute(ctx, src).await
     }
 
-    pub fn generate(data_type: u32) -> Result<Self, Error> {
-        match Data::generate(data_type) {
+    // signed by the key bound to the address, or by the key a new neighbor
+    // announces in ResponseType2
+    fn verify(&self, src: &str) -> Result<(), Error> {
+        let pk = match (Neighbors::get(src), &self.data) {
+            (Some(node), _) => node.pk,
+            (None, Data::ResponseType2(data)) => data.pk,
+            (None, _) => return Err(Error::new(ErrorKind::NotFound, "No public key found")),
+        };
+        signature::verify_signature(
+            (self.timestamp.to_string()+&serde_json::to_string(&self.data).unwrap()).as_bytes(),
+            &pk, &self.signature)?;
+        Ok(())
+    }
+
+    pub fn generate(ctx: &NodeContext, data_type: u32) -> Result<Self, Error> {
+        match Data::generate(ctx, data_type) {
             Ok(data) => {
                 let timestamp = chrono::Utc::now().timestamp();
 
-                let signature = signature::new_signature(
-                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-                    keyvalue::get(b"secret_key").unwrap().unwrap().as_slice());  
+                let signature = ctx.signer.sign(
+                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
 
                 Ok(Response {
                     timestamp,
@@ -122,14 +215,13 @@ impl Response {
         }
     }
 
-    pub fn generate_with_block_vector(data_type: u32, blocks: Vec<Block>) -> Result<Self, Error> {
+    pub fn generate_with_block_vector(ctx: &NodeContext, data_type: u32, blocks: Vec<Block>) -> Result<Self, Error> {
         match Data::generate_with_block_vector(data_type, blocks) {
             Ok(data) => {
                 let timestamp = chrono::Utc::now().timestamp();
 
-                let signature = signature::new_signature(
-                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-                    keyvalue::get(b"secret_key").unwrap().unwrap().as_slice());  
+                let signature = ctx.signer.sign(
+                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
 
                 Ok(Response {
                     timestamp,
@@ -140,4 +232,40 @@ impl Response {
             Err(err) => Err(err),
         }
     }
+
+    pub fn generate_with_transactions(ctx: &NodeContext, data_type: u32, transactions: Vec<Transaction>) -> Result<Self, Error> {
+        match Data::generate_with_transactions(data_type, transactions) {
+            Ok(data) => {
+                let timestamp = chrono::Utc::now().timestamp();
+
+                let signature = ctx.signer.sign(
+                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+
+                Ok(Response {
+                    timestamp,
+                    data,
+                    signature,
+                })
+            }, 
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn generate_with_share(ctx: &NodeContext, data_type: u32, header: Vec<u8>, share: FederationShare) -> Result<Self, Error> {
+        match Data::generate_with_share(data_type, header, share) {
+            Ok(data) => {
+                let timestamp = chrono::Utc::now().timestamp();
+
+                let signature = ctx.signer.sign(
+                    (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes())?;
+
+                Ok(Response {
+                    timestamp,
+                    data,
+                    signature,
+                })
+            },
+            Err(err) => Err(err),
+        }
+    }
 }
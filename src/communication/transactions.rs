@@ -1,7 +1,9 @@
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
 
-use crate::{storage::keyvalue, signature};
+use std::io::{Error, ErrorKind};
+
+use crate::{storage::{keyvalue, hashing::{self, HashAlgorithm}}, signature::{self, keystore, signer::{KeystoreSigner, Signer}, BatchItem, SignatureScheme}, validation::{fee, block::Block, federation::Federation, address::AddressFormat}, FEDERATION};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Transaction {
@@ -9,7 +11,15 @@ pub struct Transaction {
     #[serde(with = "BigArray")]
     pub pk: [u8; 33],
     pub data: TransactionData,
-    pub balance_variation: i64,
+    // transactions from before fees carry none
+    #[serde(default)]
+    pub fee: i64,
+    // must follow the last nonce applied for the sender
+    #[serde(default)]
+    pub nonce: u64,
+    // scheme of the signature, nodes accept both while a chain migrates
+    #[serde(default)]
+    pub scheme: SignatureScheme,
     #[serde(with = "BigArray")]
     pub signature: [u8; 64],
 }
@@ -25,7 +35,28 @@ pub enum TransactionData {
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DataTransactionType1 {
     #[serde(with = "BigArray")]
-    child_pk: [u8; 33],
+    pub child_pk: [u8; 33],
+}
+
+impl DataTransactionType1 {
+    pub fn generate(child_pk: Vec<u8>) -> Result<Self, Error> {
+        let child_pk: [u8; 33] = match child_pk.as_slice().try_into() {
+            Ok(pk) => pk,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Child public key must have 33 bytes")),
+        };
+        let data = DataTransactionType1 {
+            child_pk,
+        };
+        data.verify()?;
+        Ok(data)
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        if !signature::is_valid_public_key(&self.child_pk) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid child public key"));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -47,19 +78,50 @@ impl DataTransactionType2 {
 pub struct DataTransactionType6 {
     #[serde(with = "BigArray")]
     federated_pk: [u8; 33],
+    // hash function of the block tries of the chain
+    #[serde(default)]
+    pub hash_algorithm: HashAlgorithm,
+    // scheme new transactions of the chain are signed with
+    #[serde(default)]
+    pub signature_scheme: SignatureScheme,
+    // multisig federation signing the blocks, federated_pk alone is a 1-of-1
+    // federation in chains created before it
+    #[serde(default)]
+    federation: Option<Federation>,
 }
 
 impl DataTransactionType6 {
-    pub fn generate() -> Self {
-        let federated_pair = signature::new_pair();
-        let federated_pk = federated_pair.1;
-        keyvalue::insert(b"federated_secret_key", &federated_pair.0).unwrap();
-        DataTransactionType6 {
-            federated_pk
+    pub fn generate() -> Result<Self, Error> {
+        // without a configured federation this node holds the only federated key,
+        // the share it already holds if any
+        let federation = match FEDERATION.as_ref() {
+            Some(federation) => federation.clone(),
+            None => {
+                let federated_pk = match keystore::contains(keystore::FEDERATION_IDENTITY) {
+                    true => KeystoreSigner::new(keystore::FEDERATION_IDENTITY).public_key()?,
+                    false => keystore::generate(keystore::FEDERATION_IDENTITY)?,
+                };
+                Federation::new(1, vec![federated_pk.to_vec()])?
+            },
+        };
+        Ok(DataTransactionType6 {
+            federated_pk: federation.members[0].as_slice().try_into()
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid federation member public key"))?,
+            hash_algorithm: hashing::chain_algorithm(),
+            signature_scheme: signature::chain_scheme(),
+            federation: Some(federation),
+        })
+    }
+
+    pub fn federation(&self) -> Federation {
+        match &self.federation {
+            Some(federation) => federation.clone(),
+            None => Federation { threshold: 1, members: vec![self.federated_pk.to_vec()] },
         }
     }
 }
 
+// the recipient is signed as its raw key, it's only shown and typed as an address
 #[derive(Serialize, Deserialize, Clone)]
 pub struct DataTransactionType7 {
     #[serde(with = "BigArray")]
@@ -67,85 +129,170 @@ pub struct DataTransactionType7 {
     pub balance_variation: i64,
 }
 
+impl DataTransactionType7 {
+    pub fn generate(recipient: &AddressFormat, value: i64) -> Result<Self, Error> {
+        let data = DataTransactionType7 {
+            recipient_pk: recipient.address,
+            balance_variation: value,
+        };
+        data.verify()?;
+        Ok(data)
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        if self.balance_variation <= 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Transfer value must be positive"));
+        }
+        if !signature::is_valid_public_key(&self.recipient_pk) {
+            return Err(Error::new(ErrorKind::InvalidData, "Invalid recipient public key"));
+        }
+        Ok(())
+    }
+
+    pub fn recipient_address(&self) -> AddressFormat {
+        AddressFormat::from_public_key(self.recipient_pk)
+    }
+}
+
+#[derive(Serialize)]
+struct SignedPayload<'a> {
+    // a schnorr signature only commits to the x coordinate of the key, so the
+    // full key is signed to tell 02 and 03 keys apart
+    pk: &'a [u8],
+    timestamp: i64,
+    fee: i64,
+    nonce: u64,
+    scheme: SignatureScheme,
+    data: &'a TransactionData,
+}
+
 impl Transaction {
-    pub fn generate(transaction_type: u32) -> Self {
-        let timestamp = chrono::Utc::now().timestamp();
-        let sk = keyvalue::get(b"secret_key").unwrap().unwrap();
-        let pk = signature::generate_public_key(sk.as_slice());
-        let (data, balance_variation): (TransactionData,i64) = match transaction_type {
-            6 => {
-                let data = DataTransactionType6::generate();
-                (TransactionData::Type6(data), 1000000)
-            }
+    pub fn generate(signer: &dyn Signer, transaction_type: u32) -> Result<Self, Error> {
+        let data = match transaction_type {
+            6 => TransactionData::Type6(DataTransactionType6::generate()?),
             _ => {
                 panic!("invalid transaction type");
             }
         };
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            sk.as_slice());
-
-        Transaction {
-            timestamp,
-            pk,
-            data,
-            balance_variation,
-            signature,
-        }
+        Transaction::sign(signer, data)
     }
 
-    pub fn generate_with_vec(transaction_type: u32, data: Vec<u8>) -> Self {
-        let timestamp = chrono::Utc::now().timestamp();
-        let sk = keyvalue::get(b"secret_key").unwrap().unwrap();
-        let pk = signature::generate_public_key(sk.as_slice());
-        let (data, balance_variation): (TransactionData,i64) = match transaction_type {
-            2 => {
-                let transaction_data = DataTransactionType2::generate(data);
-                (TransactionData::Type2(transaction_data), -1)
-            }
+    pub fn generate_with_vec(signer: &dyn Signer, transaction_type: u32, data: Vec<u8>) -> Result<Self, Error> {
+        let data = match transaction_type {
+            2 => TransactionData::Type2(DataTransactionType2::generate(data)),
             _ => {
                 panic!("invalid transaction type");
             }
         };
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            sk.as_slice());
-
-        Transaction {
-            timestamp,
-            pk,
-            data,
-            balance_variation,
-            signature,
-        }
+        Transaction::sign(signer, data)
     }
 
-    pub fn generate_vec_and_i64(transaction_type: u32, data: Vec<u8>, value: i64) -> Self {
-        let timestamp = chrono::Utc::now().timestamp();
-        let sk = keyvalue::get(b"secret_key").unwrap().unwrap();
-        let pk = signature::generate_public_key(sk.as_slice());
-        let (data, balance_variation): (TransactionData,i64) = match transaction_type {
-            7 => {
-                let transaction_data = DataTransactionType7 {
-                    recipient_pk: data.as_slice().try_into().unwrap(),
-                    balance_variation: value,
-                };
-                (TransactionData::Type7(transaction_data), -1 * (value + 1))
-            }
+    pub fn generate_linked_address(signer: &dyn Signer, transaction_type: u32, child_pk: Vec<u8>) -> Result<Self, Error> {
+        let data = match transaction_type {
+            1 => TransactionData::Type1(DataTransactionType1::generate(child_pk)?),
             _ => {
                 panic!("invalid transaction type");
             }
         };
-        let signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&data).unwrap()).as_bytes(), 
-            sk.as_slice());
+        Transaction::sign(signer, data)
+    }
+
+    pub fn generate_transfer(signer: &dyn Signer, recipient: &AddressFormat, value: i64) -> Result<Self, Error> {
+        Transaction::sign(signer, TransactionData::Type7(DataTransactionType7::generate(recipient, value)?))
+    }
+
+    // builds a transaction paying the minimum fee for its payload
+    fn sign(signer: &dyn Signer, data: TransactionData) -> Result<Self, Error> {
+        let pk = signer.public_key()?;
+        let fee = fee::minimum_fee(&data);
+        Transaction::sign_with_nonce(signer, data, fee, Transaction::next_nonce(&pk)?)
+    }
 
-        Transaction {
+    // signs a transaction with an explicit fee and nonce. Reusing the nonce of a
+    // pending transaction with a higher fee replaces it
+    pub fn sign_with_nonce(signer: &dyn Signer, data: TransactionData, fee: i64, nonce: u64) -> Result<Self, Error> {
+        let timestamp = chrono::Utc::now().timestamp();
+        let pk = signer.public_key()?;
+        let scheme = signature::chain_scheme();
+        let signature = signer.sign_with_scheme(
+            &Transaction::signed_payload(&pk, timestamp, fee, nonce, scheme, &data), scheme)?;
+
+        Ok(Transaction {
             timestamp,
             pk,
             data,
-            balance_variation,
+            fee,
+            nonce,
+            scheme,
             signature,
+        })
+    }
+
+    // next nonce for a local address, above both the nonce recorded in the
+    // blockchain and the last one this node handed out for the same key
+    fn next_nonce(pk: &[u8]) -> Result<u64, Error> {
+        let chain_nonce = match Block::current_addresses_state().get_address(pk) {
+            Some(address) => address.nonce,
+            None => 0,
+        };
+        let key = format!("last_nonce:{}", hex::encode(pk));
+        let local_nonce = match keyvalue::get(key.as_bytes())? {
+            Some(nonce) => u64::from_be_bytes(nonce.as_slice().try_into()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid stored nonce"))?),
+            None => 0,
+        };
+        let nonce = chain_nonce.max(local_nonce) + 1;
+        keyvalue::insert(key.as_bytes(), &nonce.to_be_bytes())?;
+        Ok(nonce)
+    }
+
+    // fee, nonce and scheme are part of the signed payload so they can't be
+    // changed in transit. Fields are encoded as a json object, so no two
+    // transactions share a payload
+    fn signed_payload(pk: &[u8], timestamp: i64, fee: i64, nonce: u64, scheme: SignatureScheme, data: &TransactionData) -> Vec<u8> {
+        serde_json::to_vec(&SignedPayload { pk, timestamp, fee, nonce, scheme, data }).unwrap()
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        Transaction::signed_payload(&self.pk, self.timestamp, self.fee, self.nonce, self.scheme, &self.data)
+    }
+
+    pub fn balance_variation(&self) -> Option<i64> {
+        fee::balance_variation(self)
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        signature::verify_with_scheme(self.scheme, &self.payload(), &self.pk, &self.signature)?;
+        self.verify_contents()
+    }
+
+    // verifies the signatures of a block body in one batch before the rest of
+    // each transaction
+    pub fn verify_batch(transactions: &[Transaction]) -> Result<(), Error> {
+        let payloads: Vec<Vec<u8>> = transactions.iter().map(|t| t.payload()).collect();
+        let items: Vec<BatchItem> = transactions.iter().zip(payloads.iter())
+            .map(|(t, payload)| BatchItem {
+                scheme: t.scheme,
+                msg: payload,
+                public_key: &t.pk,
+                signature: &t.signature,
+            })
+            .collect();
+        if let Err((i, e)) = signature::batch_verify(&items) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Transaction {}: {}", i, e)));
+        }
+        for transaction in transactions {
+            transaction.verify_contents()?;
         }
+        Ok(())
     }
-}
\ No newline at end of file
+
+    fn verify_contents(&self) -> Result<(), Error> {
+        match &self.data {
+            TransactionData::Type1(data) => data.verify()?,
+            TransactionData::Type7(data) => data.verify()?,
+            _ => {},
+        }
+        fee::verify(self)
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::sync::Arc;
 
 use color_eyre::eyre::Result;
 
@@ -11,16 +11,28 @@ mod testing;
 
 use communication::messages::Packet;
 use lazy_static::lazy_static;
-use storage::keyvalue;
+use storage::hashing::{self, HashAlgorithm};
+use transport::limits;
+use storage::mempool;
 use storage::merkle;
-use tokio::time::sleep;
+use storage::snapshot;
+use signature::{hd::{self, ExtendedKey, ExtendedPublicKey}, keystore, SignatureScheme};
+use signature::signer::{DaemonSigner, KeystoreSigner, Signer};
 use validation::block::Block;
-use crate::communication::responses::Response;
-use communication::{transactions::Transaction, transactions::TransactionData, messages::Message, neighbors::Neighbors};
+use communication::{acl, gossip, transactions::Transaction, transactions::TransactionData, messages::Message};
 use validation::block::{LocalBlock};
+use validation::federation::Federation;
+use validation::address::{self, AddressFormat};
 
 const DB_PATH: &str = "./storage.db";
 
+// handed to everything acting on behalf of the node
+#[derive(Clone)]
+pub struct NodeContext {
+    // signs the messages, responses, transactions and blocks of the node
+    pub signer: Arc<dyn Signer>,
+}
+
 lazy_static!{
     pub static ref PORT_NUMBER: String = {
         let args: Vec<String> = std::env::args().collect();
@@ -52,27 +64,109 @@ lazy_static!{
 
     pub static ref INIT_BLOCKCHAIN: bool = {
         let args: Vec<String> = std::env::args().collect();
-        match args.iter().position(|arg| arg == "--init") {
-            Some(_) => true,
-            None => false,
+        args.iter().any(|arg| arg == "--init")
+    };
+
+    // hash function of the block tries, only used when creating a blockchain
+    pub static ref HASH_ALGORITHM: HashAlgorithm = {
+        let args: Vec<String> = std::env::args().collect();
+        match args.iter().position(|arg| arg == "--hash") {
+            Some(i) => {
+                match args.get(i + 1).and_then(|name| HashAlgorithm::from_name(name)) {
+                    Some(algorithm) => algorithm,
+                    None => panic!("Unknown hash algorithm, expected keccak, sha256, blake2s or blake3"),
+                }
+            }
+            None => HashAlgorithm::default(),
         }
     };
 
-    pub static ref GENERATE_NEW_PAIR: bool = {
+    // keystore identity signing for this node
+    pub static ref IDENTITY: String = {
         let args: Vec<String> = std::env::args().collect();
-        match args.iter().position(|arg| arg == "--gen-pair") {
-            Some(_) => true,
-            None => false,
+        match args.iter().position(|arg| arg == "--identity") {
+            Some(i) => {
+                match args.get(i + 1) {
+                    Some(name) => name.to_string(),
+                    None => panic!("No identity name provided"),
+                }
+            }
+            None => keystore::DEFAULT_IDENTITY.to_string(),
         }
     };
 
-    pub static ref EXECUTE_TESTS: bool = {
+    // unix socket of a signing daemon holding the node key, instead of the keystore
+    pub static ref SIGNER_SOCKET: Option<String> = {
+        let args: Vec<String> = std::env::args().collect();
+        match args.iter().position(|arg| arg == "--signer-socket") {
+            Some(i) => {
+                match args.get(i + 1) {
+                    Some(path) => Some(path.to_string()),
+                    None => panic!("No signer socket provided"),
+                }
+            }
+            None => None,
+        }
+    };
+
+    // signature scheme of the transactions, only used when creating a blockchain
+    pub static ref SIGNATURE_SCHEME: SignatureScheme = {
+        let args: Vec<String> = std::env::args().collect();
+        match args.iter().position(|arg| arg == "--scheme") {
+            Some(i) => {
+                match args.get(i + 1).and_then(|name| SignatureScheme::from_name(name)) {
+                    Some(scheme) => scheme,
+                    None => panic!("Unknown signature scheme, expected ecdsa or schnorr"),
+                }
+            }
+            None => SignatureScheme::default(),
+        }
+    };
+
+    // multisig federation of a new blockchain, as <threshold>:<public key>,...
+    pub static ref FEDERATION: Option<Federation> = {
+        let args: Vec<String> = std::env::args().collect();
+        match args.iter().position(|arg| arg == "--federation") {
+            Some(i) => {
+                match args.get(i + 1).map(|arg| Federation::from_arg(arg)) {
+                    Some(Ok(federation)) => Some(federation),
+                    Some(Err(e)) => panic!("Invalid federation: {}", e),
+                    None => panic!("No federation provided"),
+                }
+            }
+            None => None,
+        }
+    };
+
+    // creates the federation share of this node, to be listed in --federation
+    pub static ref GENERATE_FEDERATION_SHARE: bool = {
+        let args: Vec<String> = std::env::args().collect();
+        args.iter().any(|arg| arg == "--gen-federation-share")
+    };
+
+    // json file with the access policy of the node, see communication::acl
+    pub static ref ACL_PATH: Option<String> = {
         let args: Vec<String> = std::env::args().collect();
-        match args.iter().position(|arg| arg == "--test") {
-            Some(_) => true,
-            None => false,
+        match args.iter().position(|arg| arg == "--acl") {
+            Some(i) => {
+                match args.get(i + 1) {
+                    Some(path) => Some(path.to_string()),
+                    None => panic!("No ACL file provided"),
+                }
+            }
+            None => None,
         }
     };
+
+    pub static ref GENERATE_NEW_PAIR: bool = {
+        let args: Vec<String> = std::env::args().collect();
+        args.iter().any(|arg| arg == "--gen-pair")
+    };
+
+    pub static ref EXECUTE_TESTS: bool = {
+        let args: Vec<String> = std::env::args().collect();
+        args.iter().any(|arg| arg == "--test")
+    };
 }
 
 #[tokio::main]
@@ -80,29 +174,49 @@ async fn main() -> Result<()> {
 
     color_eyre::install()?;
 
+    keystore::unlock(&keystore::read_passphrase())?;
+    keystore::set_active(&IDENTITY);
     if GENERATE_NEW_PAIR.to_owned(){
-        storage::keyvalue::insert(b"secret_key", &signature::new_pair().0).unwrap();
+        keystore::generate(&IDENTITY)?;
     }
+    if GENERATE_FEDERATION_SHARE.to_owned() {
+        let public_key = keystore::generate(keystore::FEDERATION_IDENTITY)?;
+        println!("Chave pública federada: {}", hex::encode(public_key));
+    }
+    acl::load()?;
+    if let Some(path) = ACL_PATH.as_ref() {
+        acl::load_file(path)?;
+    }
+    let ctx = NodeContext {
+        signer: match SIGNER_SOCKET.as_ref() {
+            Some(path) => Arc::new(DaemonSigner::new(path)),
+            None => Arc::new(KeystoreSigner::active()),
+        },
+    };
+    let public_key = ctx.signer.public_key()?;
+    mempool::restore().await?;
+    limits::load_bans()?;
 
     println!("Listening on port {}", PORT_NUMBER.to_owned());
     tokio::spawn(
-        transport::listen()
+        transport::listen(ctx.clone())
     );
 
-    if EXECUTE_TESTS.to_owned() == true {
+    if EXECUTE_TESTS.to_owned() {
         testing::transport_tests().await;
         testing::signature_tests();
         testing::storage_tests();
         testing::comm_tests().await;
+        testing::validation_tests();
         return Ok(());
     }
 
-    if INIT_BLOCKCHAIN.to_owned() == true {
+    if INIT_BLOCKCHAIN.to_owned() {
 
         println!("Initializing blockchain...");
-        create_new_blockchain().await;
+        create_new_blockchain(&ctx).await?;
     }
-    if PEER_ADDR.to_owned().len() > 0 {
+    if !PEER_ADDR.to_owned().is_empty() {
 
         // println!("Sending");
 
@@ -134,9 +248,8 @@ async fn main() -> Result<()> {
         // }
     }
     loop {
-        println!("Chave pública do vértice: {}", hex::encode(signature::generate_public_key(
-            &keyvalue::get(b"secret_key").unwrap().unwrap()
-        )));
+        println!("Chave pública do vértice: {}", hex::encode(public_key));
+        println!("Endereço do vértice: {}", address::encode_address(&public_key));
         println!("Menu:");
         println!("1. Enviar mensagem de conexão");
         println!("2. Enviar transação");
@@ -144,6 +257,16 @@ async fn main() -> Result<()> {
         println!("4. Buscar transação pelo cabeçalho");
         println!("5. Transferir valor");
         println!("6. Enviar bloco local e resetar estado");
+        println!("7. Criar snapshot do último bloco");
+        println!("8. Exportar snapshot");
+        println!("9. Importar snapshot");
+        println!("10. Listar identidades");
+        println!("11. Exportar identidade");
+        println!("12. Importar identidade");
+        println!("13. Consultar endereço");
+        println!("14. Derivar chave de sensor");
+        println!("15. Auditar frota");
+        println!("16. Desbanir vértice");
 
         let mut unformatted_input = String::new();
         std::io::stdin().read_line(&mut unformatted_input).unwrap();
@@ -157,24 +280,47 @@ async fn main() -> Result<()> {
         }
         match input {
             1 => {
-                let message = Packet::Message(Message::generate(1));
-                transport::send(PEER_ADDR.to_string(), serde_json::to_string(&message).unwrap()).await;
+                let message = match Message::generate(&ctx, 1) {
+                    Ok(message) => Packet::Message(message),
+                    Err(e) => {
+                        println!("Erro ao assinar mensagem: {}", e);
+                        continue
+                    },
+                };
+                if let Err(e) = transport::send(&ctx, PEER_ADDR.to_string(), serde_json::to_string(&message).unwrap()).await {
+                    println!("Erro ao enviar mensagem: {}", e);
+                }
             },
             2 => {
                 println!("Insira dados de transação:");
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
-                let transaction = Transaction::generate_with_vec(2, (input).as_bytes().to_vec());
-                LocalBlock::insert_transaction(transaction.clone()).await;
-                let message = Packet::Message(Message::generate_with_transaction(3, transaction.clone()));
-                for (addr, _) in Neighbors::restore().neighbors {
-                    println!("Enviando para {}", addr);
-                    transport::send(addr, serde_json::to_string(&message).unwrap()).await;
+                let transaction = match Transaction::generate_with_vec(ctx.signer.as_ref(), 2, (input).as_bytes().to_vec()) {
+                    Ok(transaction) => transaction,
+                    Err(e) => {
+                        println!("Erro ao assinar transação: {}", e);
+                        continue
+                    },
+                };
+                match LocalBlock::insert_transaction(&ctx, transaction.clone()).await {
+                    Ok(()) => {
+                        gossip::mark_seen(&transaction.signature).await;
+                        gossip::propagate(ctx.clone(), transaction, None).await
+                    },
+                    Err(e) => println!("Transação rejeitada: {}", e),
                 }
             },
             3 => {
-                let message = Packet::Message(Message::generate(5));
-                transport::send(PEER_ADDR.to_string(), serde_json::to_string(&message).unwrap()).await;
+                let message = match Message::generate(&ctx, 5) {
+                    Ok(message) => Packet::Message(message),
+                    Err(e) => {
+                        println!("Erro ao assinar mensagem: {}", e);
+                        continue
+                    },
+                };
+                if let Err(e) = transport::send(&ctx, PEER_ADDR.to_string(), serde_json::to_string(&message).unwrap()).await {
+                    println!("Erro ao enviar mensagem: {}", e);
+                }
             },
             4 => {
                 println!("Insira cabeçalho de transação:");
@@ -187,6 +333,8 @@ async fn main() -> Result<()> {
                     Some(t) => {
                         println!("Transação:");
                         println!("Timestamp: {:?}", t.timestamp);
+                        println!("Taxa: {:?}", t.fee);
+                        println!("Nonce: {:?}", t.nonce);
                         match t.data {
                             TransactionData::Type2(t_data) => {
                                 println!("Tipo da Transação: 2");
@@ -199,8 +347,8 @@ async fn main() -> Result<()> {
                             TransactionData::Type7(t_data) => {
                                 println!("Transação Tipo: 7");
                                 println!("Valor transferido: {:?}", t_data.balance_variation);
-                                print!("Chave pública do remetente: {:?}", hex::encode(t.pk));
-                                println!("Chave pública do destinatário: {:?}", hex::encode(t_data.recipient_pk));
+                                println!("Endereço do remetente: {}", address::encode_address(&t.pk));
+                                println!("Endereço do destinatário: {}", t_data.recipient_address().encode());
                             },
                             _ => {
                                 println!("Transação Tipo: outro");
@@ -213,7 +361,7 @@ async fn main() -> Result<()> {
                 }
             },
             5 => {
-                println!("Insira chave pública do destinatário:");
+                println!("Insira endereço do destinatário (iot1...):");
                 let mut input = String::new();
                 std::io::stdin().read_line(&mut input).unwrap();
                 input = input.trim().to_string();
@@ -229,27 +377,235 @@ async fn main() -> Result<()> {
                         continue
                     },
                 }
-                let transaction = Transaction::generate_vec_and_i64(7, 
-                                    hex::decode(input).unwrap(), value);
+                let recipient = match AddressFormat::parse(&input) {
+                    Ok(recipient) => recipient,
+                    Err(e) => {
+                        println!("Endereço inválido: {}", e);
+                        continue
+                    },
+                };
+                let transaction = match Transaction::generate_transfer(ctx.signer.as_ref(), &recipient, value) {
+                    Ok(transaction) => transaction,
+                    Err(e) => {
+                        println!("Transferência inválida: {}", e);
+                        continue
+                    },
+                };
 
                 
-                LocalBlock::insert_transaction(transaction.clone()).await;
-                let message = Packet::Message(Message::generate_with_transaction(3, transaction.clone()));
-                for (addr, _) in Neighbors::restore().neighbors {
-                    println!("Enviando para {}", addr);
-                    transport::send(addr, serde_json::to_string(&message).unwrap()).await;
+                match LocalBlock::insert_transaction(&ctx, transaction.clone()).await {
+                    Ok(()) => {
+                        gossip::mark_seen(&transaction.signature).await;
+                        gossip::propagate(ctx.clone(), transaction, None).await
+                    },
+                    Err(e) => println!("Transação rejeitada: {}", e),
                 }
             },
             6 => {
                 println!("SENDING BLOCK AND RESETTING");
                 if INIT_BLOCKCHAIN.to_owned() {
-                    let block = Block::create_from_local_trie().await;
-                    block.clone().save_to_blockchain();
-                    block.clone().send_block(None).await;
-
-                    let mut t_n = merkle::LOCAL_BLOCK_SIZE.write().await;
-                    *t_n = 0;
-                    merkle::reset_local_trie().await;
+                    if let Err(e) = LocalBlock::seal(&ctx).await {
+                        println!("Erro ao selar bloco: {}", e);
+                    }
+                }
+            },
+            7 => {
+                println!("Insira nome do snapshot:");
+                let mut name = String::new();
+                std::io::stdin().read_line(&mut name).unwrap();
+                match LocalBlock::create_snapshot(name.trim()).await {
+                    Ok(Some(header)) => println!("Snapshot criado: {}", hex::encode(header)),
+                    Ok(None) => println!("Nenhum bloco salvo"),
+                    Err(e) => println!("Erro ao criar snapshot: {}", e),
+                }
+            },
+            8 => {
+                println!("Insira nome do snapshot:");
+                let mut name = String::new();
+                std::io::stdin().read_line(&mut name).unwrap();
+                println!("Insira caminho do arquivo:");
+                let mut path = String::new();
+                std::io::stdin().read_line(&mut path).unwrap();
+                if let Err(e) = snapshot::export(name.trim(), path.trim()) {
+                    println!("Erro ao exportar snapshot: {}", e);
+                }
+            },
+            9 => {
+                println!("Insira caminho do arquivo:");
+                let mut path = String::new();
+                std::io::stdin().read_line(&mut path).unwrap();
+                match LocalBlock::import_snapshot(path.trim()).await {
+                    Ok(name) => println!("Snapshot importado: {}", name),
+                    Err(e) => println!("Erro ao importar snapshot: {}", e),
+                }
+            },
+            10 => {
+                match keystore::identities() {
+                    Ok(identities) => for (name, public_key) in identities {
+                        println!("{}: {}", name, public_key);
+                    },
+                    Err(e) => println!("Erro ao listar identidades: {}", e),
+                }
+            },
+            11 => {
+                println!("Insira nome da identidade:");
+                let mut name = String::new();
+                std::io::stdin().read_line(&mut name).unwrap();
+                println!("Insira caminho do arquivo:");
+                let mut path = String::new();
+                std::io::stdin().read_line(&mut path).unwrap();
+                if let Err(e) = keystore::export(name.trim(), path.trim()) {
+                    println!("Erro ao exportar identidade: {}", e);
+                }
+            },
+            12 => {
+                println!("Insira nome da identidade:");
+                let mut name = String::new();
+                std::io::stdin().read_line(&mut name).unwrap();
+                println!("Insira caminho do arquivo:");
+                let mut path = String::new();
+                std::io::stdin().read_line(&mut path).unwrap();
+                println!("Insira senha do arquivo:");
+                let mut passphrase = zeroize::Zeroizing::new(String::new());
+                std::io::stdin().read_line(&mut passphrase).unwrap();
+                match keystore::import_file(name.trim(), path.trim(), passphrase.trim()) {
+                    Ok(public_key) => println!("Identidade importada: {}", hex::encode(public_key)),
+                    Err(e) => println!("Erro ao importar identidade: {}", e),
+                }
+            },
+            13 => {
+                println!("Insira endereço:");
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let address = match AddressFormat::parse(&input) {
+                    Ok(address) => address,
+                    Err(e) => {
+                        println!("Endereço inválido: {}", e);
+                        continue
+                    },
+                };
+                match Block::current_addresses_state().get_by_address(&address) {
+                    Some(state) => {
+                        println!("Saldo: {}", state.balance);
+                        println!("Nonce: {}", state.nonce);
+                    },
+                    None => println!("Endereço sem transações"),
+                }
+            },
+            14 => {
+                let master = match keystore::hd_seed().and_then(|seed| Ok(ExtendedKey::from_seed(seed.as_slice())?)) {
+                    Ok(master) => master,
+                    Err(e) => {
+                        println!("Erro ao derivar chave mestra: {}", e);
+                        continue
+                    },
+                };
+                println!("Insira índice do sensor:");
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let child = match input.trim().parse().map(|index| master.fleet_key(index)) {
+                    Ok(Ok(child)) => child,
+                    _ => {
+                        println!("Índice inválido");
+                        continue
+                    },
+                };
+                let name = format!("sensor-{}", input.trim());
+                match keystore::import(&name, child.secret_key().as_slice()) {
+                    Ok(public_key) => {
+                        println!("Identidade {} criada: {}", name, address::encode_address(&public_key));
+                        println!("Exporte-a com a opção 11 para provisionar o sensor");
+                    },
+                    Err(e) => {
+                        println!("Erro ao salvar chave do sensor: {}", e);
+                        continue
+                    },
+                }
+                // registers the sensor among the linked addresses of the gateway
+                let transaction = match Transaction::generate_linked_address(ctx.signer.as_ref(), 1, child.public_key().to_vec()) {
+                    Ok(transaction) => transaction,
+                    Err(e) => {
+                        println!("Registro inválido: {}", e);
+                        continue
+                    },
+                };
+                match LocalBlock::insert_transaction(&ctx, transaction.clone()).await {
+                    Ok(()) => {
+                        gossip::mark_seen(&transaction.signature).await;
+                        gossip::propagate(ctx.clone(), transaction, None).await
+                    },
+                    Err(e) => println!("Transação rejeitada: {}", e),
+                }
+            },
+            15 => {
+                println!("Insira chave pública estendida da frota (vazio para a deste vértice):");
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let account = if input.trim().is_empty() {
+                    // auditing doesn't create the seed, unlike option 14
+                    match keystore::secret_key(keystore::HD_SEED_IDENTITY)
+                        .and_then(|seed| Ok(ExtendedKey::from_seed(seed.as_slice())?))
+                        .and_then(|master| Ok(master.derive_path(hd::FLEET_ACCOUNT)?)) {
+                        Ok(account) => account.public(),
+                        Err(e) => {
+                            println!("Erro ao derivar chave da frota: {}", e);
+                            continue
+                        },
+                    }
+                } else {
+                    match ExtendedPublicKey::decode(&input) {
+                        Ok(account) => account,
+                        Err(e) => {
+                            println!("Chave estendida inválida: {}", e);
+                            continue
+                        },
+                    }
+                };
+                println!("Chave pública estendida da frota: {}", account.encode());
+                println!("Insira endereço do gateway:");
+                let mut gateway = String::new();
+                std::io::stdin().read_line(&mut gateway).unwrap();
+                let linked = match AddressFormat::parse(&gateway) {
+                    Ok(gateway) => Block::current_addresses_state().get_by_address(&gateway)
+                        .map(|state| state.linked_addresses).unwrap_or_default(),
+                    Err(e) => {
+                        println!("Endereço inválido: {}", e);
+                        continue
+                    },
+                };
+                println!("Insira número de sensores:");
+                let mut count = String::new();
+                std::io::stdin().read_line(&mut count).unwrap();
+                let sensors = match count.trim().parse().map(|count| hd::fleet_public_keys(&account, count)) {
+                    Ok(Ok(sensors)) => sensors,
+                    _ => {
+                        println!("Número inválido");
+                        continue
+                    },
+                };
+                for (index, public_key) in sensors.iter().enumerate() {
+                    let registered = linked.iter().any(|address| &address.address == public_key);
+                    println!("Sensor {}: {} {}", index, address::encode_address(public_key),
+                        if registered { "registrado" } else { "não registrado" });
+                }
+                for address in linked.iter().filter(|address| !sensors.contains(&address.address)) {
+                    println!("Endereço vinculado fora da frota: {}", address.encode());
+                }
+            },
+            16 => {
+                println!("Insira IP do vértice:");
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input).unwrap();
+                let ip: std::net::IpAddr = match input.trim().parse() {
+                    Ok(ip) => ip,
+                    Err(_) => {
+                        println!("IP inválido");
+                        continue
+                    },
+                };
+                match limits::unban(&ip) {
+                    Ok(()) => println!("Vértice desbanido: {}", ip),
+                    Err(e) => println!("Erro ao desbanir vértice: {}", e),
                 }
             },
             _ => {
@@ -291,20 +647,20 @@ async fn main() -> Result<()> {
     // node2.close();
     // node3.close();
     // node4.close();
-
-    Ok(())
 }
 
-async fn create_new_blockchain() {
+async fn create_new_blockchain(ctx: &NodeContext) -> Result<(), std::io::Error> {
+    hashing::set_chain_algorithm(*HASH_ALGORITHM);
+    signature::set_chain_scheme(*SIGNATURE_SCHEME);
     merkle::reset_local_trie().await;
-    let first_transaction = Transaction::generate(6);
+    let first_transaction = Transaction::generate(ctx.signer.as_ref(), 6)?;
     // let transaction: Transaction = serde_json::from_str(&first_transaction).unwrap();
     merkle::insert(&first_transaction.signature, 
         serde_json::to_vec(&first_transaction).unwrap().as_slice()).await;
-    let initial_block: Block = Block::create_from_local_trie().await;
-    initial_block.save_to_blockchain();
+    let mut initial_block: Block = Block::create_from_local_trie(ctx).await?;
+    initial_block.sign_by_federation(ctx).await?;
+    initial_block.save_to_blockchain()?;
     
-    let mut t_n = merkle::LOCAL_BLOCK_SIZE.write().await;
-    *t_n = 0;
     merkle::reset_local_trie().await;
+    Ok(())
 }
\ No newline at end of file
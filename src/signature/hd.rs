@@ -0,0 +1,276 @@
+use secp256k1::hashes::{hash160, hmac::{Hmac, HmacEngine}, sha256d, sha512, Hash, HashEngine};
+use secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+use zeroize::Zeroizing;
+
+use super::SignatureError;
+
+// BIP32 hierarchical deterministic keys over secp256k1, so a gateway can derive
+// the keys of its sensors from a single seed and re-derive them later
+
+// indexes from HARDENED on can only be derived from the secret key
+pub const HARDENED: u32 = 1 << 31;
+// account holding the sensor keys of a fleet, sensor i is FLEET_ACCOUNT/i
+pub const FLEET_ACCOUNT: &str = "m/0'";
+
+const MASTER_HMAC_KEY: &[u8] = b"Bitcoin seed";
+// version bytes of a mainnet xpub
+const XPUB_VERSION: [u8; 4] = [0x04, 0x88, 0xb2, 0x1e];
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+fn hmac_sha512(key: &[u8], data: &[u8]) -> Zeroizing<[u8; 64]> {
+    let mut engine = HmacEngine::<sha512::Hash>::new(key);
+    engine.input(data);
+    Zeroizing::new(Hmac::<sha512::Hash>::from_engine(engine).into_inner())
+}
+
+fn split(i: &[u8; 64]) -> ([u8; 32], [u8; 32]) {
+    let mut left = [0u8; 32];
+    let mut right = [0u8; 32];
+    left.copy_from_slice(&i[..32]);
+    right.copy_from_slice(&i[32..]);
+    (left, right)
+}
+
+fn tweak(left: [u8; 32]) -> Result<Scalar, SignatureError> {
+    // a tweak over the curve order makes the index invalid, BIP32 skips it
+    Scalar::from_be_bytes(left).map_err(|_| SignatureError::InvalidSecretKey)
+}
+
+// first bytes of the hash160 of a key, identifying the parent of its children
+fn fingerprint(public_key: &[u8; 33]) -> [u8; 4] {
+    let mut fingerprint = [0u8; 4];
+    fingerprint.copy_from_slice(&hash160::Hash::hash(public_key)[..4]);
+    fingerprint
+}
+
+// base58 with the first 4 bytes of a double sha256 appended
+fn base58check_encode(data: &[u8]) -> String {
+    let payload = [data, &sha256d::Hash::hash(data)[..4]].concat();
+    let mut digits: Vec<u8> = vec![];
+    for &byte in payload.iter() {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let zeros = payload.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&digit| BASE58_ALPHABET[digit as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base58check_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes: Vec<u8> = vec![];
+    for c in encoded.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|&a| a == c)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let zeros = encoded.bytes().take_while(|&c| c == b'1').count();
+    let payload: Vec<u8> = std::iter::repeat_n(0, zeros).chain(bytes.into_iter().rev()).collect();
+    if payload.len() < 4 {
+        return None;
+    }
+    let (data, checksum) = payload.split_at(payload.len() - 4);
+    match sha256d::Hash::hash(data)[..4] == *checksum {
+        true => Some(data.to_vec()),
+        false => None,
+    }
+}
+
+#[derive(Clone)]
+pub struct ExtendedKey {
+    secret_key: Zeroizing<[u8; 32]>,
+    chain_code: [u8; 32],
+    depth: u8,
+    parent_fingerprint: [u8; 4],
+    child_number: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtendedPublicKey {
+    pub public_key: [u8; 33],
+    pub chain_code: [u8; 32],
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+}
+
+impl ExtendedKey {
+    pub fn from_seed(seed: &[u8]) -> Result<Self, SignatureError> {
+        if seed.len() < 16 || seed.len() > 64 {
+            return Err(SignatureError::InvalidSecretKey);
+        }
+        let (secret_key, chain_code) = split(&hmac_sha512(MASTER_HMAC_KEY, seed));
+        SecretKey::from_slice(&secret_key).map_err(|_| SignatureError::InvalidSecretKey)?;
+        Ok(ExtendedKey {
+            secret_key: Zeroizing::new(secret_key),
+            chain_code,
+            depth: 0,
+            parent_fingerprint: [0; 4],
+            child_number: 0,
+        })
+    }
+
+    pub fn secret_key(&self) -> Zeroizing<[u8; 32]> {
+        self.secret_key.clone()
+    }
+
+    pub fn public_key(&self) -> [u8; 33] {
+        let secp = Secp256k1::signing_only();
+        // the secret key was validated when the extended key was built
+        let sk = SecretKey::from_slice(self.secret_key.as_slice()).unwrap();
+        PublicKey::from_secret_key(&secp, &sk).serialize()
+    }
+
+    pub fn public(&self) -> ExtendedPublicKey {
+        ExtendedPublicKey {
+            public_key: self.public_key(),
+            chain_code: self.chain_code,
+            depth: self.depth,
+            parent_fingerprint: self.parent_fingerprint,
+            child_number: self.child_number,
+        }
+    }
+
+    pub fn derive_child(&self, index: u32) -> Result<Self, SignatureError> {
+        let mut data = Zeroizing::new(Vec::with_capacity(37));
+        if index >= HARDENED {
+            data.push(0);
+            data.extend_from_slice(self.secret_key.as_slice());
+        } else {
+            data.extend_from_slice(&self.public_key());
+        }
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let (left, chain_code) = split(&hmac_sha512(&self.chain_code, &data));
+        let sk = SecretKey::from_slice(self.secret_key.as_slice()).unwrap()
+            .add_tweak(&tweak(left)?)
+            .map_err(|_| SignatureError::InvalidSecretKey)?;
+        Ok(ExtendedKey {
+            secret_key: Zeroizing::new(sk.secret_bytes()),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+            parent_fingerprint: fingerprint(&self.public_key()),
+            child_number: index,
+        })
+    }
+
+    // path as "m/0'/5", a quote marks hardened indexes
+    pub fn derive_path(&self, path: &str) -> Result<Self, SignatureError> {
+        parse_path(path)?.into_iter()
+            .try_fold(self.clone(), |key, index| key.derive_child(index))
+    }
+
+    // key of sensor `index` of the fleet of this master key
+    pub fn fleet_key(&self, index: u32) -> Result<Self, SignatureError> {
+        if index >= HARDENED {
+            return Err(SignatureError::InvalidSecretKey);
+        }
+        self.derive_path(FLEET_ACCOUNT)?.derive_child(index)
+    }
+}
+
+impl ExtendedPublicKey {
+    // only non hardened children can be derived without the secret key
+    pub fn derive_child(&self, index: u32) -> Result<Self, SignatureError> {
+        if index >= HARDENED {
+            return Err(SignatureError::InvalidPublicKey);
+        }
+        let mut data = Vec::with_capacity(37);
+        data.extend_from_slice(&self.public_key);
+        data.extend_from_slice(&index.to_be_bytes());
+
+        let (left, chain_code) = split(&hmac_sha512(&self.chain_code, &data));
+        let secp = Secp256k1::verification_only();
+        let pk = PublicKey::from_slice(&self.public_key)
+            .map_err(|_| SignatureError::InvalidPublicKey)?
+            .add_exp_tweak(&secp, &tweak(left)?)
+            .map_err(|_| SignatureError::InvalidPublicKey)?;
+        Ok(ExtendedPublicKey {
+            public_key: pk.serialize(),
+            chain_code,
+            depth: self.depth.saturating_add(1),
+            parent_fingerprint: fingerprint(&self.public_key),
+            child_number: index,
+        })
+    }
+
+    // BIP32 xpub, enough to audit a fleet without its secrets
+    pub fn encode(&self) -> String {
+        let mut data = Vec::with_capacity(78);
+        data.extend_from_slice(&XPUB_VERSION);
+        data.push(self.depth);
+        data.extend_from_slice(&self.parent_fingerprint);
+        data.extend_from_slice(&self.child_number.to_be_bytes());
+        data.extend_from_slice(&self.chain_code);
+        data.extend_from_slice(&self.public_key);
+        base58check_encode(&data)
+    }
+
+    pub fn decode(encoded: &str) -> Result<Self, SignatureError> {
+        let data = base58check_decode(encoded.trim()).ok_or(SignatureError::InvalidPublicKey)?;
+        if data.len() != 78 || data[..4] != XPUB_VERSION || PublicKey::from_slice(&data[45..]).is_err() {
+            return Err(SignatureError::InvalidPublicKey);
+        }
+        let mut parent_fingerprint = [0u8; 4];
+        let mut child_number = [0u8; 4];
+        let mut chain_code = [0u8; 32];
+        let mut public_key = [0u8; 33];
+        parent_fingerprint.copy_from_slice(&data[5..9]);
+        child_number.copy_from_slice(&data[9..13]);
+        chain_code.copy_from_slice(&data[13..45]);
+        public_key.copy_from_slice(&data[45..]);
+        let key = ExtendedPublicKey {
+            public_key,
+            chain_code,
+            depth: data[4],
+            parent_fingerprint,
+            child_number: u32::from_be_bytes(child_number),
+        };
+        // a master key has no parent
+        if key.depth == 0 && (key.parent_fingerprint != [0; 4] || key.child_number != 0) {
+            return Err(SignatureError::InvalidPublicKey);
+        }
+        Ok(key)
+    }
+}
+
+pub fn parse_path(path: &str) -> Result<Vec<u32>, SignatureError> {
+    let mut parts = path.trim().split('/');
+    if parts.next() != Some("m") {
+        return Err(SignatureError::InvalidSecretKey);
+    }
+    parts.map(|part| {
+        let (number, hardened) = match part.strip_suffix('\'').or_else(|| part.strip_suffix('h')) {
+            Some(number) => (number, true),
+            None => (part, false),
+        };
+        match number.parse::<u32>() {
+            Ok(index) if index < HARDENED => Ok(if hardened { index + HARDENED } else { index }),
+            _ => Err(SignatureError::InvalidSecretKey),
+        }
+    }).collect()
+}
+
+// public keys of sensors 0..count of a fleet, from the extended public key of
+// its FLEET_ACCOUNT
+pub fn fleet_public_keys(account: &ExtendedPublicKey, count: u32) -> Result<Vec<[u8; 33]>, SignatureError> {
+    (0..count.min(HARDENED))
+        .map(|index| account.derive_child(index).map(|child| child.public_key))
+        .collect()
+}
@@ -0,0 +1,254 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::RwLock;
+
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use lazy_static::lazy_static;
+use secp256k1::SecretKey;
+use secp256k1::rand::{thread_rng, RngCore};
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+use crate::storage::keyvalue;
+
+use super::{generate_public_key, new_pair};
+
+// identity signing the messages, blocks and transactions of the node
+pub const DEFAULT_IDENTITY: &str = "default";
+// identity holding the federated key created by the genesis Type6 transaction
+pub const FEDERATION_IDENTITY: &str = "federation";
+// seed of the fleet keys derived with signature::hd, kept apart from the node
+// identity so the node key is never used as a master key
+pub const HD_SEED_IDENTITY: &str = "hd-seed";
+
+// sled tree: identity name -> json EncryptedKey
+const KEYSTORE: &str = "keystore";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+// a secret key encrypted with XChaCha20-Poly1305 under a key derived from the
+// passphrase with argon2id. The public key is kept in clear to list identities
+#[derive(Serialize, Deserialize)]
+struct EncryptedKey {
+    public_key: String,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+// decrypted keys are only held here, and are zeroized when dropped
+struct Keystore {
+    passphrase: Option<Zeroizing<String>>,
+    active: String,
+    keys: HashMap<String, Zeroizing<[u8; 32]>>,
+}
+
+lazy_static! {
+    static ref KEYSTORE_STATE: RwLock<Keystore> = RwLock::new(Keystore {
+        passphrase: None,
+        active: DEFAULT_IDENTITY.to_string(),
+        keys: HashMap::new(),
+    });
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn locked() -> Error {
+    Error::new(ErrorKind::PermissionDenied, "Keystore locked")
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut *key)
+        .map_err(to_io_error)?;
+    Ok(key)
+}
+
+fn encrypt(secret_key: &[u8; 32], passphrase: &str) -> Result<EncryptedKey, Error> {
+    let mut salt = [0u8; SALT_LENGTH];
+    let mut nonce = [0u8; NONCE_LENGTH];
+    thread_rng().fill_bytes(&mut salt);
+    thread_rng().fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*key));
+    let ciphertext = cipher.encrypt(XNonce::from_slice(&nonce), secret_key.as_slice())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Key encryption failed"))?;
+
+    Ok(EncryptedKey {
+        public_key: hex::encode(generate_public_key(secret_key)?),
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(ciphertext),
+    })
+}
+
+fn decrypt(encrypted: &EncryptedKey, passphrase: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let salt = hex::decode(&encrypted.salt).map_err(to_io_error)?;
+    let nonce = hex::decode(&encrypted.nonce).map_err(to_io_error)?;
+    let ciphertext = hex::decode(&encrypted.ciphertext).map_err(to_io_error)?;
+    if nonce.len() != NONCE_LENGTH {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid key nonce"));
+    }
+
+    let key = derive_key(passphrase, &salt)?;
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&*key));
+    let plaintext = Zeroizing::new(cipher.decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+        .map_err(|_| Error::new(ErrorKind::PermissionDenied, "Wrong passphrase or corrupted key"))?);
+    if plaintext.len() != 32 {
+        return Err(Error::new(ErrorKind::InvalidData, "Invalid secret key length"));
+    }
+
+    let mut secret_key = Zeroizing::new([0u8; 32]);
+    secret_key.copy_from_slice(&plaintext);
+    Ok(secret_key)
+}
+
+// passphrase from the KEYSTORE_PASSPHRASE environment variable, asked on the
+// terminal when it is not set
+pub fn read_passphrase() -> Zeroizing<String> {
+    if let Ok(passphrase) = std::env::var("KEYSTORE_PASSPHRASE") {
+        return Zeroizing::new(passphrase);
+    }
+    println!("Insira a senha do keystore:");
+    let mut passphrase = Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut passphrase).unwrap();
+    Zeroizing::new(passphrase.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+// decrypts every stored identity, failing if the passphrase does not match.
+// Keys stored in clear by older versions are encrypted and removed. An empty
+// passphrase would leave them as good as in clear, so it is refused
+pub fn unlock(passphrase: &str) -> Result<(), Error> {
+    if passphrase.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Empty keystore passphrase"));
+    }
+    let tree = keyvalue::open_tree(KEYSTORE)?;
+    let mut keys = HashMap::new();
+    for entry in tree.iter() {
+        let (name, value) = entry.map_err(to_io_error)?;
+        let encrypted: EncryptedKey = serde_json::from_slice(&value)?;
+        keys.insert(String::from_utf8_lossy(&name).to_string(), decrypt(&encrypted, passphrase)?);
+    }
+
+    {
+        let mut state = KEYSTORE_STATE.write().unwrap();
+        state.passphrase = Some(Zeroizing::new(passphrase.to_string()));
+        state.keys = keys;
+    }
+
+    migrate_plain_key(b"secret_key", DEFAULT_IDENTITY)?;
+    migrate_plain_key(b"federated_secret_key", FEDERATION_IDENTITY)?;
+    Ok(())
+}
+
+pub fn migrate_plain_key(key: &[u8], name: &str) -> Result<(), Error> {
+    if let Some(secret_key) = keyvalue::get(key)? {
+        let secret_key = Zeroizing::new(secret_key);
+        if !contains(name) {
+            import(name, &secret_key)?;
+        }
+        keyvalue::remove(key)?;
+    }
+    Ok(())
+}
+
+pub fn contains(name: &str) -> bool {
+    KEYSTORE_STATE.read().unwrap().keys.contains_key(name)
+}
+
+// stores an encrypted secret key under a name, replacing the previous one
+pub fn import(name: &str, secret_key: &[u8]) -> Result<[u8; 33], Error> {
+    if SecretKey::from_slice(secret_key).is_err() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Invalid secret key"));
+    }
+    let mut key = Zeroizing::new([0u8; 32]);
+    key.copy_from_slice(secret_key);
+
+    let mut state = KEYSTORE_STATE.write().unwrap();
+    let encrypted = match &state.passphrase {
+        Some(passphrase) => encrypt(&key, passphrase)?,
+        None => return Err(locked()),
+    };
+    let tree = keyvalue::open_tree(KEYSTORE)?;
+    tree.insert(name, serde_json::to_vec(&encrypted)?).map_err(to_io_error)?;
+    tree.flush().map_err(to_io_error)?;
+
+    let public_key = generate_public_key(&*key)?;
+    state.keys.insert(name.to_string(), key);
+    Ok(public_key)
+}
+
+pub fn generate(name: &str) -> Result<[u8; 33], Error> {
+    let (secret_key, _) = new_pair();
+    let secret_key = Zeroizing::new(secret_key);
+    import(name, &*secret_key)
+}
+
+pub fn remove(name: &str) -> Result<(), Error> {
+    let tree = keyvalue::open_tree(KEYSTORE)?;
+    tree.remove(name).map_err(to_io_error)?;
+    tree.flush().map_err(to_io_error)?;
+    KEYSTORE_STATE.write().unwrap().keys.remove(name);
+    Ok(())
+}
+
+// writes an identity, still encrypted with the keystore passphrase, to a file
+pub fn export(name: &str, path: &str) -> Result<(), Error> {
+    let tree = keyvalue::open_tree(KEYSTORE)?;
+    match tree.get(name).map_err(to_io_error)? {
+        Some(encrypted) => fs::write(path, encrypted),
+        None => Err(Error::new(ErrorKind::NotFound, "Identity not found")),
+    }
+}
+
+// reads an identity exported by another keystore, encrypted with `passphrase`,
+// and stores it encrypted with the passphrase of this keystore
+pub fn import_file(name: &str, path: &str, passphrase: &str) -> Result<[u8; 33], Error> {
+    let encrypted: EncryptedKey = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let secret_key = decrypt(&encrypted, passphrase)?;
+    import(name, &*secret_key)
+}
+
+pub fn secret_key(name: &str) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let state = KEYSTORE_STATE.read().unwrap();
+    match state.keys.get(name) {
+        Some(key) => Ok(key.clone()),
+        None if state.passphrase.is_none() => Err(locked()),
+        None => Err(Error::new(ErrorKind::NotFound, "Identity not found")),
+    }
+}
+
+// seed of the fleet of this gateway, created on first use
+pub fn hd_seed() -> Result<Zeroizing<[u8; 32]>, Error> {
+    if !contains(HD_SEED_IDENTITY) {
+        generate(HD_SEED_IDENTITY)?;
+    }
+    secret_key(HD_SEED_IDENTITY)
+}
+
+pub fn active() -> String {
+    KEYSTORE_STATE.read().unwrap().active.clone()
+}
+
+pub fn set_active(name: &str) {
+    KEYSTORE_STATE.write().unwrap().active = name.to_string();
+}
+
+// stored identities and their public keys, without decrypting them
+pub fn identities() -> Result<Vec<(String, String)>, Error> {
+    let tree = keyvalue::open_tree(KEYSTORE)?;
+    let mut identities = vec![];
+    for entry in tree.iter() {
+        let (name, value) = entry.map_err(to_io_error)?;
+        let encrypted: EncryptedKey = serde_json::from_slice(&value)?;
+        identities.push((String::from_utf8_lossy(&name).to_string(), encrypted.public_key));
+    }
+    Ok(identities)
+}
@@ -1,36 +1,204 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use secp256k1::rand::{thread_rng};
-use secp256k1::{Message, Secp256k1, SecretKey, PublicKey, ecdsa::Signature, Error};
+pub mod hd;
+pub mod keystore;
+pub mod signer;
+
+use std::fmt;
+
+use secp256k1::rand::{thread_rng, RngCore};
+use secp256k1::{Message, Secp256k1, SecretKey, PublicKey, KeyPair, XOnlyPublicKey, ecdsa::Signature, schnorr};
 use secp256k1::hashes::sha256;
+use serde::{Serialize, Deserialize};
+
+use crate::storage::keyvalue;
+
+// signature scheme of the transactions of a chain, chosen when the blockchain
+// is created and recorded in its genesis Type6 transaction
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SignatureScheme {
+    // transactions created before the scheme was configurable use ecdsa
+    #[default]
+    Ecdsa,
+    // BIP340, verified against the x-only form of the 33 bytes public key
+    Schnorr,
+}
+
+impl SignatureScheme {
+    pub fn from_name(name: &str) -> Option<SignatureScheme> {
+        match name.to_lowercase().as_str() {
+            "ecdsa" => Some(SignatureScheme::Ecdsa),
+            "schnorr" => Some(SignatureScheme::Schnorr),
+            _ => None,
+        }
+    }
+}
+
+pub fn chain_scheme() -> SignatureScheme {
+    match keyvalue::get(b"signature_scheme").unwrap() {
+        Some(scheme) => serde_json::from_slice(&scheme).unwrap(),
+        None => SignatureScheme::default(),
+    }
+}
+
+pub fn set_chain_scheme(scheme: SignatureScheme) {
+    keyvalue::insert(b"signature_scheme", &serde_json::to_vec(&scheme).unwrap()).unwrap();
+}
+
+// errors of the signature module, so a malformed key or signature received
+// from a peer is rejected instead of crashing the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    InvalidSecretKey,
+    InvalidPublicKey,
+    InvalidSignature,
+    VerificationFailed,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignatureError::InvalidSecretKey => write!(f, "Invalid secret key"),
+            SignatureError::InvalidPublicKey => write!(f, "Invalid public key"),
+            SignatureError::InvalidSignature => write!(f, "Malformed signature"),
+            SignatureError::VerificationFailed => write!(f, "Signature verification failed"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+impl From<SignatureError> for std::io::Error {
+    fn from(e: SignatureError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
 
 pub fn new_pair() -> ([u8; 32], [u8; 33]){
     let secp = Secp256k1::new();
     let (sk, pk) = secp.generate_keypair(&mut thread_rng());
-    return (sk.secret_bytes(), pk.serialize());
+    (sk.secret_bytes(), pk.serialize())
 }
 
-pub fn generate_public_key(secret_key: &[u8]) -> [u8; 33] {
+fn parse_secret_key(secret_key: &[u8]) -> Result<SecretKey, SignatureError> {
+    SecretKey::from_slice(secret_key).map_err(|_| SignatureError::InvalidSecretKey)
+}
+
+fn parse_public_key(public_key: &[u8]) -> Result<PublicKey, SignatureError> {
+    PublicKey::from_slice(public_key).map_err(|_| SignatureError::InvalidPublicKey)
+}
+
+pub fn generate_public_key(secret_key: &[u8]) -> Result<[u8; 33], SignatureError> {
     let secp = Secp256k1::new();
-    let sk = SecretKey::from_slice(secret_key).expect("32 bytes, within curve order");
-    PublicKey::from_secret_key(&secp, &sk).serialize()
+    let sk = parse_secret_key(secret_key)?;
+    Ok(PublicKey::from_secret_key(&secp, &sk).serialize())
 }
 
-pub fn new_signature(msg: &[u8], secret_key: &[u8]) -> [u8; 64]{
+pub fn is_valid_public_key(public_key: &[u8]) -> bool {
+    PublicKey::from_slice(public_key).is_ok()
+}
+
+pub fn new_signature(msg: &[u8], secret_key: &[u8]) -> Result<[u8; 64], SignatureError> {
     let secp = Secp256k1::new();
-    let sk = SecretKey::from_slice(secret_key).expect("32 bytes, within curve order");
+    let sk = parse_secret_key(secret_key)?;
     let parsed_msg = Message::from_hashed_data::<sha256::Hash>(msg);
 
-    secp.sign_ecdsa(&parsed_msg, &sk).serialize_compact()
+    Ok(secp.sign_ecdsa(&parsed_msg, &sk).serialize_compact())
 }
 
-pub fn verify_signature(msg: &[u8], public_key: &[u8], signature: &[u8]) -> Result<(), Error>{
-    let secp = Secp256k1::new();
+pub fn verify_signature(msg: &[u8], public_key: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    let secp = Secp256k1::verification_only();
+    verify_ecdsa_with(&secp, &Message::from_hashed_data::<sha256::Hash>(msg), public_key, signature)
+}
 
+pub fn new_schnorr_signature(msg: &[u8], secret_key: &[u8]) -> Result<[u8; 64], SignatureError> {
+    let secp = Secp256k1::new();
+    let keypair = KeyPair::from_seckey_slice(&secp, secret_key).map_err(|_| SignatureError::InvalidSecretKey)?;
     let parsed_msg = Message::from_hashed_data::<sha256::Hash>(msg);
-    let pk = PublicKey::from_slice(public_key).expect("32 bytes, within curve order");
-    let sig = Signature::from_compact(signature).expect("64 bytes");
-    secp.verify_ecdsa(&parsed_msg, &sig, &pk)
+    let mut aux_rand = [0u8; 32];
+    thread_rng().fill_bytes(&mut aux_rand);
+
+    let sig = secp.sign_schnorr_with_aux_rand(&parsed_msg, &keypair, &aux_rand);
+    let bytes: &[u8; 64] = sig.as_ref();
+    Ok(*bytes)
+}
+
+pub fn verify_schnorr_signature(msg: &[u8], public_key: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    let secp = Secp256k1::verification_only();
+    verify_schnorr_with(&secp, &Message::from_hashed_data::<sha256::Hash>(msg), public_key, signature)
+}
+
+// accepts both the 33 bytes compressed key and the 32 bytes x-only key
+fn x_only_public_key(public_key: &[u8]) -> Result<XOnlyPublicKey, SignatureError> {
+    match public_key.len() {
+        32 => XOnlyPublicKey::from_slice(public_key).map_err(|_| SignatureError::InvalidPublicKey),
+        _ => Ok(parse_public_key(public_key)?.x_only_public_key().0),
+    }
+}
+
+fn verify_schnorr_with<C: secp256k1::Verification>(secp: &Secp256k1<C>, msg: &Message, public_key: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    let pk = x_only_public_key(public_key)?;
+    let sig = schnorr::Signature::from_slice(signature).map_err(|_| SignatureError::InvalidSignature)?;
+    secp.verify_schnorr(&sig, msg, &pk).map_err(|_| SignatureError::VerificationFailed)
 }
 
+fn verify_ecdsa_with<C: secp256k1::Verification>(secp: &Secp256k1<C>, msg: &Message, public_key: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    let pk = parse_public_key(public_key)?;
+    let sig = Signature::from_compact(signature).map_err(|_| SignatureError::InvalidSignature)?;
+    secp.verify_ecdsa(msg, &sig, &pk).map_err(|_| SignatureError::VerificationFailed)
+}
+
+pub fn sign_with_scheme(scheme: SignatureScheme, msg: &[u8], secret_key: &[u8]) -> Result<[u8; 64], SignatureError> {
+    match scheme {
+        SignatureScheme::Ecdsa => new_signature(msg, secret_key),
+        SignatureScheme::Schnorr => new_schnorr_signature(msg, secret_key),
+    }
+}
+
+pub fn verify_with_scheme(scheme: SignatureScheme, msg: &[u8], public_key: &[u8], signature: &[u8]) -> Result<(), SignatureError> {
+    match scheme {
+        SignatureScheme::Ecdsa => verify_signature(msg, public_key, signature),
+        SignatureScheme::Schnorr => verify_schnorr_signature(msg, public_key, signature),
+    }
+}
+
+// a signature waiting for batch verification
+pub struct BatchItem<'a> {
+    pub scheme: SignatureScheme,
+    pub msg: &'a [u8],
+    pub public_key: &'a [u8],
+    pub signature: &'a [u8],
+}
+
+// verifies many signatures, as when ingesting a block body, returning the index
+// of the first invalid one. libsecp256k1 exposes no aggregate batch check, so
+// the batch shares a single verification context and is split across threads
+pub fn batch_verify(items: &[BatchItem]) -> Result<(), (usize, SignatureError)> {
+    let secp = Secp256k1::verification_only();
+    let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let chunk_size = items.len().div_ceil(threads).max(1);
+
+    let verify_chunk = |offset: usize, chunk: &[BatchItem]| -> Result<(), (usize, SignatureError)> {
+        for (i, item) in chunk.iter().enumerate() {
+            let msg = Message::from_hashed_data::<sha256::Hash>(item.msg);
+            let result = match item.scheme {
+                SignatureScheme::Ecdsa => verify_ecdsa_with(&secp, &msg, item.public_key, item.signature),
+                SignatureScheme::Schnorr => verify_schnorr_with(&secp, &msg, item.public_key, item.signature),
+            };
+            result.map_err(|e| (offset + i, e))?;
+        }
+        Ok(())
+    };
+
+    if items.len() <= chunk_size {
+        return verify_chunk(0, items);
+    }
+    let results: Vec<Result<(), (usize, SignatureError)>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = items.chunks(chunk_size).enumerate()
+            .map(|(n, chunk)| scope.spawn(move || verify_chunk(n * chunk_size, chunk)))
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+    results.into_iter().collect()
+}
@@ -0,0 +1,210 @@
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::Mutex;
+
+use serde::{Serialize, Deserialize};
+use zeroize::Zeroizing;
+
+use super::{generate_public_key, keystore, new_pair, new_signature, SignatureScheme};
+
+// signs on behalf of an identity without exposing its secret key to the caller
+pub trait Signer: Send + Sync {
+    fn public_key(&self) -> Result<[u8; 33], Error>;
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64], Error>;
+
+    // signers that only produce ecdsa signatures keep this default
+    fn sign_with_scheme(&self, msg: &[u8], scheme: SignatureScheme) -> Result<[u8; 64], Error> {
+        match scheme {
+            SignatureScheme::Ecdsa => self.sign(msg),
+            _ => Err(Error::new(ErrorKind::Unsupported, "Signature scheme not supported by signer")),
+        }
+    }
+}
+
+// secret key held by the signer itself
+pub struct MemorySigner {
+    secret_key: Zeroizing<[u8; 32]>,
+}
+
+impl MemorySigner {
+    pub fn new(secret_key: [u8; 32]) -> Self {
+        MemorySigner { secret_key: Zeroizing::new(secret_key) }
+    }
+
+    pub fn random() -> Self {
+        MemorySigner::new(new_pair().0)
+    }
+}
+
+impl Signer for MemorySigner {
+    fn public_key(&self) -> Result<[u8; 33], Error> {
+        generate_public_key(self.secret_key.as_slice()).map_err(Error::from)
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64], Error> {
+        new_signature(msg, self.secret_key.as_slice()).map_err(Error::from)
+    }
+
+    fn sign_with_scheme(&self, msg: &[u8], scheme: SignatureScheme) -> Result<[u8; 64], Error> {
+        super::sign_with_scheme(scheme, msg, self.secret_key.as_slice()).map_err(Error::from)
+    }
+}
+
+// identity of the encrypted keystore, the key is only read while signing
+pub struct KeystoreSigner {
+    identity: Option<String>,
+}
+
+impl KeystoreSigner {
+    pub fn new(identity: &str) -> Self {
+        KeystoreSigner { identity: Some(identity.to_string()) }
+    }
+
+    // follows keystore::set_active
+    pub fn active() -> Self {
+        KeystoreSigner { identity: None }
+    }
+
+    fn secret_key(&self) -> Result<Zeroizing<[u8; 32]>, Error> {
+        match &self.identity {
+            Some(identity) => keystore::secret_key(identity),
+            None => keystore::secret_key(&keystore::active()),
+        }
+    }
+}
+
+impl Signer for KeystoreSigner {
+    fn public_key(&self) -> Result<[u8; 33], Error> {
+        generate_public_key(self.secret_key()?.as_slice()).map_err(Error::from)
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64], Error> {
+        new_signature(msg, self.secret_key()?.as_slice()).map_err(Error::from)
+    }
+
+    fn sign_with_scheme(&self, msg: &[u8], scheme: SignatureScheme) -> Result<[u8; 64], Error> {
+        super::sign_with_scheme(scheme, msg, self.secret_key()?.as_slice()).map_err(Error::from)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum DaemonRequest {
+    PublicKey,
+    Sign { msg: String },
+    SignSchnorr { msg: String },
+}
+
+#[derive(Deserialize)]
+struct DaemonResponse {
+    public_key: Option<String>,
+    signature: Option<String>,
+    error: Option<String>,
+}
+
+// signing daemon listening on a unix socket, so the device keys never enter the
+// node process. One json request and response per line:
+//   {"method":"public_key"}             -> {"public_key":"<hex>"}
+//   {"method":"sign","msg":"<hex>"}     -> {"signature":"<hex>"}
+//   {"method":"sign_schnorr","msg":"<hex>"} -> {"signature":"<hex>"}
+// failures are answered with {"error":"<reason>"}
+pub struct DaemonSigner {
+    socket_path: String,
+    // public key asked once, it cannot change while the daemon runs
+    public_key: Mutex<Option<[u8; 33]>>,
+}
+
+impl DaemonSigner {
+    pub fn new(socket_path: &str) -> Self {
+        DaemonSigner {
+            socket_path: socket_path.to_string(),
+            public_key: Mutex::new(None),
+        }
+    }
+
+    fn request(&self, request: &DaemonRequest) -> Result<DaemonResponse, Error> {
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        let response: DaemonResponse = serde_json::from_str(&response)?;
+        match &response.error {
+            Some(e) => Err(Error::new(ErrorKind::PermissionDenied, e.clone())),
+            None => Ok(response),
+        }
+    }
+}
+
+fn decode_field<const N: usize>(field: Option<String>) -> Result<[u8; N], Error> {
+    let bytes = match field {
+        Some(field) => hex::decode(field).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        None => return Err(Error::new(ErrorKind::InvalidData, "Missing field in signer response")),
+    };
+    bytes.try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid length in signer response"))
+}
+
+impl Signer for DaemonSigner {
+    fn public_key(&self) -> Result<[u8; 33], Error> {
+        let mut cached = self.public_key.lock().unwrap();
+        if let Some(public_key) = *cached {
+            return Ok(public_key);
+        }
+        let public_key = decode_field(self.request(&DaemonRequest::PublicKey)?.public_key)?;
+        *cached = Some(public_key);
+        Ok(public_key)
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64], Error> {
+        let response = self.request(&DaemonRequest::Sign { msg: hex::encode(msg) })?;
+        decode_field(response.signature)
+    }
+
+    fn sign_with_scheme(&self, msg: &[u8], scheme: SignatureScheme) -> Result<[u8; 64], Error> {
+        let request = match scheme {
+            SignatureScheme::Ecdsa => DaemonRequest::Sign { msg: hex::encode(msg) },
+            SignatureScheme::Schnorr => DaemonRequest::SignSchnorr { msg: hex::encode(msg) },
+        };
+        decode_field(self.request(&request)?.signature)
+    }
+}
+
+// deterministic signer for tests, keeps every message it signed
+pub struct MockSigner {
+    inner: MemorySigner,
+    signed: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MockSigner {
+    // the same seed always gives the same key
+    pub fn new(seed: u8) -> Self {
+        let mut secret_key = [0u8; 32];
+        secret_key[31] = seed.max(1);
+        MockSigner {
+            inner: MemorySigner::new(secret_key),
+            signed: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn signed(&self) -> Vec<Vec<u8>> {
+        self.signed.lock().unwrap().clone()
+    }
+}
+
+impl Signer for MockSigner {
+    fn public_key(&self) -> Result<[u8; 33], Error> {
+        self.inner.public_key()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Result<[u8; 64], Error> {
+        self.signed.lock().unwrap().push(msg.to_vec());
+        self.inner.sign(msg)
+    }
+
+    fn sign_with_scheme(&self, msg: &[u8], scheme: SignatureScheme) -> Result<[u8; 64], Error> {
+        self.signed.lock().unwrap().push(msg.to_vec());
+        self.inner.sign_with_scheme(msg, scheme)
+    }
+}
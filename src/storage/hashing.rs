@@ -0,0 +1,79 @@
+use std::sync::Arc;
+
+use cita_trie::{HasherBlake2s, HasherBlake3, HasherSha256};
+use hasher::{Hasher, HasherKeccak};
+use serde::{Serialize, Deserialize};
+
+use super::keyvalue;
+
+// hash function of the block tries, chosen when the blockchain is created and
+// recorded in its genesis Type6 transaction
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    // chains created before the algorithm was configurable use keccak
+    #[default]
+    Keccak,
+    Sha256,
+    Blake2s,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 4] = [
+        HashAlgorithm::Keccak,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Blake2s,
+        HashAlgorithm::Blake3,
+    ];
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name.to_lowercase().as_str() {
+            "keccak" => Some(HashAlgorithm::Keccak),
+            "sha256" => Some(HashAlgorithm::Sha256),
+            "blake2s" => Some(HashAlgorithm::Blake2s),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+// hasher::Hasher dispatching to the algorithm of the chain, so the tries keep a
+// single type whatever algorithm was chosen
+#[derive(Clone, Copy, Debug)]
+pub struct ChainHasher {
+    algorithm: HashAlgorithm,
+}
+
+impl ChainHasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        ChainHasher { algorithm }
+    }
+}
+
+impl Hasher for ChainHasher {
+    const LENGTH: usize = 32;
+
+    fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            HashAlgorithm::Keccak => HasherKeccak::new().digest(data),
+            HashAlgorithm::Sha256 => HasherSha256::new().digest(data),
+            HashAlgorithm::Blake2s => HasherBlake2s::new().digest(data),
+            HashAlgorithm::Blake3 => HasherBlake3::new().digest(data),
+        }
+    }
+}
+
+pub fn chain_algorithm() -> HashAlgorithm {
+    match keyvalue::get(b"hash_algorithm").unwrap() {
+        Some(algorithm) => serde_json::from_slice(&algorithm).unwrap(),
+        None => HashAlgorithm::default(),
+    }
+}
+
+pub fn set_chain_algorithm(algorithm: HashAlgorithm) {
+    keyvalue::insert(b"hash_algorithm", &serde_json::to_vec(&algorithm).unwrap()).unwrap();
+}
+
+pub fn chain_hasher() -> Arc<ChainHasher> {
+    Arc::new(ChainHasher::new(chain_algorithm()))
+}
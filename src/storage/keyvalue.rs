@@ -27,4 +27,18 @@ pub fn get(key: &[u8]) -> Result<Option<Vec<u8>>, std::io::Error> {
         Ok(None) => Ok(None),
         Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
     }
+}
+
+pub fn remove(key: &[u8]) -> Result<(), std::io::Error> {
+    match DB_CONN.lock().unwrap().remove(key) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+    }
+}
+
+pub fn open_tree(name: &str) -> Result<sled::Tree, std::io::Error> {
+    match DB_CONN.lock().unwrap().open_tree(name) {
+        Ok(tree) => Ok(tree),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{Error, ErrorKind};
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use tokio::sync::RwLock;
+
+use crate::communication::transactions::Transaction;
+use crate::validation::{address::AddressesState, block::Block};
+
+use super::keyvalue;
+
+// maximum number of pending transactions held by the node
+const MAX_SIZE: usize = 1000;
+// maximum number of pending transactions held for a single sender
+pub const MAX_PER_SENDER: usize = 16;
+// seconds a pending transaction is kept before being dropped
+pub const EXPIRY_SECONDS: i64 = 60 * 60;
+
+// sled tree mirroring the pool, transaction signature -> pending transaction,
+// so pending transactions survive a restart
+const STORED_MEMPOOL: &str = "mempool";
+
+#[derive(Serialize, Deserialize)]
+struct PendingTransaction {
+    transaction: Transaction,
+    received_at: i64,
+}
+
+// pending transactions queued per sender (hex encoded pk), ordered by nonce
+pub struct Mempool {
+    queues: HashMap<String, BTreeMap<u64, PendingTransaction>>,
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Mempool {
+            queues: HashMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.values().map(|queue| queue.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    fn remove_where<F>(&mut self, mut f: F)
+        where F: FnMut(&PendingTransaction) -> bool
+    {
+        for queue in self.queues.values_mut() {
+            queue.retain(|_, pending| !f(pending));
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    pub fn remove_expired(&mut self, now: i64) {
+        self.remove_where(|pending| now - pending.received_at > EXPIRY_SECONDS);
+    }
+
+    // adds a verified transaction to the sender queue. A pending transaction with
+    // the same nonce is only replaced by one paying a higher fee
+    pub fn insert(&mut self, transaction: Transaction, chain_nonce: u64, now: i64) -> Result<(), Error> {
+        if transaction.nonce <= chain_nonce {
+            return Err(Error::new(ErrorKind::InvalidData, "Nonce already used"));
+        }
+
+        self.remove_expired(now);
+        let size = self.len();
+        let queue = self.queues.entry(hex::encode(transaction.pk)).or_default();
+        match queue.get(&transaction.nonce) {
+            Some(pending) if transaction.fee <= pending.transaction.fee => {
+                return Err(Error::new(ErrorKind::AlreadyExists, "Pending transaction with the same nonce and a higher or equal fee"));
+            },
+            Some(_) => {},
+            None => {
+                if queue.len() >= MAX_PER_SENDER {
+                    return Err(Error::other("Too many pending transactions for sender"));
+                }
+                if size >= MAX_SIZE {
+                    return Err(Error::other("Mempool full"));
+                }
+            }
+        }
+        queue.insert(transaction.nonce, PendingTransaction {
+            transaction,
+            received_at: now,
+        });
+        Ok(())
+    }
+
+    pub fn get(&self, signature: &[u8]) -> Option<Transaction> {
+        self.queues.values()
+            .flat_map(|queue| queue.values())
+            .find(|pending| pending.transaction.signature == signature)
+            .map(|pending| pending.transaction.clone())
+    }
+
+    // picks up to `limit` transactions for a new block. A sender's transactions are
+    // always taken in nonce order, and among senders the highest fee goes first
+    pub fn select(&self, limit: usize) -> Vec<Transaction> {
+        // reversed so the lowest nonce of each sender is at the end
+        let mut queues: Vec<Vec<&PendingTransaction>> = self.queues.values()
+            .map(|queue| queue.values().rev().collect())
+            .collect();
+
+        let mut selected = vec![];
+        while selected.len() < limit {
+            let best = queues.iter()
+                .enumerate()
+                .filter_map(|(i, queue)| queue.last().map(|pending| (i, pending.transaction.fee)))
+                .max_by_key(|(_, fee)| *fee);
+            match best {
+                Some((i, _)) => selected.push(queues[i].pop().unwrap().transaction.clone()),
+                None => break,
+            }
+        }
+        selected
+    }
+
+    // drops the transactions included in a block, along with any pending transaction
+    // of the same sender whose nonce has been used
+    pub fn remove_included(&mut self, transactions: &[Transaction]) {
+        for transaction in transactions {
+            if let Some(queue) = self.queues.get_mut(&hex::encode(transaction.pk)) {
+                queue.retain(|nonce, _| *nonce > transaction.nonce);
+            }
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+
+    // writes the transactions added to the pool and drops the removed ones
+    pub fn save(&self, tree: &sled::Tree) -> Result<(), Error> {
+        let pending: HashMap<&[u8], &PendingTransaction> = self.queues.values()
+            .flat_map(|queue| queue.values())
+            .map(|pending| (&pending.transaction.signature[..], pending))
+            .collect();
+        for key in tree.iter().keys() {
+            let key = key.map_err(to_io_error)?;
+            if !pending.contains_key(&key[..]) {
+                tree.remove(key).map_err(to_io_error)?;
+            }
+        }
+        for (signature, pending) in pending {
+            if !tree.contains_key(signature).map_err(to_io_error)? {
+                tree.insert(signature, serde_json::to_vec(pending).map_err(to_io_error)?).map_err(to_io_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    // pool written by save. Transactions keep the time they were first received,
+    // and the ones expired or with a used nonce are left out
+    pub fn load(tree: &sled::Tree, state: &AddressesState, now: i64) -> Result<Self, Error> {
+        let mut mempool = Mempool::new();
+        for value in tree.iter().values() {
+            let pending: PendingTransaction = match serde_json::from_slice(&value.map_err(to_io_error)?) {
+                Ok(pending) => pending,
+                Err(_) => continue,
+            };
+            let chain_nonce = state.get_address(&pending.transaction.pk).map_or(0, |address| address.nonce);
+            let _ = mempool.insert(pending.transaction, chain_nonce, pending.received_at);
+        }
+        mempool.remove_expired(now);
+        Ok(mempool)
+    }
+
+    // drops expired transactions and the ones no longer valid over the last block
+    pub fn revalidate(&mut self, now: i64) {
+        self.remove_expired(now);
+        if self.is_empty() {
+            return;
+        }
+
+        let pending: Vec<Transaction> = self.queues.values()
+            .flat_map(|queue| queue.values())
+            .map(|pending| pending.transaction.clone())
+            .collect();
+        // the pool is kept as is when the chain can't be read
+        let rejected: HashSet<[u8; 64]> = match Block::invalid_pending_transactions(&pending) {
+            Ok(rejected) => rejected.into_iter().map(|transaction| transaction.signature).collect(),
+            Err(e) => {
+                println!("Erro ao revalidar transações pendentes: {}", e);
+                return;
+            }
+        };
+        self.remove_where(|pending| rejected.contains(&pending.transaction.signature));
+    }
+}
+
+lazy_static! {
+    static ref MEMPOOL: RwLock<Mempool> = RwLock::new(Mempool::new());
+}
+
+// reloads the pool saved before a restart, dropping what is no longer valid
+pub async fn restore() -> Result<(), Error> {
+    let tree = keyvalue::open_tree(STORED_MEMPOOL)?;
+    let now = chrono::Utc::now().timestamp();
+    let mut mempool = MEMPOOL.write().await;
+    *mempool = Mempool::load(&tree, &Block::current_addresses_state(), now)?;
+    mempool.revalidate(now);
+    mempool.save(&tree)
+}
+
+pub async fn insert(transaction: Transaction) -> Result<(), Error> {
+    let chain_nonce = match Block::current_addresses_state().get_address(&transaction.pk) {
+        Some(address) => address.nonce,
+        None => 0,
+    };
+    let mut mempool = MEMPOOL.write().await;
+    mempool.insert(transaction, chain_nonce, chrono::Utc::now().timestamp())?;
+    mempool.save(&keyvalue::open_tree(STORED_MEMPOOL)?)
+}
+
+pub async fn len() -> usize {
+    MEMPOOL.read().await.len()
+}
+
+pub async fn get(signature: &[u8]) -> Option<Transaction> {
+    MEMPOOL.read().await.get(signature)
+}
+
+pub async fn select(limit: usize) -> Vec<Transaction> {
+    MEMPOOL.read().await.select(limit)
+}
+
+pub async fn on_new_block(block: &Block) {
+    let mut mempool = MEMPOOL.write().await;
+    if let Ok(transactions) = block.transactions() {
+        mempool.remove_included(&transactions);
+    }
+    mempool.revalidate(chrono::Utc::now().timestamp());
+    if let Err(e) = keyvalue::open_tree(STORED_MEMPOOL).and_then(|tree| mempool.save(&tree)) {
+        println!("Erro ao salvar transações pendentes: {}", e);
+    }
+}
@@ -1,26 +1,52 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use cita_trie::{MemoryDB, Trie};
+use cita_trie::{MemoryDB, Trie, DB};
 use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
 use tokio::sync::RwLock;
+use std::collections::HashMap;
 use std::sync::Arc;
-use hasher::HasherKeccak;
+use std::io::{Error, ErrorKind};
 use cita_trie::PatriciaTrie;
 
+use hasher::Hasher;
+
+use super::hashing::{self, ChainHasher, HashAlgorithm};
+use super::sleddb::SledDB;
+use super::snapshot;
+
+// sled tree holding the nodes of every block trie saved locally
+pub const STORED_TRIES: &str = "trie_nodes";
+
+// encoding of a block body. Blocks created before bodies were rlp encoded carry
+// no format, their body is the json dump of the whole trie db
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BodyFormat {
+    #[default]
+    Json,
+    Rlp,
+}
+
+// json dump of a MemoryDB, hex encoded node hash -> hex encoded node
+#[derive(Deserialize)]
+struct LegacyBody {
+    data: HashMap<String, String>,
+}
+
 lazy_static! {
 
-    static ref LOCAL_BLOCK: RwLock<PatriciaTrie<MemoryDB, HasherKeccak>> = {
+    // trie of the block being sealed, rebuilt from the mempool on every seal.
+    // Pending transactions are persisted by the mempool, not here
+    static ref LOCAL_BLOCK: RwLock<PatriciaTrie<MemoryDB, ChainHasher>> = {
         let db = MemoryDB::new(true);
         let memdb = Arc::new(db);
-        let hasher = Arc::new(HasherKeccak::new());
+        let hasher = hashing::chain_hasher();
 
         let trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
     
         RwLock::new(trie)
     };
-
-    pub static ref LOCAL_BLOCK_SIZE: RwLock<u64> = RwLock::new(0);
 }
 
 pub async fn get_trie_data_as_vector() -> Vec<u8> {
@@ -28,39 +54,102 @@ pub async fn get_trie_data_as_vector() -> Vec<u8> {
     i
 }
 
+// the local trie follows the hash algorithm of the chain, so it must be reset
+// once the genesis block is known
 pub async fn reset_local_trie(){
     let db = MemoryDB::new(true);
     let memdb = Arc::new(db);
-    let hasher = Arc::new(HasherKeccak::new());
+    let hasher = hashing::chain_hasher();
 
     let trie = PatriciaTrie::new(Arc::clone(&memdb), Arc::clone(&hasher));
     *LOCAL_BLOCK.write().await = trie;
 }
 
+// only the nodes reachable from the root are exported, so nodes left behind
+// by removed transactions don't leak into the block body
 pub async fn get_local_trie() -> (Vec<u8>, Vec<u8>) {
-
-    let root = LOCAL_BLOCK.write().await.root().unwrap();
-    let data = LOCAL_BLOCK.read().await.db.serialize();
+    let mut trie = LOCAL_BLOCK.write().await;
+    let root = trie.root().unwrap();
+    let data = MemoryDB::serialize_nodes(&trie.reachable_nodes().unwrap());
     (root,data)
 }
 
-pub async fn create_trie_from_str(body: Vec<u8>, root: Vec<u8>) {
-    let db = MemoryDB::new((true, body));
-    let memdb = Arc::new(db);
-    let hasher = Arc::new(HasherKeccak::new());
-
-    let trie = PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap();
-    
+pub async fn create_trie_from_str(body: Vec<u8>, root: Vec<u8>) -> Result<(), Error> {
+    let trie = create_evaluation_trie(body, root, hashing::chain_algorithm())?;
     *LOCAL_BLOCK.write().await = trie;
+    Ok(())
+}
+
+// bodies must hold exactly the canonical export of the block trie, so bodies
+// with extra, missing or reordered nodes are rejected
+pub fn create_evaluation_trie(str: Vec<u8>, root: Vec<u8>, algorithm: HashAlgorithm) -> Result<PatriciaTrie<MemoryDB, ChainHasher>, Error> {
+    let nodes = match MemoryDB::deserialize_nodes(&str) {
+        Ok(nodes) => nodes,
+        Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+    };
+    let hasher = Arc::new(ChainHasher::new(algorithm));
 
+    match PatriciaTrie::from_exported(Arc::clone(&hasher), &root, nodes) {
+        Ok(trie) => Ok(trie),
+        Err(e) => Err(Error::new(ErrorKind::InvalidData, e)),
+    }
 }
 
-pub fn create_evaluation_trie(str: Vec<u8>, root: Vec<u8>) -> PatriciaTrie<MemoryDB, HasherKeccak> {
-    let db = MemoryDB::new((true, str));
-    let memdb = Arc::new(db);
-    let hasher = Arc::new(HasherKeccak::new());
+// legacy bodies may hold nodes left behind by removed keys, so only the nodes
+// and the root are checked, not that the body is a canonical export
+fn create_legacy_trie(body: &[u8], root: &[u8], algorithm: HashAlgorithm) -> Result<PatriciaTrie<MemoryDB, ChainHasher>, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid legacy block body");
+    let body: LegacyBody = serde_json::from_slice(body).map_err(|_| invalid())?;
+    let hasher = Arc::new(ChainHasher::new(algorithm));
+    let db = Arc::new(MemoryDB::new(true));
+    for (key, value) in body.data {
+        let key = hex::decode(key).map_err(|_| invalid())?;
+        let value = hex::decode(value).map_err(|_| invalid())?;
+        if hasher.digest(&value) != key {
+            return Err(invalid());
+        }
+        db.insert(key, value).map_err(|_| invalid())?;
+    }
+
+    PatriciaTrie::from(db, hasher, root).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn create_body_trie(format: BodyFormat, body: &[u8], root: &[u8], algorithm: HashAlgorithm) -> Result<PatriciaTrie<MemoryDB, ChainHasher>, Error> {
+    match format {
+        BodyFormat::Json => create_legacy_trie(body, root, algorithm),
+        BodyFormat::Rlp => create_evaluation_trie(body.to_vec(), root.to_vec(), algorithm),
+    }
+}
 
-    PatriciaTrie::from(Arc::clone(&memdb), Arc::clone(&hasher), &root).unwrap()
+// copies the nodes reachable from the root of a checked block trie into the
+// persistent node storage
+pub fn store_trie(evaluation_trie: &PatriciaTrie<MemoryDB, ChainHasher>, root: &[u8]) -> Result<(), Error> {
+    let (keys, values) = evaluation_trie.reachable_nodes()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+        .into_iter()
+        .unzip();
+    // nodes are shared between tries, so they are only removed by pruning
+    let db = SledDB::new(STORED_TRIES, false)?;
+    db.insert_batch(keys, values).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    db.flush().map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    snapshot::record_block_root(root)
+}
+
+pub fn create_stored_trie(root: &[u8]) -> Option<PatriciaTrie<SledDB, ChainHasher>> {
+    let db = Arc::new(SledDB::new(STORED_TRIES, false).ok()?);
+    let hasher = hashing::chain_hasher();
+
+    PatriciaTrie::from(Arc::clone(&db), Arc::clone(&hasher), root).ok()
+}
+
+// body of a stored block, rebuilt from its nodes. Fails for the blocks before
+// the checkpoint a node started from
+pub fn stored_body(root: &[u8]) -> Result<Vec<u8>, Error> {
+    let trie = create_stored_trie(root)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Block body not stored"))?;
+    let nodes = trie.reachable_nodes()
+        .map_err(|_| Error::new(ErrorKind::NotFound, "Block body not stored"))?;
+    Ok(MemoryDB::serialize_nodes(&nodes))
 }
 
 pub async fn insert(key: &[u8], value: &[u8]) {
@@ -68,6 +157,16 @@ pub async fn insert(key: &[u8], value: &[u8]) {
     trie.insert(key.to_vec(), value.to_vec()).unwrap();
 }
 
+pub async fn insert_batch(items: Vec<(Vec<u8>, Vec<u8>)>) {
+    let mut trie = LOCAL_BLOCK.write().await;
+    trie.insert_batch(items).unwrap();
+}
+
+pub async fn remove(key: &[u8]) {
+    let mut trie = LOCAL_BLOCK.write().await;
+    trie.remove(key).unwrap();
+}
+
 pub async fn get(key: Vec<u8>) -> Option<Vec<u8>> {
     let trie = LOCAL_BLOCK.read().await;
     trie.get(key.as_slice()).unwrap()
@@ -1,2 +1,6 @@
+pub mod hashing;
 pub mod keyvalue;
-pub mod merkle;
\ No newline at end of file
+pub mod merkle;
+pub mod mempool;
+pub mod sleddb;
+pub mod snapshot;
\ No newline at end of file
@@ -0,0 +1,72 @@
+use cita_trie::DB;
+use sled::{Batch, Tree};
+
+use super::keyvalue;
+
+// cita_trie::DB backed by a sled tree, so trie nodes survive restarts and can be
+// shared between tries stored in the same tree
+#[derive(Debug)]
+pub struct SledDB {
+    // If "light" is true, removed nodes are deleted from the tree,
+    // otherwise they are kept for other tries that may reference them.
+    light: bool,
+    tree: Tree,
+}
+
+impl SledDB {
+    pub fn new(tree_name: &str, light: bool) -> Result<SledDB, std::io::Error> {
+        let tree = keyvalue::open_tree(tree_name)?;
+        Ok(SledDB {
+            light,
+            tree,
+        })
+    }
+}
+
+impl DB for SledDB {
+    type Error = sled::Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.tree.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.tree.contains_key(key)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), Self::Error> {
+        self.tree.insert(key, value)?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), Self::Error> {
+        if self.light {
+            self.tree.remove(key)?;
+        }
+        Ok(())
+    }
+
+    fn insert_batch(&self, keys: Vec<Vec<u8>>, values: Vec<Vec<u8>>) -> Result<(), Self::Error> {
+        let mut batch = Batch::default();
+        for (key, value) in keys.into_iter().zip(values) {
+            batch.insert(key, value);
+        }
+        self.tree.apply_batch(batch)
+    }
+
+    fn remove_batch(&self, keys: &[Vec<u8>]) -> Result<(), Self::Error> {
+        if !self.light {
+            return Ok(());
+        }
+        let mut batch = Batch::default();
+        for key in keys {
+            batch.remove(key.as_slice());
+        }
+        self.tree.apply_batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.tree.flush()?;
+        Ok(())
+    }
+}
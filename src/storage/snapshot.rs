@@ -0,0 +1,160 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+
+use cita_trie::{MemoryDB, Nodes};
+use serde::{Deserialize, Serialize};
+
+use crate::validation::block::Checkpoint;
+
+use super::hashing;
+use super::keyvalue;
+use super::merkle;
+
+// node hash -> number of retained roots referencing the node
+const NODE_REFS: &str = "trie_node_refs";
+// root of every block trie of the chain -> empty
+const BLOCK_ROOTS: &str = "trie_block_roots";
+// snapshot name -> retained root
+const SNAPSHOTS: &str = "trie_snapshots";
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    name: String,
+    root: String,
+    nodes: String,
+    // block of the snapshot root, files exported before checkpoints only hold
+    // the trie
+    #[serde(default)]
+    checkpoint: Option<Checkpoint>,
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn decode_count(count: Option<&[u8]>) -> u64 {
+    match count {
+        Some(bytes) if bytes.len() == 8 => {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(bytes);
+            u64::from_be_bytes(buf)
+        }
+        _ => 0,
+    }
+}
+
+fn reachable_nodes(root: &[u8]) -> Result<Nodes, Error> {
+    let trie = match merkle::create_stored_trie(root) {
+        Some(trie) => trie,
+        None => return Err(Error::new(ErrorKind::NotFound, "Trie root not stored")),
+    };
+    trie.reachable_nodes().map_err(to_io_error)
+}
+
+// adds a reference from a stored root to every node reachable from it
+fn retain(root: &[u8]) -> Result<(), Error> {
+    let refs = keyvalue::open_tree(NODE_REFS)?;
+    for (hash, _) in reachable_nodes(root)? {
+        refs.update_and_fetch(hash, |count| {
+            Some((decode_count(count) + 1).to_be_bytes().to_vec())
+        }).map_err(to_io_error)?;
+    }
+    Ok(())
+}
+
+// drops the references of a root, deleting the nodes no retained root uses
+fn release(root: &[u8]) -> Result<(), Error> {
+    let refs = keyvalue::open_tree(NODE_REFS)?;
+    let nodes = keyvalue::open_tree(merkle::STORED_TRIES)?;
+    for (hash, _) in reachable_nodes(root)? {
+        let count = refs.update_and_fetch(&hash, |count| {
+            match decode_count(count) {
+                0 | 1 => None,
+                n => Some((n - 1).to_be_bytes().to_vec()),
+            }
+        }).map_err(to_io_error)?;
+        if count.is_none() {
+            nodes.remove(&hash).map_err(to_io_error)?;
+        }
+    }
+    nodes.flush().map_err(to_io_error)?;
+    Ok(())
+}
+
+// retains the trie of a newly stored block. Called by merkle::store_trie.
+// Block tries are the bodies peers sync the chain from, so they are never
+// released, only tries held by nothing but a replaced snapshot are pruned. A
+// block stored again, as when it is synced twice, keeps its single reference
+pub fn record_block_root(root: &[u8]) -> Result<(), Error> {
+    let roots = keyvalue::open_tree(BLOCK_ROOTS)?;
+    if roots.insert(root, &[]).map_err(to_io_error)?.is_none() {
+        retain(root)?;
+    }
+    Ok(())
+}
+
+// retains a stored root under a name, replacing the root previously held by it
+pub fn create(name: &str, root: &[u8]) -> Result<(), Error> {
+    retain(root)?;
+    let snapshots = keyvalue::open_tree(SNAPSHOTS)?;
+    if let Some(previous) = snapshots.insert(name, root).map_err(to_io_error)? {
+        release(&previous)?;
+    }
+    Ok(())
+}
+
+pub fn get(name: &str) -> Result<Option<Vec<u8>>, Error> {
+    let snapshots = keyvalue::open_tree(SNAPSHOTS)?;
+    Ok(snapshots.get(name).map_err(to_io_error)?.map(|root| root.to_vec()))
+}
+
+// writes a snapshot and the nodes of its trie to a file another node can import
+pub fn export(name: &str, path: &str) -> Result<(), Error> {
+    let root = match get(name)? {
+        Some(root) => root,
+        None => return Err(Error::new(ErrorKind::NotFound, "Snapshot not found")),
+    };
+    let file = SnapshotFile {
+        name: name.to_string(),
+        root: hex::encode(&root),
+        nodes: hex::encode(MemoryDB::serialize_nodes(&reachable_nodes(&root)?)),
+        checkpoint: Checkpoint::of(&root)?,
+    };
+    fs::write(path, serde_json::to_string(&file)?)
+}
+
+// loads a snapshot file, checking its nodes against its root before storing
+// them, and returns the snapshot name. A node without a chain continues it
+// from the checkpoint block of the file
+pub fn import(path: &str) -> Result<String, Error> {
+    let file: SnapshotFile = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let root = hex::decode(&file.root).map_err(to_io_error)?;
+    let body = hex::decode(&file.nodes).map_err(to_io_error)?;
+
+    let checkpoint = match file.checkpoint {
+        Some(checkpoint) if keyvalue::get(b"last_block_header")?.is_none() => Some(checkpoint),
+        _ => None,
+    };
+    let algorithm = match &checkpoint {
+        Some(checkpoint) => checkpoint.hash_algorithm,
+        None => hashing::chain_algorithm(),
+    };
+    let trie = merkle::create_evaluation_trie(body, root.clone(), algorithm)?;
+
+    match checkpoint {
+        Some(checkpoint) => {
+            checkpoint.install(&trie, &root)?;
+            println!("Checkpoint instalado: {}", hex::encode(&root));
+        },
+        None => {
+            let nodes = keyvalue::open_tree(merkle::STORED_TRIES)?;
+            for (hash, node) in trie.reachable_nodes().map_err(to_io_error)? {
+                nodes.insert(hash, node).map_err(to_io_error)?;
+            }
+            nodes.flush().map_err(to_io_error)?;
+        },
+    }
+
+    create(&file.name, &root)?;
+    Ok(file.name)
+}
@@ -1,6 +1,24 @@
 mod transport;
 mod storage;
-use crate::{signature, communication};
+use crate::{signature, communication, NodeContext};
+use crate::signature::{BatchItem, SignatureScheme};
+use crate::signature::signer::{MockSigner, Signer};
+use crate::signature::keystore;
+use crate::signature::hd::{self, ExtendedKey, ExtendedPublicKey};
+use crate::storage::keyvalue;
+use std::io::{Error, ErrorKind};
+use crate::communication::acl::{Permission, Policy, Principal};
+use crate::transport::{tls, limits::{self, Verdict}};
+use crate::communication::transactions::{Transaction, TransactionData, DataTransactionType2, DataTransactionType7};
+use crate::validation::address::{self, Address, AddressesState, AddressFormat};
+use bech32::{ToBase32, Variant};
+use crate::validation::block::Block;
+use crate::validation::federation;
+use crate::storage::mempool::{self, Mempool};
+use crate::storage::merkle::{self, BodyFormat};
+use crate::storage::hashing::{self, ChainHasher, HashAlgorithm};
+use std::sync::Arc;
+use cita_trie::{MemoryDB, PatriciaTrie, Trie};
 
 pub async fn transport_tests(){
     println!("\n\n--------------------- TRANSPORT MODULE TESTS ---------------------");
@@ -8,8 +26,8 @@ pub async fn transport_tests(){
     let (server1, incoming1) = transport::test_server("10001".to_string()).await.unwrap();
     let (server2, incoming2) = transport::test_server("10002".to_string()).await.unwrap();
 
-    let (client1, c_incoming1) = transport::test_server("10003".to_string()).await.unwrap();
-    let (client2, c_incoming2) = transport::test_server("10004".to_string()).await.unwrap();
+    let (client1, _c_incoming1) = transport::test_server("10003".to_string()).await.unwrap();
+    let (client2, _c_incoming2) = transport::test_server("10004".to_string()).await.unwrap();
 
     tokio::spawn(
         transport::test_listen(incoming1)
@@ -23,7 +41,7 @@ pub async fn transport_tests(){
 
     println!("Transport module test #1: OK\n");
 
-    match transport::test_send(client1.clone(), server1.public_addr().to_string(), "Hello from client 1".to_string()).await {
+    match transport::test_send(client1.clone(), server1.local_addr().unwrap().to_string(), "Hello from client 1".to_string()).await {
         Ok(_) => {
             println!("Transport module test #2: OK\n");},
         Err(e) => {
@@ -32,7 +50,7 @@ pub async fn transport_tests(){
     }
 
     for t in 0..10 {
-        match transport::test_send(client1.clone(), server1.public_addr().to_string(), format!("Message # {} from client 1", t)).await {
+        match transport::test_send(client1.clone(), server1.local_addr().unwrap().to_string(), format!("Message # {} from client 1", t)).await {
             Ok(_) => {},
             Err(e) => {
                 panic!("Transport module test #3: FAILED\nERROR: {}", e);
@@ -43,13 +61,13 @@ pub async fn transport_tests(){
 
 
     for t in 0..10 {
-        match transport::test_send(if t%2==0 {client1.clone()} else {client2.clone()}, server1.public_addr().to_string(), format!("Message # {} from client {}", t, t%2+1)).await {
+        match transport::test_send(if t%2==0 {client1.clone()} else {client2.clone()}, server1.local_addr().unwrap().to_string(), format!("Message # {} from client {}", t, t%2+1)).await {
             Ok(_) => {},
             Err(e) => {
                 panic!("Transport module test #4: FAILED\nERROR: {}", e);
             },
         }
-        match transport::test_send(if t%2==0 {client1.clone()} else {client2.clone()}, server2.public_addr().to_string(), format!("Message # {} from client {}", t, t%2+1)).await {
+        match transport::test_send(if t%2==0 {client1.clone()} else {client2.clone()}, server2.local_addr().unwrap().to_string(), format!("Message # {} from client {}", t, t%2+1)).await {
             Ok(_) => {},
             Err(e) => {
                 panic!("Transport module test #4: FAILED\nERROR: {}", e);
@@ -58,6 +76,40 @@ pub async fn transport_tests(){
     }
     println!("Transport module test #4: OK\n");
 
+    // a documentation address, two chain syncs drain the budget of a peer
+    let peer: std::net::SocketAddr = "192.0.2.1:1000".parse().unwrap();
+    let verdicts: Vec<Verdict> = (0..3).map(|_| limits::charge(&peer, 50.0)).collect();
+    if verdicts == [Verdict::Allow, Verdict::Allow, Verdict::Throttle] {
+        println!("Transport module test #5: OK\n");
+    } else {
+        panic!("Transport module test #5: FAILED\nERROR: Peer budget not enforced: {:?}", verdicts);
+    }
+
+    // the certificate carries the node key that endorsed it
+    let ctx = NodeContext { signer: Arc::new(MockSigner::new(2)) };
+    match tls::generate(&ctx).and_then(|(cert, _)| tls::node_key(&cert.0)) {
+        Ok(pk) if pk == ctx.signer.public_key().unwrap() => println!("Transport module test #6: OK\n"),
+        Ok(_) => panic!("Transport module test #6: FAILED\nERROR: Certificate endorsed by another key"),
+        Err(e) => panic!("Transport module test #6: FAILED\nERROR: {}", e),
+    }
+
+    // a peer whose certificate no node key endorsed can't connect
+    let (unendorsed, _) = transport::test_unendorsed("10005".to_string()).await.unwrap();
+    match transport::test_send(unendorsed, server1.local_addr().unwrap().to_string(), "Hello from nobody".to_string()).await {
+        Ok(_) => panic!("Transport module test #7: FAILED\nERROR: Unendorsed certificate accepted"),
+        Err(_) => println!("Transport module test #7: OK\n"),
+    }
+
+    // bans are answered from memory and lifted by unban
+    let banned: std::net::IpAddr = "192.0.2.2".parse().unwrap();
+    let result = limits::ban(&banned).map(|_| limits::is_banned(&banned))
+        .and_then(|was_banned| Ok((was_banned, limits::unban(&banned).map(|_| limits::is_banned(&banned))?)));
+    match result {
+        Ok((true, false)) => println!("Transport module test #8: OK\n"),
+        Ok(_) => panic!("Transport module test #8: FAILED\nERROR: Ban not applied or not lifted"),
+        Err(e) => panic!("Transport module test #8: FAILED\nERROR: {}", e),
+    }
+
 }
 
 pub fn signature_tests(){
@@ -74,7 +126,7 @@ pub fn signature_tests(){
 
     println!("Generating signature to string \"Hello World\"...");
 
-    let sig = signature::new_signature(msg.as_bytes(), &sk);
+    let sig = signature::new_signature(msg.as_bytes(), &sk).unwrap();
 
     println!("Signature module test #2: OK\n");
 
@@ -92,16 +144,224 @@ pub fn signature_tests(){
 
     println!("Generating and verifying invalid signature...");
 
-    let new_sig = signature::new_signature(b"Random data", &sk);
+    let new_sig = signature::new_signature(b"Random data", &sk).unwrap();
     let invalid = signature::verify_signature(msg.as_bytes(), &pk, &new_sig);
     match invalid {
         Ok(_) => {
             panic!("Signature module test #4: FAILED\nERROR: Signature verification should have failed");
         },
-        Err(e) => {
+        Err(_) => {
             println!("Signature module test #4: OK\n");
         },
     }
+
+    println!("Signing with mock signer...");
+
+    let mock = MockSigner::new(1);
+    let mock_sig = mock.sign(msg.as_bytes()).unwrap();
+    match signature::verify_signature(msg.as_bytes(), &mock.public_key().unwrap(), &mock_sig) {
+        Ok(_) if mock.signed() == vec![msg.as_bytes().to_vec()] => {
+            println!("Signature module test #5: OK\n");
+        },
+        Ok(_) => panic!("Signature module test #5: FAILED\nERROR: Signed messages not recorded"),
+        Err(e) => {
+            panic!("Signature module test #5: FAILED\nERROR: {}", e);
+        },
+    }
+
+    println!("Generating and verifying schnorr signature...");
+
+    let schnorr_sig = signature::new_schnorr_signature(msg.as_bytes(), &sk).unwrap();
+    match signature::verify_schnorr_signature(msg.as_bytes(), &pk, &schnorr_sig) {
+        Ok(_) => {
+            println!("Signature module test #6: OK\n");
+        },
+        Err(e) => {
+            panic!("Signature module test #6: FAILED\nERROR: {}", e);
+        },
+    }
+
+    println!("Batch verifying signatures...");
+
+    let mut items: Vec<BatchItem> = (0..32).map(|i| BatchItem {
+        scheme: if i % 2 == 0 { SignatureScheme::Ecdsa } else { SignatureScheme::Schnorr },
+        msg: msg.as_bytes(),
+        public_key: &pk,
+        signature: if i % 2 == 0 { &sig } else { &schnorr_sig },
+    }).collect();
+    if let Err((i, e)) = signature::batch_verify(&items) {
+        panic!("Signature module test #7: FAILED\nERROR: signature {}: {}", i, e);
+    }
+    items[20].signature = &new_sig;
+    match signature::batch_verify(&items) {
+        Err((20, _)) => {
+            println!("Signature module test #7: OK\n");
+        },
+        _ => {
+            panic!("Signature module test #7: FAILED\nERROR: Invalid signature not detected");
+        },
+    }
+    println!("Verifying signature with malformed public key...");
+
+    match signature::verify_signature(msg.as_bytes(), &[0u8; 33], &sig) {
+        Err(signature::SignatureError::InvalidPublicKey) => {
+            println!("Signature module test #8: OK\n");
+        },
+        _ => {
+            panic!("Signature module test #8: FAILED\nERROR: Malformed public key not rejected");
+        },
+    }
+
+    println!("Unlocking keystore with empty passphrase...");
+
+    match keystore::unlock("") {
+        Err(e) if e.kind() == ErrorKind::InvalidInput => {
+            println!("Signature module test #9: OK\n");
+        },
+        _ => {
+            panic!("Signature module test #9: FAILED\nERROR: Empty passphrase accepted");
+        },
+    }
+
+    println!("Exporting and importing identity...");
+
+    let (secret_key, public_key) = signature::new_pair();
+    let path = "test-identity.json";
+    let result = keystore::import("test-exported", &secret_key)
+        .and_then(|_| keystore::export("test-exported", path))
+        .and_then(|_| match keystore::import_file("test-imported", path, "wrong passphrase") {
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Err(Error::new(ErrorKind::InvalidData, "Wrong passphrase accepted")),
+        })
+        .and_then(|_| keystore::import_file("test-imported", path, &keystore::read_passphrase()))
+        .and_then(|imported| keystore::secret_key("test-imported").map(|key| (imported, key)));
+    let _ = std::fs::remove_file(path);
+    let _ = keystore::remove("test-exported");
+    let _ = keystore::remove("test-imported");
+    match result {
+        Ok((imported, key)) if imported == public_key && *key == secret_key => {
+            println!("Signature module test #10: OK\n");
+        },
+        Ok(_) => panic!("Signature module test #10: FAILED\nERROR: Imported identity differs"),
+        Err(e) => panic!("Signature module test #10: FAILED\nERROR: {}", e),
+    }
+
+    println!("Migrating plain secret key into the keystore...");
+
+    let (secret_key, _) = signature::new_pair();
+    let result = keyvalue::insert(b"test_plain_key", &secret_key)
+        .and_then(|_| keystore::migrate_plain_key(b"test_plain_key", "test-migrated"))
+        .and_then(|_| Ok((keystore::secret_key("test-migrated")?, keyvalue::get(b"test_plain_key")?)));
+    let _ = keystore::remove("test-migrated");
+    match result {
+        Ok((key, None)) if *key == secret_key => {
+            println!("Signature module test #11: OK\n");
+        },
+        Ok(_) => panic!("Signature module test #11: FAILED\nERROR: Plain key not migrated"),
+        Err(e) => panic!("Signature module test #11: FAILED\nERROR: {}", e),
+    }
+
+    println!("Deriving BIP32 test vectors...");
+
+    match check_bip32_vector("000102030405060708090a0b0c0d0e0f", &BIP32_VECTOR_1) {
+        Ok(()) => println!("Signature module test #12: OK\n"),
+        Err(e) => panic!("Signature module test #12: FAILED\nERROR: {}", e),
+    }
+    match check_bip32_vector(BIP32_SEED_2, &BIP32_VECTOR_2) {
+        Ok(()) => println!("Signature module test #13: OK\n"),
+        Err(e) => panic!("Signature module test #13: FAILED\nERROR: {}", e),
+    }
+
+    // xpubs of test vector 2, derived and decoded with their depth and parent
+    let master = ExtendedKey::from_seed(&hex::decode(BIP32_SEED_2).unwrap()).unwrap();
+    for (path, xpub) in BIP32_XPUBS_2.iter() {
+        let public = master.derive_path(path).unwrap().public();
+        match ExtendedPublicKey::decode(xpub) {
+            Ok(decoded) if public.encode() == *xpub && decoded == public => (),
+            Ok(_) => panic!("Signature module test #14: FAILED\nERROR: xpub mismatch at {}", path),
+            Err(e) => panic!("Signature module test #14: FAILED\nERROR: {}: {}", path, e),
+        }
+    }
+    println!("Signature module test #14: OK\n");
+}
+
+const BIP32_XPUBS_2: [(&str, &str); 3] = [
+    ("m", "xpub661MyMwAqRbcFW31YEwpkMuc5THy2PSt5bDMsktWQcFF8syAmRUapSCGu8ED9W6oDMSgv6Zz8idoc4a6mr8BDzTJY47LJhkJ8UB7WEGuduB"),
+    ("m/0/2147483647'/1", "xpub6DF8uhdarytz3FWdA8TvFSvvAh8dP3283MY7p2V4SeE2wyWmG5mg5EwVvmdMVCQcoNJxGoWaU9DCWh89LojfZ537wTfunKau47EL2dhHKon"),
+    ("m/0/2147483647'/1/2147483646'/2", "xpub6FnCn6nSzZAw5Tw7cgR9bi15UV96gLZhjDstkXXxvCLsUXBGXPdSnLFbdpq8p9HmGsApME5hQTZ3emM2rnY5agb9rXpVGyy3bdW6EEgAtqt"),
+];
+
+// path, chain code, public key and secret key of the BIP32 test vectors
+type Bip32Vector = [(&'static str, &'static str, &'static str, &'static str); 6];
+
+const BIP32_VECTOR_1: Bip32Vector = [
+    ("m", "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+        "0339a36013301597daef41fbe593a02cc513d0b55527ec2df1050e2e8ff49c85c2",
+        "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"),
+    ("m/0'", "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141",
+        "035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56",
+        "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"),
+    ("m/0'/1", "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19",
+        "03501e454bf00751f24b1b489aa925215d66af2234e3891c3b21a52bedb3cd711c",
+        "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368"),
+    ("m/0'/1/2'", "04466b9cc8e161e966409ca52986c584f07e9dc81f735db683c3ff6ec7b1503f",
+        "0357bfe1e341d01c69fe5654309956cbea516822fba8a601743a012a7896ee8dc2",
+        "cbce0d719ecf7431d88e6a89fa1483e02e35092af60c042b1df2ff59fa424dca"),
+    ("m/0'/1/2'/2", "cfb71883f01676f587d023cc53a35bc7f88f724b1f8c2892ac1275ac822a3edd",
+        "02e8445082a72f29b75ca48748a914df60622a609cacfce8ed0e35804560741d29",
+        "0f479245fb19a38a1954c5c7c0ebab2f9bdfd96a17563ef28a6a4b1a2a764ef4"),
+    ("m/0'/1/2'/2/1000000000", "c783e67b921d2beb8f6b389cc646d7263b4145701dadd2161548a8b078e65e9e",
+        "022a471424da5e657499d1ff51cb43c47481a03b1e77f951fe64cec9f5a48f7011",
+        "471b76e389e528d6de6d816857e012c5455051cad6660850e58372a6c3e6e7c8"),
+];
+
+const BIP32_SEED_2: &str = "fffcf9f6f3f0edeae7e4e1dedbd8d5d2cfccc9c6c3c0bdbab7b4b1aeaba8a5a29f9c999693908d8a8784817e7b7875726f6c696663605d5a5754514e4b484542";
+
+const BIP32_VECTOR_2: Bip32Vector = [
+    ("m", "60499f801b896d83179a4374aeb7822aaeaceaa0db1f85ee3e904c4defbd9689",
+        "03cbcaa9c98c877a26977d00825c956a238e8dddfbd322cce4f74b0b5bd6ace4a7",
+        "4b03d6fc340455b363f51020ad3ecca4f0850280cf436c70c727923f6db46c3e"),
+    ("m/0", "f0909affaa7ee7abe5dd4e100598d4dc53cd709d5a5c2cac40e7412f232f7c9c",
+        "02fc9e5af0ac8d9b3cecfe2a888e2117ba3d089d8585886c9c826b6b22a98d12ea",
+        "abe74a98f6c7eabee0428f53798f0ab8aa1bd37873999041703c742f15ac7e1e"),
+    ("m/0/2147483647'", "be17a268474a6bb9c61e1d720cf6215e2a88c5406c4aee7b38547f585c9a37d9",
+        "03c01e7425647bdefa82b12d9bad5e3e6865bee0502694b94ca58b666abc0a5c3b",
+        "877c779ad9687164e9c2f4f0f4ff0340814392330693ce95a58fe18fd52e6e93"),
+    ("m/0/2147483647'/1", "f366f48f1ea9f2d1d3fe958c95ca84ea18e4c4ddb9366c336c927eb246fb38cb",
+        "03a7d1d856deb74c508e05031f9895dab54626251b3806e16b4bd12e781a7df5b9",
+        "704addf544a06e5ee4bea37098463c23613da32020d604506da8c0518e1da4b7"),
+    ("m/0/2147483647'/1/2147483646'", "637807030d55d01f9a0cb3a7839515d796bd07706386a6eddf06cc29a65a0e29",
+        "02d2b36900396c9282fa14628566582f206a5dd0bcc8d5e892611806cafb0301f0",
+        "f1c7c871a54a804afe328b4c83a1c33b8e5ff48f5087273f04efa83b247d6a2d"),
+    ("m/0/2147483647'/1/2147483646'/2", "9452b549be8cea3ecb7a84bec10dcfd94afe4d129ebfd3b3cb58eedf394ed271",
+        "024d902e1a2fc7a8755ab5b694c575fce742c48d9ff192e63df5193e4c7afe1f9c",
+        "bb7d39bdb83ecf58f2fd82b6d918341cbef428661ef01ab97c28a4842125ac23"),
+];
+
+// derives every key of a vector from the seed with CKDpriv, and the children at
+// non hardened indexes from the public key of their parent with CKDpub
+fn check_bip32_vector(seed: &str, vector: &Bip32Vector) -> Result<(), String> {
+    let master = ExtendedKey::from_seed(&hex::decode(seed).unwrap()).map_err(|e| e.to_string())?;
+    let mut parent: Option<ExtendedKey> = None;
+    for (path, chain_code, public_key, secret_key) in vector.iter() {
+        let key = master.derive_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        let public = key.public();
+        if hex::encode(key.secret_key().as_slice()) != *secret_key
+            || hex::encode(public.public_key) != *public_key
+            || hex::encode(public.chain_code) != *chain_code {
+            return Err(format!("CKDpriv mismatch at {}", path));
+        }
+        let index = *hd::parse_path(path).map_err(|e| e.to_string())?.last().unwrap_or(&hd::HARDENED);
+        if let (Some(parent), true) = (&parent, index < hd::HARDENED) {
+            let child = parent.public().derive_child(index).map_err(|e| format!("{}: {}", path, e))?;
+            if child.public_key != public.public_key || child.chain_code != public.chain_code {
+                return Err(format!("CKDpub mismatch at {}", path));
+            }
+        }
+        parent = Some(key);
+    }
+    Ok(())
 }
 
 pub fn storage_tests(){
@@ -139,17 +399,130 @@ pub fn storage_tests(){
         Ok(_) => {
             panic!("Storage module test #4: FAILED\nERROR: Key should not exist");
         },
-        Err(e) => {
+        Err(_) => {
             println!("Storage module test #4: OK\n");
         },
     }
 
+    let sender = signature::new_pair().1;
+    let now = chrono::Utc::now().timestamp();
+
+    let mut pool = Mempool::new();
+    pool.insert(pending_transaction(sender, 1, 10), 0, now).unwrap();
+    let same_fee = pool.insert(pending_transaction(sender, 1, 10), 0, now);
+    let higher_fee = pool.insert(pending_transaction(sender, 1, 20), 0, now);
+    let replaced = pool.select(1).first().map(|transaction| transaction.fee);
+    if same_fee.is_err() && higher_fee.is_ok() && pool.len() == 1 && replaced == Some(20) {
+        println!("Storage module test #5: OK\n");
+    } else {
+        panic!("Storage module test #5: FAILED\nERROR: Fee replacement not enforced");
+    }
+
+    let mut pool = Mempool::new();
+    for nonce in 1..=mempool::MAX_PER_SENDER as u64 {
+        pool.insert(pending_transaction(sender, nonce, 10), 0, now).unwrap();
+    }
+    let over_limit = pool.insert(pending_transaction(sender, mempool::MAX_PER_SENDER as u64 + 1, 10), 0, now);
+    let other_sender = pool.insert(pending_transaction(signature::new_pair().1, 1, 10), 0, now);
+    if over_limit.is_err() && other_sender.is_ok() {
+        println!("Storage module test #6: OK\n");
+    } else {
+        panic!("Storage module test #6: FAILED\nERROR: Sender limit not enforced");
+    }
+
+    let mut pool = Mempool::new();
+    pool.insert(pending_transaction(sender, 1, 10), 0, now).unwrap();
+    pool.insert(pending_transaction(sender, 2, 10), 0, now + mempool::EXPIRY_SECONDS).unwrap();
+    pool.remove_expired(now + mempool::EXPIRY_SECONDS + 1);
+    if pool.len() == 1 && pool.select(1)[0].nonce == 2 {
+        println!("Storage module test #7: OK\n");
+    } else {
+        panic!("Storage module test #7: FAILED\nERROR: Expired transaction kept");
+    }
+
+    let mut pool = Mempool::new();
+    for nonce in 1..=3 {
+        pool.insert(pending_transaction(sender, nonce, 10), 0, now).unwrap();
+    }
+    pool.remove_included(&[pending_transaction(sender, 2, 10)]);
+    let left = pool.select(3).iter().map(|transaction| transaction.nonce).collect::<Vec<u64>>();
+    // neither sender has balance over the local chain. The first transaction of
+    // the other sender can't be paid, while the ones after a nonce gap wait
+    let other = signature::new_pair().1;
+    pool.insert(pending_transaction(other, 1, 10), 0, now).unwrap();
+    pool.insert(pending_transaction(other, 2, 10), 0, now).unwrap();
+    pool.revalidate(now);
+    let mut kept = pool.select(3).iter().map(|transaction| (transaction.pk == sender, transaction.nonce)).collect::<Vec<(bool, u64)>>();
+    kept.sort();
+    if left == [3] && kept == [(false, 2), (true, 3)] {
+        println!("Storage module test #8: OK\n");
+    } else {
+        panic!("Storage module test #8: FAILED\nERROR: Included or invalid transactions kept, or gapped ones dropped: {:?}", kept);
+    }
+
+    // blocks stored before bodies were rlp encoded hold a json dump of the trie db
+    let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(ChainHasher::new(HashAlgorithm::Keccak)));
+    trie.insert(b"key".to_vec(), b"value".to_vec()).unwrap();
+    let root = trie.root().unwrap();
+    let nodes = trie.reachable_nodes().unwrap();
+    let legacy = serde_json::to_vec(&serde_json::json!({
+        "data": nodes.iter()
+            .map(|(hash, node)| (hex::encode(hash), serde_json::Value::String(hex::encode(node))))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+    })).unwrap();
+    let value = |format: BodyFormat, body: &[u8]| {
+        merkle::create_body_trie(format, body, &root, HashAlgorithm::Keccak)
+            .map(|trie| trie.get(b"key").unwrap())
+    };
+    match (value(BodyFormat::Json, &legacy), value(BodyFormat::Rlp, &MemoryDB::serialize_nodes(&nodes)), value(BodyFormat::Rlp, &legacy)) {
+        (Ok(Some(legacy)), Ok(Some(rlp)), Err(_)) if legacy == b"value" && rlp == b"value" => {
+            println!("Storage module test #9: OK\n");
+        },
+        _ => panic!("Storage module test #9: FAILED\nERROR: Block body formats not decoded"),
+    }
+
+    // a saved pool is reloaded as it was, without the transactions removed since
+    let tree = keyvalue::open_tree("mempool_test").unwrap();
+    let mut pool = Mempool::new();
+    pool.insert(pending_transaction(sender, 1, 10), 0, now).unwrap();
+    pool.insert(pending_transaction(sender, 2, 10), 0, now).unwrap();
+    pool.save(&tree).unwrap();
+    pool.remove_included(&[pending_transaction(sender, 1, 10)]);
+    pool.save(&tree).unwrap();
+    let loaded = Mempool::load(&tree, &AddressesState::new(), now).unwrap();
+    tree.clear().unwrap();
+    let nonces = loaded.select(2).iter().map(|transaction| transaction.nonce).collect::<Vec<u64>>();
+    if nonces == [2] {
+        println!("Storage module test #10: OK\n");
+    } else {
+        panic!("Storage module test #10: FAILED\nERROR: Saved pool not reloaded: {:?}", nonces);
+    }
+}
+
+fn pending_transaction(pk: [u8; 33], nonce: u64, fee: i64) -> Transaction {
+    let mut signature = [0u8; 64];
+    signature[..8].copy_from_slice(&nonce.to_be_bytes());
+    signature[8..16].copy_from_slice(&fee.to_be_bytes());
+    signature[16..49].copy_from_slice(&pk);
+    Transaction {
+        timestamp: 0,
+        pk,
+        data: TransactionData::Type2(DataTransactionType2::generate(vec![])),
+        fee,
+        nonce,
+        scheme: SignatureScheme::Ecdsa,
+        signature,
+    }
 }
 
 pub async fn comm_tests(){
     println!("\n\n--------------------- COMMUNICATION MODULE TESTS ---------------------");
 
-    let msg = communication::messages::Message::generate(1);
+    // signed by a mock signer, the keys of the node are not needed
+    let ctx = NodeContext { signer: Arc::new(MockSigner::new(2)) };
+    let msg = communication::messages::Message::generate(&ctx, 1).unwrap();
+    // as if the TLS session of the peer had authenticated its key
+    tls::set_peer_key("192.0.0.1:1000", ctx.signer.public_key().unwrap());
     let serialized_msg = match serde_json::to_string(&msg) {
         Ok(m) => {
             println!("Communication module test #1: OK\n");
@@ -164,13 +537,233 @@ pub async fn comm_tests(){
         },
         Err(e) => panic!("Communication module test #2: FAILED\nERROR: {}", e),
     };
-    let _: communication::responses::Response = match deserialized_msg.execute("192.0.0.1:1000".to_string()).await {
+    let _: communication::responses::Response = match deserialized_msg.execute(&ctx, "192.0.0.1:1000".to_string()).await {
         Ok(r) => {
             println!("Communication module test #3: OK\n");
             r
         },
         Err(e) => panic!("Communication module test #3: FAILED\nERROR: {}", e),
     };
+
+    let pk = [2u8; 33];
+    let mut policy = Policy::default();
+    policy.rules.insert(Permission::SyncBlocks, vec![Principal::Validators]);
+    policy.rules.insert(Permission::QueryTransactions, vec![Principal::PublicKey(hex::encode(pk))]);
+    if policy.allows(Permission::SyncBlocks, Some(&pk), true)
+        && !policy.allows(Permission::SyncBlocks, Some(&pk), false)
+        && policy.allows(Permission::QueryTransactions, Some(&pk), false)
+        && !policy.allows(Permission::QueryTransactions, None, true)
+        && policy.allows(Permission::ListNeighbors, None, false) {
+        println!("Communication module test #4: OK\n");
+    } else {
+        panic!("Communication module test #4: FAILED\nERROR: Access policy not enforced");
+    }
+}
+
+pub fn validation_tests(){
+    println!("\n\n--------------------- VALIDATION MODULE TESTS ---------------------");
+
+    let sender = signature::new_pair().1;
+    let recipient = signature::new_pair().1;
+    let transaction = |nonce: u64, data: TransactionData| Transaction {
+        timestamp: 0,
+        pk: sender,
+        data,
+        fee: 10,
+        nonce,
+        scheme: SignatureScheme::Ecdsa,
+        signature: [nonce as u8; 64],
+    };
+    let record = |nonce: u64| transaction(nonce, TransactionData::Type2(DataTransactionType2::generate(b"data".to_vec())));
+    let transfer = |nonce: u64| transaction(nonce, TransactionData::Type7(DataTransactionType7 {
+        recipient_pk: recipient,
+        balance_variation: 100,
+    }));
+
+    // a funded sender whose last applied nonce is 5
+    let mut state = AddressesState::new();
+    let mut address = Address::new();
+    address.balance = 1000;
+    address.nonce = 5;
+    state.set_address(&sender, address);
+    let apply = |state: &AddressesState, transactions: &[Transaction]| {
+        Block::apply_transactions(state.clone(), transactions, &[0; 33], false).unwrap()
+    };
+
+    let (_, rejected) = apply(&state, &[record(4), record(5)]);
+    if rejected.len() == 2 {
+        println!("Validation module test #1: OK\n");
+    } else {
+        panic!("Validation module test #1: FAILED\nERROR: Stale nonce accepted");
+    }
+
+    let mut duplicate = record(6);
+    duplicate.signature = [60; 64];
+    let (_, rejected) = apply(&state, &[record(6), duplicate]);
+    if rejected.len() == 1 {
+        println!("Validation module test #2: OK\n");
+    } else {
+        panic!("Validation module test #2: FAILED\nERROR: Duplicated nonce accepted");
+    }
+
+    let (_, rejected) = apply(&state, &[record(8)]);
+    let (_, in_sequence) = apply(&state, &[record(8), record(6), record(7)]);
+    if rejected.len() == 1 && in_sequence.is_empty() {
+        println!("Validation module test #3: OK\n");
+    } else {
+        panic!("Validation module test #3: FAILED\nERROR: Nonce gap not enforced");
+    }
+
+    // a transfer can't be applied again once the nonce of the sender advanced
+    let (after_transfer, rejected) = apply(&state, &[transfer(6)]);
+    let (after_record, _) = apply(&after_transfer, &[record(7)]);
+    let (_, replayed) = apply(&after_record, &[transfer(6)]);
+    let balance = after_transfer.get_address(&recipient).map(|address| address.balance);
+    if rejected.is_empty() && balance == Some(100) && replayed.len() == 1 {
+        println!("Validation module test #4: OK\n");
+    } else {
+        panic!("Validation module test #4: FAILED\nERROR: Transfer replayed");
+    }
+
+    let mut broke = AddressesState::new();
+    broke.set_address(&sender, Address::new());
+    let (_, rejected) = apply(&broke, &[transaction(1, TransactionData::Type2(DataTransactionType2::generate(vec![])))]);
+    if rejected.len() == 1 {
+        println!("Validation module test #5: OK\n");
+    } else {
+        panic!("Validation module test #5: FAILED\nERROR: Fee paid without balance");
+    }
+
+    // a federation member signs a single block on top of each previous block,
+    // another one only when the round of the first failed and it was resealed
+    let previous = signature::new_pair().1;
+    let committed = signature::new_pair().1;
+    let first = federation::record_signed(&previous, b"first block");
+    let again = federation::record_signed(&previous, b"first block");
+    let resealed = federation::record_signed(&previous, &committed);
+    let saved = keyvalue::insert(&committed, b"committed block");
+    let competing = federation::record_signed(&previous, b"competing block");
+    keyvalue::remove(format!("federation_signed:{}", hex::encode(previous)).as_bytes()).unwrap();
+    keyvalue::remove(&committed).unwrap();
+    if first.is_ok() && again.is_ok() && resealed.is_ok() && saved.is_ok() && competing.is_err() {
+        println!("Validation module test #6: OK\n");
+    } else {
+        panic!("Validation module test #6: FAILED\nERROR: Competing block signed or reseal refused");
+    }
+
+    let public_key = signature::new_pair().1;
+    let encoded = address::encode_address(&public_key);
+    match AddressFormat::parse(&format!("{}\n", encoded)) {
+        Ok(parsed) if encoded.starts_with("iot1") && parsed.address == public_key => {
+            println!("Validation module test #7: OK\n");
+        },
+        Ok(_) => panic!("Validation module test #7: FAILED\nERROR: Address does not round-trip"),
+        Err(e) => panic!("Validation module test #7: FAILED\nERROR: {}", e),
+    }
+
+    // same key under another prefix, or with the bech32 checksum instead of bech32m
+    let other_network = bech32::encode("bc", public_key.to_base32(), Variant::Bech32m).unwrap();
+    let other_variant = bech32::encode(address::ADDRESS_PREFIX, public_key.to_base32(), Variant::Bech32).unwrap();
+    if !address::is_valid_address(&other_network) && !address::is_valid_address(&other_variant) {
+        println!("Validation module test #8: OK\n");
+    } else {
+        panic!("Validation module test #8: FAILED\nERROR: Address from another network accepted");
+    }
+
+    // a single mistyped character breaks the checksum
+    let mut typo = encoded.clone().into_bytes();
+    let last = typo.len() - 1;
+    typo[last] = if typo[last] == b'q' { b'p' } else { b'q' };
+    if !address::is_valid_address(&String::from_utf8(typo).unwrap()) {
+        println!("Validation module test #9: OK\n");
+    } else {
+        panic!("Validation module test #9: FAILED\nERROR: Bad checksum accepted");
+    }
+
+    let not_a_point = bech32::encode(address::ADDRESS_PREFIX, [0u8; 33].to_base32(), Variant::Bech32m).unwrap();
+    let short = bech32::encode(address::ADDRESS_PREFIX, [2u8; 32].to_base32(), Variant::Bech32m).unwrap();
+    if !address::is_valid_address(&not_a_point) && !address::is_valid_address(&short) {
+        println!("Validation module test #10: OK\n");
+    } else {
+        panic!("Validation module test #10: FAILED\nERROR: Invalid public key accepted");
+    }
+
+    // a member of the chain federation is a validator, any other key isn't
+    let chain_federation = keyvalue::get(b"federation").unwrap();
+    let member = signature::new_pair().1;
+    federation::set_chain_federation(&federation::Federation::new(1, vec![member.to_vec()]).unwrap());
+    let validators = (Block::is_validator(&member), Block::is_validator(&signature::new_pair().1));
+    match chain_federation {
+        Some(chain_federation) => keyvalue::insert(b"federation", &chain_federation).unwrap(),
+        None => keyvalue::remove(b"federation").unwrap(),
+    }
+    if validators == (true, false) {
+        println!("Validation module test #11: OK\n");
+    } else {
+        panic!("Validation module test #11: FAILED\nERROR: Validators not taken from the chain: {:?}", validators);
+    }
+
+    // a block stored before fees, nonces and rlp bodies, its transactions carry
+    // a balance variation instead of a fee
+    let baseline = serde_json::json!({
+        "timestamp": 0,
+        "pk": sender.to_vec(),
+        "data": {"Type2": {"data": [1, 2, 3]}},
+        "balance_variation": 0,
+        "signature": vec![0u8; 64],
+    });
+    let mut trie = PatriciaTrie::new(Arc::new(MemoryDB::new(true)), Arc::new(ChainHasher::new(hashing::chain_algorithm())));
+    trie.insert(vec![0u8; 64], serde_json::to_vec(&baseline).unwrap()).unwrap();
+    let header = trie.root().unwrap();
+    let body = serde_json::to_vec(&serde_json::json!({
+        "data": trie.reachable_nodes().unwrap().iter()
+            .map(|(hash, node)| (hex::encode(hash), serde_json::Value::String(hex::encode(node))))
+            .collect::<serde_json::Map<String, serde_json::Value>>()
+    })).unwrap();
+    let block = serde_json::from_value::<Block>(serde_json::json!({
+        "header": header,
+        "issuer": sender.to_vec(),
+        "addresses_state": {"state": {}},
+        "body": body,
+        "timestamp": 0,
+        "previous_block_header": [1],
+        "issuer_signature": vec![0u8; 64],
+        "federation_signature": "Unsigned",
+    }));
+    match block.map_err(Error::from).and_then(|block| block.transactions()) {
+        Ok(transactions) if transactions.len() == 1 && transactions[0].fee == 0 && transactions[0].nonce == 0 => {
+            println!("Validation module test #12: OK\n");
+        },
+        Ok(_) => panic!("Validation module test #12: FAILED\nERROR: Baseline transactions decoded wrong"),
+        Err(e) => panic!("Validation module test #12: FAILED\nERROR: {}", e),
+    }
+
+    // a schnorr signature holds for both parities of the key, the transaction
+    // must only verify under the key that signed it
+    let chain_scheme = signature::chain_scheme();
+    signature::set_chain_scheme(SignatureScheme::Schnorr);
+    let signed = Transaction::sign_with_nonce(&MockSigner::new(3), TransactionData::Type2(DataTransactionType2::generate(vec![1])), 10, 1);
+    signature::set_chain_scheme(chain_scheme);
+    let signed = signed.unwrap();
+    let mut flipped = signed.clone();
+    flipped.pk[0] ^= 1;
+    match (signed.verify(), flipped.verify()) {
+        (Ok(()), Err(_)) => println!("Validation module test #13: OK\n"),
+        (Err(e), _) => panic!("Validation module test #13: FAILED\nERROR: {}", e),
+        (Ok(()), Ok(())) => panic!("Validation module test #13: FAILED\nERROR: Key with the other parity accepted"),
+    }
+
+    // a transfer typed with an address pays the key behind it
+    let recipient = address::encode_address(&signature::new_pair().1);
+    let transfer = AddressFormat::parse(&recipient)
+        .and_then(|address| Transaction::generate_transfer(&MockSigner::new(4), &address, 100));
+    match transfer.map(|transfer| transfer.data) {
+        Ok(TransactionData::Type7(data)) if data.recipient_address().encode() == recipient => {
+            println!("Validation module test #14: OK\n");
+        },
+        Ok(_) => panic!("Validation module test #14: FAILED\nERROR: Transfer to another recipient"),
+        Err(e) => panic!("Validation module test #14: FAILED\nERROR: {}", e),
+    }
 }
 
 fn checksum(data: &[u8]) -> u32 {
@@ -1,30 +1,51 @@
-use std::{net::{SocketAddr, Ipv4Addr}, time::Duration, io::{Error, ErrorKind}};
-use bytes::Bytes;
+use std::{net::{SocketAddr, Ipv4Addr}, io::{Error, ErrorKind}, sync::Arc};
 use color_eyre::Result;
-use qp2p::{Endpoint, IncomingConnections, Config};
-
-pub async fn test_server(port_number: String) -> Result<(Endpoint, IncomingConnections), Error> {
-    match Endpoint::new_peer(
-        SocketAddr::from((Ipv4Addr::LOCALHOST, port_number.parse().unwrap())),
-        &[],
-        Config {
-            idle_timeout: Duration::from_secs(60 * 60).into(), // 1 hour idle timeout.
-            ..Default::default()
-        },
-    ).await {
-        Ok((node, incoming, _contact)) => Ok((node, incoming)),
-        Err(e) => Err(Error::new(ErrorKind::AddrNotAvailable, e)),
-    }
+use futures::StreamExt;
+use quinn::{Endpoint, Incoming, NewConnection};
+use rustls::{Certificate, PrivateKey};
+
+use crate::NodeContext;
+use crate::signature::signer::MemorySigner;
+use crate::transport::{self, tls};
+
+pub async fn test_server(port_number: String) -> Result<(Endpoint, Incoming), Error> {
+    let ctx = NodeContext { signer: Arc::new(MemorySigner::random()) };
+    let (cert, key) = tls::generate(&ctx)?;
+    test_endpoint(port_number, cert, key)
+}
+
+// endpoint whose certificate isn't endorsed by any node key
+pub async fn test_unendorsed(port_number: String) -> Result<(Endpoint, Incoming), Error> {
+    let cert = rcgen::generate_simple_self_signed(vec![tls::SERVER_NAME.to_string()])
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let key = PrivateKey(cert.serialize_private_key_der());
+    let cert = Certificate(cert.serialize_der().map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?);
+    test_endpoint(port_number, cert, key)
+}
+
+fn test_endpoint(port_number: String, cert: Certificate, key: PrivateKey) -> Result<(Endpoint, Incoming), Error> {
+    let (server, client) = tls::configs(cert, key)?;
+    transport::create_endpoint(SocketAddr::from((Ipv4Addr::LOCALHOST, port_number.parse().unwrap())), server, client)
+        .map_err(|e| Error::new(ErrorKind::AddrNotAvailable, e))
 }
 
-pub async fn test_listen(mut incoming: IncomingConnections) -> Result<()> {
-    while let Some((connection, mut incoming_messages)) = incoming.next().await {
-        let src = connection.remote_address();
+pub async fn test_listen(mut incoming: Incoming) -> Result<()> {
+    while let Some(connecting) = incoming.next().await {
+        let src = connecting.remote_address();
+        let NewConnection { mut bi_streams, .. } = match connecting.await {
+            Ok(new_connection) => new_connection,
+            Err(e) => {
+                println!("Refused {:?}: {}", src, e);
+                continue;
+            }
+        };
 
         // loop over incoming messages
-        while let Some(bytes) = incoming_messages.next().await? {
-            println!("Received: {:?} from {:?}", String::from_utf8(bytes.to_vec()), src.to_string());
-            connection.send(Bytes::from("Ok")).await?;
+        while let Some(Ok((mut send, recv))) = bi_streams.next().await {
+            let bytes = recv.read_to_end(1024).await?;
+            println!("Received: {:?} from {:?}", String::from_utf8(bytes), src.to_string());
+            send.write_all(b"Ok").await?;
+            send.finish().await?;
         }
     }
 
@@ -36,9 +57,11 @@ pub async fn test_send(node: Endpoint, addr: String, msg: String) -> Result<()>
             .parse()
             .expect("Invalid SocketAddr.  Use the form 127.0.0.1:1234");
 
-    let (connection, mut incoming) = node.connect_to(&addr.parse().unwrap()).await?;
-    connection.send(Bytes::from(msg)).await?;
-    let reply = incoming.next().await?.unwrap();
-    println!("Received from {:?} --> {:?}", peer, reply);
+    let NewConnection { connection, .. } = node.connect(peer, tls::SERVER_NAME)?.await?;
+    let (mut send, recv) = connection.open_bi().await?;
+    send.write_all(msg.as_bytes()).await?;
+    send.finish().await?;
+    let reply = recv.read_to_end(1024).await?;
+    println!("Received from {:?} --> {:?}", peer, String::from_utf8_lossy(&reply));
     Ok(())
-}
\ No newline at end of file
+}
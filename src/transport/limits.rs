@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+use crate::communication::neighbors::Neighbors;
+use crate::storage::keyvalue;
+use crate::validation::block::Block;
+
+// Budgets are token buckets refilled every second. Peers are counted by IP, so
+// opening more sockets doesn't buy a peer more budget
+pub const PEER_RATE: f64 = 20.0;
+pub const PEER_BURST: f64 = 100.0;
+pub const GLOBAL_RATE: f64 = 500.0;
+pub const GLOBAL_BURST: f64 = 2000.0;
+// validators of the chain get a larger budget, they sync blocks and
+// collect federation shares far more often than sensors
+pub const VALIDATOR_FACTOR: f64 = 5.0;
+// cost of every KiB of a packet, on top of the cost of its message type
+pub const KIB_COST: f64 = 1.0;
+// cost of a packet that can't be parsed
+pub const INVALID_PACKET_COST: f64 = 20.0;
+
+pub const MAX_CONNECTIONS: usize = 256;
+pub const MAX_CONNECTIONS_PER_PEER: usize = 4;
+pub const MAX_STREAMS_PER_CONNECTION: usize = 8;
+// packets are read whole, a stream sending more is dropped
+pub const MAX_PACKET_SIZE: usize = 32 * 1024 * 1024;
+
+// times a peer can go over its budget before it's banned
+pub const STRIKES_BEFORE_BAN: u32 = 10;
+pub const BAN_SECONDS: i64 = 60 * 60;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Throttle,
+    Ban,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(burst: f64) -> Self {
+        Bucket { tokens: burst, last: Instant::now() }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * rate).min(burst);
+        self.last = now;
+    }
+
+    fn take(&mut self, cost: f64) -> bool {
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+struct Peer {
+    bucket: Bucket,
+    strikes: u32,
+    connections: usize,
+}
+
+struct Limiter {
+    global: Bucket,
+    peers: HashMap<IpAddr, Peer>,
+    connections: usize,
+}
+
+lazy_static! {
+    static ref LIMITER: Mutex<Limiter> = Mutex::new(Limiter {
+        global: Bucket::new(GLOBAL_BURST),
+        peers: HashMap::new(),
+        connections: 0,
+    });
+    // ban expirations by peer, loaded from the keyvalue store at startup
+    static ref BANNED: Mutex<HashMap<IpAddr, i64>> = Mutex::new(HashMap::new());
+    // whether the neighbor at an address is a validator, until a block or
+    // the neighbor table changes
+    static ref VALIDATORS: Mutex<HashMap<SocketAddr, bool>> = Mutex::new(HashMap::new());
+}
+
+fn peer_budget(src: &SocketAddr) -> (f64, f64) {
+    let cached = VALIDATORS.lock().unwrap().get(src).copied();
+    let validator = match cached {
+        Some(validator) => validator,
+        None => {
+            let validator = Neighbors::get(&src.to_string()).is_some_and(|node| Block::is_validator(&node.pk));
+            VALIDATORS.lock().unwrap().insert(*src, validator);
+            validator
+        },
+    };
+    match validator {
+        true => (PEER_RATE * VALIDATOR_FACTOR, PEER_BURST * VALIDATOR_FACTOR),
+        false => (PEER_RATE, PEER_BURST),
+    }
+}
+
+// forgets the validators cached, once a block is saved or a neighbor changes
+pub fn refresh_validators() {
+    VALIDATORS.lock().unwrap().clear();
+}
+
+// charges a request to the peer and to the node. Going over the global budget
+// only throttles, going over the budget of the peer also counts a strike
+pub fn charge(src: &SocketAddr, cost: f64) -> Verdict {
+    if is_banned(&src.ip()) {
+        return Verdict::Ban;
+    }
+    let (rate, burst) = peer_budget(src);
+
+    let mut limiter = LIMITER.lock().unwrap();
+    let peer = limiter.peers.entry(src.ip()).or_insert_with(|| Peer {
+        bucket: Bucket::new(burst),
+        strikes: 0,
+        connections: 0,
+    });
+    peer.bucket.refill(rate, burst);
+    // strikes are forgiven once the peer lets its budget fill up again
+    if peer.bucket.tokens >= burst {
+        peer.strikes = 0;
+    }
+    if !peer.bucket.take(cost) {
+        peer.strikes += 1;
+        if peer.strikes < STRIKES_BEFORE_BAN {
+            return Verdict::Throttle;
+        }
+        limiter.peers.remove(&src.ip());
+        drop(limiter);
+        if let Err(e) = ban(&src.ip()) {
+            println!("Erro ao salvar banimento: {}", e);
+        }
+        return Verdict::Ban;
+    }
+
+    limiter.global.refill(GLOBAL_RATE, GLOBAL_BURST);
+    if !limiter.global.take(cost) {
+        // refund the peer, the node being busy is not its fault
+        if let Some(peer) = limiter.peers.get_mut(&src.ip()) {
+            peer.bucket.tokens += cost;
+        }
+        return Verdict::Throttle;
+    }
+    Verdict::Allow
+}
+
+// counts an open connection until the guard is dropped
+pub struct ConnectionGuard {
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut limiter = LIMITER.lock().unwrap();
+        limiter.connections = limiter.connections.saturating_sub(1);
+        if let Some(peer) = limiter.peers.get_mut(&self.ip) {
+            peer.connections = peer.connections.saturating_sub(1);
+        }
+    }
+}
+
+pub fn open_connection(src: &SocketAddr) -> Option<ConnectionGuard> {
+    if is_banned(&src.ip()) {
+        return None;
+    }
+    let (_, burst) = peer_budget(src);
+
+    let mut limiter = LIMITER.lock().unwrap();
+    if limiter.connections >= MAX_CONNECTIONS {
+        return None;
+    }
+    let peer = limiter.peers.entry(src.ip()).or_insert_with(|| Peer {
+        bucket: Bucket::new(burst),
+        strikes: 0,
+        connections: 0,
+    });
+    if peer.connections >= MAX_CONNECTIONS_PER_PEER {
+        return None;
+    }
+    peer.connections += 1;
+    limiter.connections += 1;
+    Some(ConnectionGuard { ip: src.ip() })
+}
+
+// reads the bans kept from previous runs
+pub fn load_bans() -> Result<(), Error> {
+    let stored: HashMap<IpAddr, i64> = match keyvalue::get(b"banned_peers")? {
+        Some(data) => serde_json::from_slice(&data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        None => HashMap::new(),
+    };
+    *BANNED.lock().unwrap() = stored;
+    Ok(())
+}
+
+fn save_bans(banned: &HashMap<IpAddr, i64>) -> Result<(), Error> {
+    let data = serde_json::to_vec(banned).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    keyvalue::insert(b"banned_peers", &data)
+}
+
+pub fn is_banned(ip: &IpAddr) -> bool {
+    match BANNED.lock().unwrap().get(ip) {
+        Some(until) => *until > chrono::Utc::now().timestamp(),
+        None => false,
+    }
+}
+
+// bans are kept in the keyvalue store so they survive a restart, and the peer
+// leaves the neighbor table so gossip and sync stop reaching out to it
+pub fn ban(ip: &IpAddr) -> Result<(), Error> {
+    println!("Banindo {} por {} segundos", ip, BAN_SECONDS);
+    let now = chrono::Utc::now().timestamp();
+    let mut banned = BANNED.lock().unwrap();
+    banned.retain(|_, until| *until > now);
+    banned.insert(*ip, now + BAN_SECONDS);
+    save_bans(&banned)?;
+    drop(banned);
+    Neighbors::remove_host(&ip.to_string());
+    Ok(())
+}
+
+pub fn unban(ip: &IpAddr) -> Result<(), Error> {
+    let mut banned = BANNED.lock().unwrap();
+    banned.remove(ip);
+    save_bans(&banned)
+}
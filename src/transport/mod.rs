@@ -1,107 +1,227 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use async_once::AsyncOnce;
 use async_recursion::async_recursion;
 use color_eyre::eyre::Result;
-use bytes::Bytes;
+use futures::StreamExt;
 use lazy_static::lazy_static;
-use qp2p::{Config, Endpoint, IncomingConnections};
+use quinn::{Connecting, Endpoint, IdleTimeout, Incoming, NewConnection, TransportConfig};
 use std::{
+    io::Error,
     net::{Ipv4Addr, SocketAddr},
     time::Duration, sync::{Arc},
 };
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell, Semaphore};
 
-use crate::{PORT_NUMBER, communication::{messages::{Packet}, responses::Response}};
+use crate::{NodeContext, PORT_NUMBER, communication::{messages::{Packet}, responses::Response}};
+
+pub mod limits;
+pub mod tls;
+
+use limits::{ConnectionGuard, Verdict};
 
 lazy_static! {
-    // QUIC_CONN.1 = Endpoint, QUIC_CONN.2 = IncomingConnections
-
-    pub static ref QUIC_CONN: AsyncOnce<(Arc<Mutex<Endpoint>>, Arc<Mutex<IncomingConnections>>)> = 
-        AsyncOnce::new(async{
-            let (node, incoming, _contact) = Endpoint::new_peer(
-                SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT_NUMBER.parse().unwrap())),
-                &[],
-                Config {
-                    idle_timeout: Duration::from_secs(60 * 60).into(), // 1 hour idle timeout.
-                    ..Default::default()
-                },
-            ).await.unwrap();
-            (Arc::new(Mutex::new(node)), Arc::new(Mutex::new(incoming)))
-        });
-    
+    // QUIC_CONN.0 = Endpoint, QUIC_CONN.1 = Incoming
+    static ref QUIC_CONN: OnceCell<(Endpoint, Mutex<Incoming>)> = OnceCell::new();
+}
 
-    pub static ref LOCAL_ADDR: AsyncOnce<std::string::String> = AsyncOnce::new(async{
-        QUIC_CONN.get().await.0.lock().await.public_addr().to_string()
-    });
+// endpoint of the node, created on first use with a certificate endorsed by
+// the node key. Peers must present one too
+pub async fn endpoint(ctx: &NodeContext) -> Result<&'static (Endpoint, Mutex<Incoming>), Error> {
+    QUIC_CONN.get_or_try_init(|| async {
+        let (cert, key) = tls::generate(ctx)?;
+        let (server, client) = tls::configs(cert, key)?;
+        create_endpoint(SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT_NUMBER.parse().unwrap())), server, client)
+            .map(|(endpoint, incoming)| (endpoint, Mutex::new(incoming)))
+    }).await
 }
 
-pub async fn listen() -> Result<()> {
-    
+pub fn create_endpoint(addr: SocketAddr, mut server: quinn::ServerConfig, mut client: quinn::ClientConfig) -> Result<(Endpoint, Incoming), Error> {
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(Some(IdleTimeout::try_from(Duration::from_secs(60 * 60)).unwrap())); // 1 hour idle timeout.
+    let transport = Arc::new(transport);
+    server.transport = transport.clone();
+    client.transport = transport;
+
+    let (mut endpoint, incoming) = Endpoint::server(server, addr)?;
+    endpoint.set_default_client_config(client);
+    Ok((endpoint, incoming))
+}
+
+pub async fn listen(ctx: NodeContext) -> Result<()> {
+    let (_, incoming) = endpoint(&ctx).await?;
+
     // loop over incoming connections
-    while let Some((connection, mut incoming_messages)) = 
-    QUIC_CONN.get().await.1.lock().await.next().await {
-        let src = connection.remote_address();
-
-        // loop over incoming messages
-        while let Some(bytes) = incoming_messages.next().await? {
-            match callback(&bytes.to_vec(), 
-            src.to_string()).await {
+    while let Some(connecting) = incoming.lock().await.next().await {
+        let src = connecting.remote_address();
+
+        let guard = match limits::open_connection(&src) {
+            Some(guard) => guard,
+            None => {
+                println!("Conexão recusada de {:?}", src);
+                continue;
+            }
+        };
+        tokio::spawn(handle_connection(ctx.clone(), connecting, guard));
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(ctx: NodeContext, connecting: Connecting, _guard: ConnectionGuard) {
+    let src = connecting.remote_address();
+    let NewConnection { connection, mut bi_streams, .. } = match connecting.await {
+        Ok(new_connection) => new_connection,
+        Err(e) => {
+            println!("Erro na conexão com {:?}: {}", src, e);
+            return;
+        }
+    };
+    if let Err(e) = tls::authenticate(&connection, &src.to_string()) {
+        println!("Conexão recusada de {:?}: {}", src, e);
+        return;
+    }
+    let streams = Arc::new(Semaphore::new(limits::MAX_STREAMS_PER_CONNECTION));
+
+    // loop over incoming messages, each one on its own stream
+    loop {
+        let (mut send, recv) = match bi_streams.next().await {
+            Some(Ok(stream)) => stream,
+            None | Some(Err(quinn::ConnectionError::ApplicationClosed(_))) => break,
+            Some(Err(e)) => {
+                println!("Erro na conexão com {:?}: {}", src, e);
+                break;
+            }
+        };
+        // a banned peer loses the connection, dropping it closes the streams
+        if limits::is_banned(&src.ip()) {
+            break;
+        }
+        // reading stops while every stream is busy, so a peer can't pile up work
+        let permit = streams.clone().acquire_owned().await.unwrap();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            let bytes = match recv.read_to_end(limits::MAX_PACKET_SIZE).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("Erro ao ler de {:?}: {}", src, e);
+                    return;
+                }
+            };
+            match callback(&ctx, &bytes, src.to_string()).await {
                 Some(response) => {
-                    connection.send(Bytes::from(response)).await?;
+                    if let Err(e) = send.write_all(response.as_bytes()).await {
+                        println!("Erro ao responder {:?}: {}", src, e);
+                    }
                 }
                 None => {
                     println!("Error: No response");
                 }
             }
-        }
+            let _ = send.finish().await;
+            drop(permit);
+        });
     }
+}
 
-    Ok(())
+// charges a packet to the budget of its sender, addresses that aren't sockets
+// are local and not limited
+fn charge(src: &str, cost: f64, len: usize) -> Verdict {
+    match src.parse::<SocketAddr>() {
+        Ok(addr) => limits::charge(&addr, cost + (len / 1024) as f64 * limits::KIB_COST),
+        Err(_) => Verdict::Allow,
+    }
 }
 
-pub async fn send(addr: String, msg: String) -> Result<()> {
+pub async fn send(ctx: &NodeContext, addr: String, msg: String) -> Result<()> {
 
     let peer: SocketAddr = addr
             .parse()
             .expect("Invalid SocketAddr.  Use the form 127.0.0.1:1234");
-        let msg = Bytes::from(msg);
         println!("Enviando para {:?} --> {:?}\n", peer, msg);
-        let (conn, mut incoming) = QUIC_CONN.get().await.0.lock().await.connect_to(&peer).await?;
-        conn.send(msg.clone()).await?;
-        // `Endpoint` no longer having `connection_pool` to hold established connection.
-        // Which means the connection get closed immediately when it reaches end of life span.
-        // And causes the receiver side a sending error when reply via the in-coming connection.
-        // Hence here have to listen for the reply to avoid such error
-        let reply = incoming.next().await?.unwrap();
-        callback(&reply.to_vec(), peer.to_string()).await;
+        let (endpoint, _) = endpoint(ctx).await?;
+        let NewConnection { connection, .. } = endpoint.connect(peer, tls::SERVER_NAME)?.await?;
+        tls::authenticate(&connection, &peer.to_string())?;
+        // the reply comes back on the stream of the message
+        let (mut send, recv) = connection.open_bi().await?;
+        send.write_all(msg.as_bytes()).await?;
+        send.finish().await?;
+        let reply = recv.read_to_end(limits::MAX_PACKET_SIZE).await?;
+        if !reply.is_empty() {
+            handle_reply(ctx, &reply, peer.to_string()).await;
+        }
 
     println!("Envio finalizado");
     Ok(())
 }
 
+// response with no data but its type, None when it can't be signed
+fn respond(ctx: &NodeContext, data_type: u32) -> Option<String> {
+    match Response::generate(ctx, data_type) {
+        Ok(response) => Some(serde_json::to_string(&Packet::Response(response)).unwrap()),
+        Err(e) => {
+            println!("Erro ao assinar resposta: {}", e);
+            None
+        }
+    }
+}
+
+// reply on a stream this node opened, the only place a response is accepted
 #[async_recursion]
-pub async fn callback(bytes: &Vec<u8>, src: String) -> Option<String> {
+async fn handle_reply(ctx: &NodeContext, bytes: &[u8], src: String) {
+    println!("Recebido de {:?} --> {:?}\n", src, String::from_utf8_lossy(bytes));
+    match serde_json::from_slice(bytes) {
+        Ok(Packet::Response(res)) => {
+            if let Err(e) = res.execute(ctx, src.clone()).await {
+                println!("Resposta rejeitada: {}", e);
+            }
+        },
+        Ok(Packet::Message(_)) => println!("Mensagem inesperada em resposta de {:?}", src),
+        Err(e) => println!("Resposta inválida de {:?}: {}", src, e),
+    }
+}
 
-    println!("Recebido de {:?} --> {:?}\n", src, std::str::from_utf8(bytes).unwrap());
-    let request: Packet = serde_json::from_slice(bytes).unwrap();
+#[async_recursion]
+pub async fn callback(ctx: &NodeContext, bytes: &[u8], src: String) -> Option<String> {
+
+    println!("Recebido de {:?} --> {:?}\n", src, String::from_utf8_lossy(bytes));
+    let request: Packet = match serde_json::from_slice(bytes) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("Pacote inválido de {:?}: {}", src, e);
+            if charge(&src, limits::INVALID_PACKET_COST, bytes.len()) == Verdict::Ban {
+                return None;
+            }
+            return respond(ctx, 500);
+        }
+    };
 
             let response: Option<Packet>;
             match request {
                 Packet::Message(msg) => {
-                    response = Some(match msg.execute(src.clone()).await {
-                        Ok(response) => {
-                            println!("Enviando resposta para {:?} --> {:?}\n", src.clone(), serde_json::to_string(&response).unwrap());
-                            Packet::Response(response)
+                    match charge(&src, msg.cost(), bytes.len()) {
+                        Verdict::Allow => (),
+                        Verdict::Throttle => {
+                            println!("Limite de requisições excedido por {:?}", src);
+                            return respond(ctx, 429);
+                        }
+                        Verdict::Ban => return None,
+                    }
+                    match msg.execute(ctx, src.clone()).await {
+                        Ok(reply) => {
+                            println!("Enviando resposta para {:?} --> {:?}\n", src.clone(), serde_json::to_string(&reply).unwrap());
+                            response = Some(Packet::Response(reply))
                         },
                         Err(err) => {
                             println!("Erro: {:?}", err);
-                            Packet::Response(Response::generate(500).unwrap())
-                        }});
+                            return respond(ctx, 500);
+                        }}
                 },
-                Packet::Response(res) => {
-                    res.execute(src.clone()).await.unwrap();
+                // responses only come back on streams opened by send, one
+                // arriving unasked is charged as an invalid packet
+                Packet::Response(_) => {
+                    println!("Resposta não solicitada de {:?}", src);
+                    charge(&src, limits::INVALID_PACKET_COST, bytes.len());
                     response = None
                 }
             } 
@@ -0,0 +1,191 @@
+use std::{collections::HashMap, io::{Error, ErrorKind}, sync::{Arc, Mutex}, time::SystemTime};
+
+use lazy_static::lazy_static;
+use quinn::Connection;
+use rcgen::{CertificateParams, CustomExtension, KeyPair, PKCS_ECDSA_P256_SHA256};
+use rustls::{Certificate, DistinguishedNames, PrivateKey, ServerName};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use yasna::{models::ObjectIdentifier, Tag};
+
+use crate::{NodeContext, signature, communication::neighbors::Neighbors};
+
+// name every node presents, peers are told apart by key and not by name
+pub const SERVER_NAME: &str = "secure-iot-protocol";
+
+// extension carrying the node key and its endorsement of the certificate key,
+// only read by other nodes. 1.3.6.1.4.1.99999 is not an assigned enterprise
+// number, it's a private placeholder until the project registers its own arc
+const NODE_KEY_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 99999, 1, 1];
+
+// prefix of the endorsed message, so the signature can't be taken for anything else
+const ENDORSEMENT_PREFIX: &[u8] = b"secure-iot-tls:";
+
+lazy_static! {
+    // node key authenticated by the TLS session of each peer address
+    static ref PEER_KEYS: Mutex<HashMap<String, [u8; 33]>> = Mutex::new(HashMap::new());
+}
+
+// self-signed certificate of a fresh P-256 key, endorsed by the node key. rustls
+// can't handshake with secp256k1, so the node key signs the certificate key
+// instead of being it
+pub fn generate(ctx: &NodeContext) -> Result<(Certificate, PrivateKey), Error> {
+    let key_pair = KeyPair::generate(&PKCS_ECDSA_P256_SHA256).map_err(other)?;
+    let spki = key_pair.public_key_der();
+    let pk = ctx.signer.public_key()?;
+    let signature = ctx.signer.sign(&[ENDORSEMENT_PREFIX, &spki].concat())?;
+    let endorsement = yasna::construct_der(|writer| writer.write_sequence(|writer| {
+        writer.next().write_bytes(&pk);
+        writer.next().write_bytes(&signature);
+    }));
+
+    let mut params = CertificateParams::new(vec![SERVER_NAME.to_string()]);
+    params.alg = &PKCS_ECDSA_P256_SHA256;
+    params.key_pair = Some(key_pair);
+    params.custom_extensions = vec![CustomExtension::from_oid_content(NODE_KEY_OID, endorsement)];
+    let cert = rcgen::Certificate::from_params(params).map_err(other)?;
+    Ok((Certificate(cert.serialize_der().map_err(other)?), PrivateKey(cert.serialize_private_key_der())))
+}
+
+// node key that endorsed the certificate, the handshake itself proves the
+// peer holds the certificate key
+pub fn node_key(cert: &[u8]) -> Result<[u8; 33], Error> {
+    let (spki, endorsement) = parse_certificate(cert)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid certificate: {}", e)))?;
+    let endorsement = endorsement
+        .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Certificate not endorsed by a node key"))?;
+    let (pk, signature) = yasna::parse_der(&endorsement, |reader| reader.read_sequence(|reader| {
+        let pk = reader.next().read_bytes()?;
+        let signature = reader.next().read_bytes()?;
+        Ok((pk, signature))
+    })).map_err(|e| Error::new(ErrorKind::InvalidData, format!("Invalid endorsement: {}", e)))?;
+
+    signature::verify_signature(&[ENDORSEMENT_PREFIX, &spki].concat(), &pk, &signature)?;
+    pk.try_into().map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid node key length"))
+}
+
+// subject public key info and node extension of a certificate
+fn parse_certificate(cert: &[u8]) -> yasna::ASN1Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let oid = ObjectIdentifier::from_slice(NODE_KEY_OID);
+    yasna::parse_der(cert, |reader| reader.read_sequence(|reader| {
+        let tbs = reader.next().read_sequence(|reader| {
+            reader.read_optional(|reader| reader.read_tagged(Tag::context(0), |reader| reader.read_u8()))?;
+            for _ in 0..5 {
+                // serial, signature algorithm, issuer, validity and subject
+                reader.next().read_der()?;
+            }
+            let spki = reader.next().read_der()?;
+            let mut endorsement = None;
+            reader.read_optional(|reader| reader.read_tagged(Tag::context(3), |reader| {
+                reader.read_sequence_of(|reader| reader.read_sequence(|reader| {
+                    let id = reader.next().read_oid()?;
+                    reader.read_optional(|reader| reader.read_bool())?;
+                    let value = reader.next().read_bytes()?;
+                    if id == oid {
+                        endorsement = Some(value);
+                    }
+                    Ok(())
+                }))
+            }))?;
+            Ok((spki, endorsement))
+        })?;
+        reader.next().read_der()?;
+        reader.next().read_der()?;
+        Ok(tbs)
+    }))
+}
+
+// binds the TLS identity of a connection to its address. An address the
+// neighbor table knows can only be used by the key recorded for it
+pub fn authenticate(connection: &Connection, src: &str) -> Result<[u8; 33], Error> {
+    let certs = connection.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<Certificate>>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Peer sent no certificate"))?;
+    let cert = certs.first()
+        .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Peer sent no certificate"))?;
+    let pk = node_key(&cert.0)?;
+    if let Some(node) = Neighbors::get(src) {
+        if node.pk != pk {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Address bound to another public key"));
+        }
+    }
+    set_peer_key(src, pk);
+    Ok(pk)
+}
+
+pub fn set_peer_key(src: &str, pk: [u8; 33]) {
+    PEER_KEYS.lock().unwrap().insert(src.to_string(), pk);
+}
+
+pub fn peer_key(src: &str) -> Option<[u8; 33]> {
+    PEER_KEYS.lock().unwrap().get(src).copied()
+}
+
+// a key announced by a peer must be the one its TLS session authenticated
+pub fn check_peer_key(src: &str, pk: &[u8; 33]) -> Result<(), Error> {
+    match peer_key(src) {
+        Some(key) if key == *pk => Ok(()),
+        _ => Err(Error::new(ErrorKind::PermissionDenied, "Public key doesn't match the TLS identity")),
+    }
+}
+
+// accepts any certificate endorsed by a node key, whether that key may use
+// the address is checked by authenticate once the handshake is done
+pub struct NodeKeyVerifier;
+
+impl ServerCertVerifier for NodeKeyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        node_key(&end_entity.0).map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for NodeKeyVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        Some(Vec::new())
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        node_key(&end_entity.0).map_err(|e| rustls::Error::InvalidCertificateData(e.to_string()))?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+// server and client configs presenting the certificate and requiring one from the peer
+pub fn configs(cert: Certificate, key: PrivateKey) -> Result<(quinn::ServerConfig, quinn::ClientConfig), Error> {
+    let verifier = Arc::new(NodeKeyVerifier);
+    let server = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(other)?
+        .with_client_cert_verifier(verifier.clone())
+        .with_single_cert(vec![cert.clone()], key.clone())
+        .map_err(other)?;
+    let client = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(other)?
+        .with_custom_certificate_verifier(verifier)
+        .with_single_cert(vec![cert], key)
+        .map_err(other)?;
+    Ok((quinn::ServerConfig::with_crypto(Arc::new(server)), quinn::ClientConfig::new(Arc::new(client))))
+}
+
+fn other<E: std::fmt::Display>(e: E) -> Error {
+    Error::other(e.to_string())
+}
@@ -1,19 +1,67 @@
+use std::io::{Error, ErrorKind};
+
+use bech32::{FromBase32, ToBase32, Variant};
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
 use serde_json::{Map, Value};
 
+use crate::signature;
+
+// human readable part of the addresses shown to and typed by users
+pub const ADDRESS_PREFIX: &str = "iot";
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct AddressFormat{
     #[serde(with = "BigArray")]
     pub address: [u8; 33],
 }
 
+impl AddressFormat {
+    pub fn from_public_key(public_key: [u8; 33]) -> Self {
+        AddressFormat { address: public_key }
+    }
+
+    // bech32m of the whole public key, so the address maps back to the key the
+    // state and Type7 transfers use, and its checksum catches typos
+    pub fn encode(&self) -> String {
+        bech32::encode(ADDRESS_PREFIX, self.address.to_base32(), Variant::Bech32m).unwrap()
+    }
+
+    pub fn parse(address: &str) -> Result<Self, Error> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+        let (prefix, data, variant) = bech32::decode(address.trim())
+            .map_err(|e| invalid(&format!("Invalid address: {}", e)))?;
+        if prefix != ADDRESS_PREFIX || variant != Variant::Bech32m {
+            return Err(invalid("Address from another network"));
+        }
+        let public_key: [u8; 33] = Vec::<u8>::from_base32(&data)
+            .map_err(|e| invalid(&format!("Invalid address: {}", e)))?
+            .try_into()
+            .map_err(|_| invalid("Invalid address length"))?;
+        if !signature::is_valid_public_key(&public_key) {
+            return Err(invalid("Address is not a valid public key"));
+        }
+        Ok(AddressFormat::from_public_key(public_key))
+    }
+}
+
+pub fn encode_address(public_key: &[u8; 33]) -> String {
+    AddressFormat::from_public_key(*public_key).encode()
+}
+
+pub fn is_valid_address(address: &str) -> bool {
+    AddressFormat::parse(address).is_ok()
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Address {
     #[serde(with = "BigArray")]
     pub last_transaction: [u8; 64],
     pub balance: i64,
     pub linked_addresses: Vec<AddressFormat>,
+    // nonce of the last transaction applied for this address
+    #[serde(default)]
+    pub nonce: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -21,10 +69,36 @@ pub struct AddressesState {
     pub state: Map<String, Value>
 }
 
+impl Address {
+    pub fn new() -> Self {
+        Address {
+            last_transaction: [0; 64],
+            balance: 0,
+            linked_addresses: vec![],
+            nonce: 0,
+        }
+    }
+}
+
 impl AddressesState {
     pub fn new() -> Self {
         AddressesState {
             state: Map::new()
         }
     }
+
+    pub fn get_by_address(&self, address: &AddressFormat) -> Option<Address> {
+        self.get_address(&address.address)
+    }
+
+    pub fn get_address(&self, pk: &[u8]) -> Option<Address> {
+        self.state.get(&hex::encode(pk)).map(|value| {
+            serde_json::from_str(&value.as_str().unwrap().replace("//", "")).unwrap()
+        })
+    }
+
+    pub fn set_address(&mut self, pk: &[u8], address: Address) {
+        self.state.insert(hex::encode(pk),
+            Value::String(serde_json::to_string(&address).unwrap()));
+    }
 }
\ No newline at end of file
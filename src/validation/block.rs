@@ -1,19 +1,24 @@
+use std::collections::HashSet;
 use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use async_recursion::async_recursion;
-use lazy_static::__Deref;
+use cita_trie::{MemoryDB, PatriciaTrie};
+use lazy_static::lazy_static;
 use serde::{Serialize, Deserialize};
 use serde_big_array::BigArray;
-use serde_json::Value;
-use super::address::{Address, AddressesState};
+use tokio::sync::Mutex;
+use super::address::{Address, AddressesState, AddressFormat};
+use super::federation::{self, Federation, FederationShare};
 
-use crate::{storage::{merkle, self, keyvalue}, signature, communication::{transactions::{Transaction, DataTransactionType7, TransactionData}, neighbors::{self, Node, Neighbors}, messages::{Packet, Message}}, transport, INIT_BLOCKCHAIN};
+use crate::{storage::{merkle::{self, BodyFormat}, mempool, snapshot, self, keyvalue, hashing::{self, ChainHasher, HashAlgorithm}}, signature::{self, SignatureScheme}, communication::{transactions::{Transaction, TransactionData}, neighbors::Neighbors, messages::{Packet, Message}}, transport::{self, limits}, NodeContext, INIT_BLOCKCHAIN};
 
 #[derive(Serialize, Deserialize, Clone)]
 enum FederationSignature {
+    // single federated key of chains created before multisig federations
     #[serde(with = "BigArray")]
     Signed([u8; 64]),
     Unsigned,
+    Multisig(Vec<FederationShare>),
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -23,6 +28,8 @@ pub struct Block {
     issuer: [u8; 33],
     pub addresses_state: AddressesState,
     pub body: Vec<u8>,
+    #[serde(default)]
+    body_format: BodyFormat,
     timestamp: i64,
     pub previous_block_header: Vec<u8>,
     #[serde(with = "BigArray")]
@@ -31,114 +38,405 @@ pub struct Block {
 }
 
 impl Block {
-    pub async fn create_from_local_trie() -> Block {
+    pub async fn create_from_local_trie(ctx: &NodeContext) -> Result<Block, Error> {
         let timestamp = chrono::Utc::now().timestamp();
+        let issuer = ctx.signer.public_key()?;
+        let previous_block_header = storage::keyvalue::get(b"last_block_header")?.unwrap_or_default();
+        
+        let transactions: Vec<Transaction> = merkle::get_all().await.into_iter()
+            .map(|(_, value)| serde_json::from_slice(value.as_slice()))
+            .collect::<Result<_, _>>()?;
+        let (addresses_state, rejected) = Block::compute_addresses_state(
+            &previous_block_header, &transactions, &issuer)?;
+
+        // rejected transactions must not be part of the block body
+        for transaction in rejected {
+            merkle::remove(&transaction.signature).await;
+        }
         let (header, body) = merkle::get_local_trie().await;
-        let sk = storage::keyvalue::get(b"secret_key").unwrap().unwrap();
-        let issuer = signature::generate_public_key(&sk);
-        let previous_block_header = match storage::keyvalue::get(b"last_block_header").unwrap() {
-            Some(header) => header,
-            None => vec![],
+
+        let mut block = Block {
+            header,
+            issuer,
+            addresses_state,
+            body,
+            body_format: BodyFormat::Rlp,
+            timestamp,
+            previous_block_header,
+            issuer_signature: [0; 64],
+            federation_signature: FederationSignature::Unsigned,
         };
-        
-        let addresses_state = {
-        
-            let mut previous_state = match previous_block_header.len(){
-                0 => {AddressesState::new()},
-                _ => {
-                    let previous_block: Block = serde_json::from_slice(storage::keyvalue::get(previous_block_header.clone().as_slice()).unwrap().unwrap().as_slice()).unwrap();
-                    let previous_state = previous_block.addresses_state;
-                    previous_state
+        block.issuer_signature = ctx.signer.sign(&block.signed_payload())?;
+        Ok(block)
+    }
+
+    // the federation members sign the block in a round over the transport. A
+    // block of a chain with a federation is only valid once its threshold signed
+    pub async fn sign_by_federation(&mut self, ctx: &NodeContext) -> Result<(), Error> {
+        if let Some(federation) = Block::federation_of(&self.previous_block_header, &self.transactions()?) {
+            let shares = federation::sign_round(ctx, self, &federation).await?;
+            self.federation_signature = FederationSignature::Multisig(shares);
+        }
+        Ok(())
+    }
+
+    // payload signed by the issuer and the federation members. It commits to the
+    // previous block too, so a block can't be presented on top of another one,
+    // and to the addresses state, so a checkpoint can be trusted without the
+    // blocks before it
+    pub fn signed_payload(&self) -> Vec<u8> {
+        (self.timestamp.to_string()+&serde_json::to_string(&self.header).unwrap()
+            +&serde_json::to_string(&self.previous_block_header).unwrap()
+            +&serde_json::to_string(&self.addresses_state.state).unwrap()).into_bytes()
+    }
+
+    // the genesis block declares the federation in its Type6 transaction, later
+    // blocks use the one recorded when the genesis block was saved
+    fn federation_of(previous_block_header: &[u8], transactions: &[Transaction]) -> Option<Federation> {
+        if !previous_block_header.is_empty() {
+            return federation::chain_federation();
+        }
+        transactions.iter().find_map(|transaction| match &transaction.data {
+            TransactionData::Type6(data) => Some(data.federation()),
+            _ => None,
+        })
+    }
+
+    // share of this node for a block being signed by the federation, asked by
+    // `requester`. Only a block extending the local tip and issued by the issuer
+    // of the tip is signed, or a genesis block issued by the requester itself,
+    // and a single block per height
+    pub fn sign_share(&self, requester: &[u8]) -> Result<FederationShare, Error> {
+        let transactions = self.verify_issued()?;
+        let known_issuer = match Block::load(&self.previous_block_header)? {
+            Some(previous_block) => previous_block.issuer,
+            None if self.previous_block_header.is_empty() => requester.try_into()
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid requester public key"))?,
+            None => return Err(Error::new(ErrorKind::NotFound, "Previous block not found")),
+        };
+        if self.issuer != known_issuer {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Unknown block issuer"));
+        }
+        let federation = match Block::federation_of(&self.previous_block_header, &transactions) {
+            Some(federation) => federation,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Chain without federation")),
+        };
+        let share = federation::local_share(&federation, &self.signed_payload())
+            .ok_or_else(|| Error::new(ErrorKind::PermissionDenied, "Node is not a federation member"))?;
+        federation::record_signed(&self.previous_block_header, &self.header)?;
+        Ok(share)
+    }
+
+    // addresses state after the last block saved locally
+    pub fn current_addresses_state() -> AddressesState {
+        match keyvalue::get(b"last_block_header").unwrap() {
+            Some(header) => Block::previous_addresses_state(&header).unwrap(),
+            None => AddressesState::new(),
+        }
+    }
+
+    fn previous_addresses_state(previous_block_header: &[u8]) -> Result<AddressesState, Error> {
+        match previous_block_header.len() {
+            0 => Ok(AddressesState::new()),
+            _ => match Block::load(previous_block_header)? {
+                Some(previous_block) => Ok(previous_block.addresses_state),
+                None => Err(Error::new(ErrorKind::NotFound, "Previous block not found")),
+            }
+        }
+    }
+
+    // applies a single transaction over the addresses state, leaving the state
+    // untouched when the transaction is rejected
+    fn apply_transaction(state: &mut AddressesState, transaction: &Transaction, is_genesis: bool) -> Result<(), Error> {
+        let overflow = || Error::new(ErrorKind::InvalidData, "Balance overflow");
+
+        // nonces of a sender are used in sequence, so a transaction can't be
+        // applied twice and an older one can't be replayed once a newer one is in
+        let mut sender = state.get_address(&transaction.pk).unwrap_or_else(Address::new);
+        if transaction.nonce <= sender.nonce {
+            return Err(Error::new(ErrorKind::InvalidData, "Nonce already used"));
+        }
+        if transaction.nonce != sender.nonce + 1 {
+            return Err(Error::new(ErrorKind::InvalidData, "Nonce gap"));
+        }
+        sender.nonce = transaction.nonce;
+        let balance_variation = transaction.balance_variation().ok_or_else(overflow)?;
+        sender.balance = sender.balance.checked_add(balance_variation).ok_or_else(overflow)?;
+        sender.last_transaction = transaction.signature;
+        // fees and transfers can't be paid on credit
+        if sender.balance < 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "Insufficient balance"));
+        }
+
+        match &transaction.data {
+            TransactionData::Type6(_) if !is_genesis => {
+                return Err(Error::new(ErrorKind::InvalidData, "Type6 transaction outside genesis block"));
+            },
+            TransactionData::Type1(data) => {
+                data.verify()?;
+                if data.child_pk == transaction.pk {
+                    return Err(Error::new(ErrorKind::InvalidData, "An address can't be linked to itself"));
                 }
-            };
+                if !sender.linked_addresses.iter().any(|linked| linked.address == data.child_pk) {
+                    sender.linked_addresses.push(AddressFormat::from_public_key(data.child_pk));
+                }
+            },
+            TransactionData::Type7(data) => {
+                data.verify()?;
+                if data.recipient_pk == transaction.pk {
+                    return Err(Error::new(ErrorKind::InvalidData, "Recipient must differ from sender"));
+                }
+                let mut recipient = state.get_address(&data.recipient_pk).unwrap_or_else(Address::new);
+                recipient.balance = recipient.balance.checked_add(data.balance_variation).ok_or_else(overflow)?;
+                state.set_address(&data.recipient_pk, recipient);
+            },
+            _ => {}
+        }
 
-            let all_transactions = merkle::get_all().await;
-            for t in all_transactions {
-                let transaction: Transaction = serde_json::from_slice(t.1.as_slice()).unwrap();
-                let last_transaction = transaction.signature;
-                let mut balance: i64 = 0;
-                let mut linked_addresses = vec![];
-                if previous_state.state.contains_key(&hex::encode(transaction.pk)) {
-
-                    match transaction.data {
-                        TransactionData::Type7(data) => {
-                            let sent_address_pk = data.recipient_pk;
-                            let mut sent_address_current_balance = 0;
-                            let mut sent_address_linked_addresses = vec![];
-                            let mut sent_address_last_transaction: [u8; 64] = [0; 64];
-                            if previous_state.state.contains_key(&hex::encode(sent_address_pk)) {
-                                let found_address:Address = serde_json::from_str(previous_state.state.get(&hex::encode(sent_address_pk)).unwrap().as_str().unwrap()).unwrap();
-                                sent_address_last_transaction = found_address.last_transaction;
-                                sent_address_linked_addresses = found_address.linked_addresses;
-                                sent_address_current_balance = found_address.balance;
-                                
-                            }
-                            let sent_address = Address{
-                                last_transaction: sent_address_last_transaction,
-                                balance: sent_address_current_balance + data.balance_variation,
-                                linked_addresses: sent_address_linked_addresses,
-                            };
-                            previous_state.state.insert(hex::encode(sent_address_pk), Value::String(serde_json::to_string(&sent_address).unwrap()));
-                        },
-                        _ => {}
-                    }
+        state.set_address(&transaction.pk, sender);
+        Ok(())
+    }
 
-                    let value = previous_state.state.get(&hex::encode(transaction.pk)).unwrap()
-                    .to_owned().as_str().unwrap().replace("//", "");
-                    let found_address:Address = serde_json::from_str(&value).unwrap();
-                    balance = found_address.balance;
-                    linked_addresses = found_address.linked_addresses;
+    // applies the transactions of a block over the state of its previous block
+    fn compute_addresses_state(previous_block_header: &[u8], transactions: &[Transaction], issuer: &[u8; 33]) -> Result<(AddressesState, Vec<Transaction>), Error> {
+        let state = Block::previous_addresses_state(previous_block_header)?;
+        Block::apply_transactions(state, transactions, issuer, previous_block_header.is_empty())
+    }
+
+    // applies transactions over an addresses state, crediting the collected fees
+    // to the block issuer. Rejected transactions are returned alongside the
+    // resulting state
+    pub fn apply_transactions(mut state: AddressesState, transactions: &[Transaction], issuer: &[u8; 33], is_genesis: bool) -> Result<(AddressesState, Vec<Transaction>), Error> {
+        // transactions are applied in nonce order, not in trie order, so each
+        // sender's transactions are applied in the order they were issued
+        let mut transactions = transactions.to_vec();
+        transactions.sort_by_key(|transaction| transaction.nonce);
+
+        let mut collected_fees: i64 = 0;
+        let mut rejected = vec![];
+        for transaction in transactions.iter() {
+            let fees = match collected_fees.checked_add(transaction.fee) {
+                Some(fees) => fees,
+                None => {
+                    rejected.push(transaction.clone());
+                    continue;
                 }
+            };
+            match Block::apply_transaction(&mut state, transaction, is_genesis) {
+                Ok(()) => collected_fees = fees,
+                Err(_) => rejected.push(transaction.clone()),
+            }
+        }
+
+        if collected_fees > 0 {
+            let mut issuer_address = state.get_address(issuer).unwrap_or_else(Address::new);
+            issuer_address.balance = issuer_address.balance.checked_add(collected_fees)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Balance overflow"))?;
+            state.set_address(issuer, issuer_address);
+        }
+
+        Ok((state, rejected))
+    }
 
-                balance += transaction.balance_variation;
+    // pending transactions that can no longer be included on top of the last
+    // local block: stale nonces, and the ones a sender can't afford in nonce
+    // order. Transactions after a nonce gap stay queued until the gap is filled
+    pub fn invalid_pending_transactions(transactions: &[Transaction]) -> Result<Vec<Transaction>, Error> {
+        let last_block_header = keyvalue::get(b"last_block_header")?.unwrap_or_default();
+        let mut state = Block::previous_addresses_state(&last_block_header)?;
+        let mut transactions = transactions.to_vec();
+        transactions.sort_by_key(|transaction| transaction.nonce);
+
+        let mut gapped = HashSet::new();
+        let mut rejected = vec![];
+        for transaction in transactions {
+            if gapped.contains(&transaction.pk) {
+                continue;
+            }
+            let nonce = state.get_address(&transaction.pk).map_or(0, |address| address.nonce);
+            if transaction.nonce > nonce + 1 {
+                gapped.insert(transaction.pk);
+                continue;
+            }
+            if Block::apply_transaction(&mut state, &transaction, last_block_header.is_empty()).is_err() {
+                rejected.push(transaction);
+            }
+        }
+        Ok(rejected)
+    }
 
-                let new_address_data:Address = Address{
-                    balance,
-                    linked_addresses,
-                    last_transaction,
-                };
-                
-                previous_state.state.insert(hex::encode(transaction.pk), 
-                    Value::String(serde_json::to_string(&new_address_data).unwrap()));
+    // the genesis block declares the hash algorithm of the chain in its Type6
+    // transaction, and its body only decodes with that algorithm. Later blocks
+    // use the algorithm recorded when the genesis block was saved
+    pub fn hash_algorithm(&self) -> Result<HashAlgorithm, Error> {
+        if !self.previous_block_header.is_empty() {
+            return Ok(hashing::chain_algorithm());
+        }
+        for algorithm in HashAlgorithm::ALL {
+            let trie = match merkle::create_body_trie(self.body_format, &self.body, &self.header, algorithm) {
+                Ok(trie) => trie,
+                Err(_) => continue,
+            };
+            for (_, value) in trie.iter() {
+                let transaction: Transaction = serde_json::from_slice(value.as_slice())
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                if let TransactionData::Type6(data) = transaction.data {
+                    if data.hash_algorithm != algorithm {
+                        return Err(Error::new(ErrorKind::InvalidData, "Genesis block hash algorithm mismatch"));
+                    }
+                    return Ok(algorithm);
+                }
             }
+            return Err(Error::new(ErrorKind::InvalidData, "Genesis block without Type6 transaction"));
+        }
+        Err(Error::new(ErrorKind::InvalidData, "Block body does not match its header"))
+    }
+
+    fn evaluation_trie(&self) -> Result<PatriciaTrie<MemoryDB, ChainHasher>, Error> {
+        merkle::create_body_trie(self.body_format, &self.body, &self.header, self.hash_algorithm()?)
+    }
+
+    pub fn transactions(&self) -> Result<Vec<Transaction>, Error> {
+        let trie = self.evaluation_trie()?;
+        trie.iter()
+            .map(|(_, value)| serde_json::from_slice(value.as_slice())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)))
+            .collect()
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        let transactions = self.verify_issued()?;
+        self.verify_federation_signature(&transactions)
+    }
+
+    // everything but the federation signature, which members check before
+    // signing. Recomputes the addresses state from the block body instead of
+    // trusting the one sent by the issuer, and returns the decoded body
+    fn verify_issued(&self) -> Result<Vec<Transaction>, Error> {
+        self.verify_extends_tip()?;
+        signature::verify_signature(&self.signed_payload(), &self.issuer, &self.issuer_signature)?;
+        let transactions = self.transactions()?;
+        Transaction::verify_batch(&transactions)?;
+        let (expected_state, rejected) = Block::compute_addresses_state(
+            &self.previous_block_header, &transactions, &self.issuer)?;
+        if !rejected.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Block contains invalid transactions"));
+        }
+        if expected_state.state != self.addresses_state.state {
+            return Err(Error::new(ErrorKind::InvalidData, "Addresses state mismatch"));
+        }
+        Ok(transactions)
+    }
+
+    // fork choice: the first valid block seen at each height wins. The local
+    // chain is only ever extended, so a block must build on the local tip and
+    // competing blocks for a height that is already filled are dropped
+    fn verify_extends_tip(&self) -> Result<(), Error> {
+        let tip = keyvalue::get(b"last_block_header")?.unwrap_or_default();
+        if self.previous_block_header != tip {
+            return Err(Error::new(ErrorKind::InvalidData, "Block does not extend the local tip"));
+        }
+        Ok(())
+    }
 
-            previous_state
+    // blocks of a chain whose genesis Type6 defines a federation must carry the
+    // signature of its threshold
+    fn verify_federation_signature(&self, transactions: &[Transaction]) -> Result<(), Error> {
+        match (Block::federation_of(&self.previous_block_header, transactions), &self.federation_signature) {
+            (Some(federation), _) => self.verify_signed_by(&federation),
+            (None, FederationSignature::Unsigned) => Ok(()),
+            (None, _) => Err(Error::new(ErrorKind::InvalidData, "Federation signature without federation")),
+        }
+    }
+
+    fn verify_signed_by(&self, federation: &Federation) -> Result<(), Error> {
+        let shares = match &self.federation_signature {
+            FederationSignature::Unsigned => {
+                return Err(Error::new(ErrorKind::InvalidData, "Block not signed by the federation"));
+            },
+            FederationSignature::Signed(signature) => vec![FederationShare { member: 0, signature: *signature }],
+            FederationSignature::Multisig(shares) => shares.clone(),
         };
+        federation.verify(&self.signed_payload(), &shares)
+    }
+
+    // stored block, without its body. The header comes from peers too, so it
+    // may point at any stored value
+    fn load(header: &[u8]) -> Result<Option<Block>, Error> {
+        match keyvalue::get(header)? {
+            Some(block) => serde_json::from_slice(&block)
+                .map(Some)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "Stored value is not a block")),
+            None => Ok(None),
+        }
+    }
 
-        let issuer_signature = signature::new_signature(
-            (timestamp.to_string()+&serde_json::to_string(&header).unwrap()).as_bytes(), 
-            sk.as_slice());
-
-        let federation_signature = match storage::keyvalue::get(b"federated_secret_key").unwrap() {
-            Some(federated_sk) => FederationSignature::Signed(
-                signature::new_signature(
-                    (timestamp.to_string()+&serde_json::to_string(&header).unwrap()).as_bytes(), 
-                    federated_sk.as_slice())
-            ),
-            None => FederationSignature::Unsigned,
+    // blocks are saved under their header
+    pub fn is_saved(header: &[u8]) -> Result<bool, Error> {
+        Ok(keyvalue::get(header)?.is_some())
+    }
+
+    // stored block with its body rebuilt from the stored trie, as sent to peers
+    pub fn load_with_body(header: &[u8]) -> Result<Option<Block>, Error> {
+        let mut block = match Block::load(header)? {
+            Some(block) => block,
+            None => return Ok(None),
         };
+        if block.body.is_empty() {
+            block.body = merkle::stored_body(&block.header)?;
+            block.body_format = BodyFormat::Rlp;
+        }
+        Ok(Some(block))
+    }
 
-        Block {
-            header,
-            issuer,
-            addresses_state,
-            body,
-            timestamp,
-            previous_block_header,
-            issuer_signature,
-            federation_signature,
+    // verifies a block received from a peer and appends it to the local chain,
+    // under the sealing lock so the tip can't move in between
+    pub async fn accept(&self) -> Result<(), Error> {
+        let _sealing = SEALING.lock().await;
+        self.verify()?;
+        self.save_to_blockchain()?;
+        mempool::on_new_block(self).await;
+        Ok(())
+    }
+
+    // validators are told by the chain itself: the issuer of its blocks and the
+    // federation set up by its genesis block
+    pub fn is_validator(pk: &[u8]) -> bool {
+        if federation::chain_federation().is_some_and(|federation| federation.member_index(pk).is_some()) {
+            return true;
+        }
+        match keyvalue::get(b"last_block_header") {
+            Ok(Some(header)) => matches!(Block::load(&header), Ok(Some(block)) if block.issuer[..] == *pk),
+            _ => false,
         }
     }
 
-    pub fn save_to_blockchain(&self){
-        let value = serde_json::to_vec(&self).unwrap();
-        keyvalue::insert(self.header.as_slice(), value.as_slice()).unwrap();
-        keyvalue::insert(b"last_block_header", &self.header).unwrap();
+    // the body is kept once, as the nodes of the block trie, and the stored
+    // block only refers to it by its header
+    pub fn save_to_blockchain(&self) -> Result<(), Error> {
+        if self.previous_block_header.is_empty() {
+            hashing::set_chain_algorithm(self.hash_algorithm()?);
+            for transaction in self.transactions()? {
+                if let TransactionData::Type6(data) = transaction.data {
+                    signature::set_chain_scheme(data.signature_scheme);
+                    federation::set_chain_federation(&data.federation());
+                }
+            }
+        }
+        merkle::store_trie(&self.evaluation_trie()?, &self.header)?;
+        let stored = Block {
+            body: vec![],
+            body_format: BodyFormat::Rlp,
+            ..self.clone()
+        };
+        keyvalue::insert(&self.header, &serde_json::to_vec(&stored)?)?;
+        keyvalue::insert(b"last_block_header", &self.header)?;
+        limits::refresh_validators();
         Block::print_blockchain();
+        Ok(())
     }
 
     pub fn print_block(&self) {
-        let trie = merkle::create_evaluation_trie(self.body.clone(), self.header.clone());
         println!("HEADER: {:?}", self.header.clone());
         println!("ISSUER: {:?}", self.issuer);
         println!("TIMESTAMP: {:?}", self.timestamp);
@@ -149,93 +447,231 @@ impl Block {
         }
 
         println!("BODY: ");
-        for (key, value) in trie.iter() {
-            let t: Transaction = serde_json::from_slice(&value.as_slice()).unwrap();
-            println!("{}: {}", hex::encode(key), serde_json::to_string(&t).unwrap());
+        match self.transactions() {
+            Ok(transactions) => for t in transactions {
+                println!("{}: {}", hex::encode(t.signature), serde_json::to_string(&t).unwrap());
+            },
+            Err(e) => println!("Corpo indisponível: {}", e),
         }
         println!("-----------------------------------\n\n")
     }
 
     pub fn print_blockchain() {
         println!("\n\nPRINT FULL BLOCKCHAIN");
-        let mut header = keyvalue::get(b"last_block_header").unwrap().unwrap();
-        let mut block_serialized = keyvalue::get(&header).unwrap().unwrap();
-        let mut block: Block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
-        block.print_block();
-        while block.previous_block_header.len() > 0 {
-            header = block.previous_block_header.clone();
-            block_serialized = keyvalue::get(&header).unwrap().unwrap();
-            block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
+        let mut header = keyvalue::get(b"last_block_header").ok().flatten().unwrap_or_default();
+        while !header.is_empty() {
+            let mut block = match Block::load(&header) {
+                Ok(Some(block)) => block,
+                Ok(None) => return println!("Bloco não encontrado: {}", hex::encode(&header)),
+                Err(e) => return println!("Erro ao ler bloco {}: {}", hex::encode(&header), e),
+            };
+            // blocks are saved without their body, which is kept as trie nodes
+            if block.body.is_empty() {
+                block.body = merkle::stored_body(&header).unwrap_or_default();
+                block.body_format = BodyFormat::Rlp;
+            }
             block.print_block();
+            header = block.previous_block_header;
         }
     }
 
     pub fn search_transaction_in_blockchain(signature: &[u8]) -> Option<Transaction> {
+        let mut header = keyvalue::get(b"last_block_header").ok()??;
+        loop {
+            let block = Block::load(&header).ok()??;
 
-        let mut header = keyvalue::get(b"last_block_header").unwrap().unwrap();
-        let mut block_serialized = keyvalue::get(&header).unwrap().unwrap();
-        let mut block: Block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
-        let trie = merkle::create_evaluation_trie(block.body, block.header);
-        
-        while block.previous_block_header.len() > 0 && !trie.contains(signature).unwrap() {
-            header = block.previous_block_header.clone();
-            let mut block_serialized = keyvalue::get(&header).unwrap().unwrap();
-            let mut block: Block = serde_json::from_slice(&block_serialized.as_slice()).unwrap();
-            let trie = merkle::create_evaluation_trie(block.body, block.header);
-        }
-        if trie.contains(&signature).unwrap() {
-            Some(serde_json::from_slice(trie.get(signature).unwrap().unwrap().as_slice()).unwrap())
-        } else {
-            None
-        }
-    }
-
-    pub async fn send_block(self, peer: Option<String>) {
-        let packet = Packet::Message(Message::generate_with_block(4, self).await);
-        let serialized_packet = serde_json::to_string(&packet).unwrap();
-        match peer {
-            Some(addr) => transport::send(addr, serialized_packet.clone()).await.unwrap(),
-            None => {
-                let mut neighbors = Neighbors::restore();
-                for (addr, _) in neighbors.neighbors.iter() {
-                    transport::send(addr.to_string(), serialized_packet.clone()).await.unwrap();
-                }
+            // blocks saved before tries were persisted are read from their body
+            let value = match merkle::create_stored_trie(&block.header) {
+                Some(trie) => trie.get(signature).ok()?,
+                None if !block.body.is_empty() => block.evaluation_trie().ok()?.get(signature).ok()?,
+                None => None,
+            };
+            if let Some(value) = value {
+                return serde_json::from_slice(value.as_slice()).ok();
+            }
+
+            if block.previous_block_header.is_empty() {
+                return None;
+            }
+            header = block.previous_block_header;
+        }
+    }
+
+    pub async fn send_block(self, ctx: &NodeContext, peer: Option<String>) -> Result<(), Error> {
+        let packet = Packet::Message(Message::generate_with_block(ctx, 4, self)?);
+        let serialized_packet = serde_json::to_string(&packet)?;
+        let peers = match peer {
+            Some(addr) => vec![addr],
+            None => Neighbors::restore().neighbors.keys().cloned().collect(),
+        };
+        for addr in peers {
+            if let Err(e) = transport::send(ctx, addr.clone(), serialized_packet.clone()).await {
+                println!("Erro ao enviar bloco para {}: {}", addr, e);
             }
         }
+        Ok(())
     }
     
 }
 
+// a stored block and the chain parameters recorded with the genesis block, so
+// a node can continue the chain from a snapshot without the earlier blocks
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    block: Block,
+    pub hash_algorithm: HashAlgorithm,
+    signature_scheme: SignatureScheme,
+    federation: Option<Federation>,
+}
+
+impl Checkpoint {
+    pub fn of(header: &[u8]) -> Result<Option<Checkpoint>, Error> {
+        Ok(Block::load(header)?.map(|block| Checkpoint {
+            block,
+            hash_algorithm: hashing::chain_algorithm(),
+            signature_scheme: signature::chain_scheme(),
+            federation: federation::chain_federation(),
+        }))
+    }
+
+    // makes the block the local tip of a node without a chain, along with its
+    // addresses state and the nodes of its trie
+    pub fn install(&self, trie: &PatriciaTrie<MemoryDB, ChainHasher>, root: &[u8]) -> Result<(), Error> {
+        if self.block.header != root {
+            return Err(Error::new(ErrorKind::InvalidData, "Checkpoint block does not match the snapshot root"));
+        }
+        if keyvalue::get(b"last_block_header")?.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "Local blockchain already started"));
+        }
+        signature::verify_signature(&self.block.signed_payload(), &self.block.issuer, &self.block.issuer_signature)?;
+        if let Some(federation) = &self.federation {
+            self.block.verify_signed_by(federation)?;
+        }
+
+        hashing::set_chain_algorithm(self.hash_algorithm);
+        merkle::store_trie(trie, root)?;
+        signature::set_chain_scheme(self.signature_scheme);
+        if let Some(federation) = &self.federation {
+            federation::set_chain_federation(federation);
+        }
+        let stored = Block {
+            body: vec![],
+            body_format: BodyFormat::Rlp,
+            ..self.block.clone()
+        };
+        keyvalue::insert(&stored.header, &serde_json::to_vec(&stored)?)?;
+        keyvalue::insert(b"last_block_header", &stored.header)
+    }
+}
+
+// number of pending transactions that triggers the issuance of a new block
+const BLOCK_SIZE: usize = 10;
+
+lazy_static! {
+    // guards building and saving a local block, but not its signing round
+    static ref SEALING: Mutex<()> = Mutex::new(());
+}
+
+// set while a local block is being sealed, so only one is signed at a time
+static SIGNING: AtomicBool = AtomicBool::new(false);
+
+// clears SIGNING however sealing ends
+struct SigningGuard;
+
+impl Drop for SigningGuard {
+    fn drop(&mut self) {
+        SIGNING.store(false, Ordering::SeqCst);
+    }
+}
+
 pub struct LocalBlock {
 }
 
 impl LocalBlock {
-    pub async fn insert_transaction(transaction: Transaction) -> Result<(), Error> {
-        // TODO: verify transaction data in block
-        match signature::verify_signature((transaction.timestamp.to_string()+
-                                &serde_json::to_string(&transaction.data).unwrap()).as_bytes(), 
-            &transaction.pk, &transaction.signature) {
+    pub async fn insert_transaction(ctx: &NodeContext, transaction: Transaction) -> Result<(), Error> {
+        if let TransactionData::Type6(_) = transaction.data {
+            return Err(Error::new(ErrorKind::InvalidData, "Type6 transactions are only allowed in the genesis block"));
+        }
+        match transaction.verify() {
                 Ok(()) => {
                     println!("OK");
-                    merkle::insert(&transaction.signature, &serde_json::to_vec(&transaction).unwrap()).await;
-                    let mut t_n = merkle::LOCAL_BLOCK_SIZE.write().await;
-                    *t_n += 1;
-                    if *t_n == 10 {
+                    mempool::insert(transaction).await?;
+                    if mempool::len().await >= BLOCK_SIZE && INIT_BLOCKCHAIN.to_owned() {
                         println!("SENDING BLOCK AND RESETTING");
-                        if INIT_BLOCKCHAIN.to_owned() {
-                            let block = Block::create_from_local_trie().await;
-                            block.clone().save_to_blockchain();
-                            block.clone().send_block(None).await;
-
-                            *t_n = 0;
-                            merkle::reset_local_trie().await;
+                        if let Err(e) = LocalBlock::seal_block(ctx, true).await {
+                            println!("Erro ao selar bloco: {}", e);
                         }
                     }
                     Ok(())
                 },
                 Err(e) => {
                     println!("{}", e);
-                    Err(Error::new(ErrorKind::InvalidData, e))},
+                    Err(e)},
             }
     }
-}
\ No newline at end of file
+
+    // retains the trie of the last block under a name. Snapshots are taken and
+    // imported under the sealing lock, so the tip can't move in between
+    pub async fn create_snapshot(name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let _sealing = SEALING.lock().await;
+        match keyvalue::get(b"last_block_header")? {
+            Some(header) => {
+                snapshot::create(name, &header)?;
+                Ok(Some(header))
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub async fn import_snapshot(path: &str) -> Result<String, Error> {
+        let _sealing = SEALING.lock().await;
+        snapshot::import(path)
+    }
+
+    // builds, signs, saves and propagates a block made of transactions
+    // selected from the mempool
+    pub async fn seal(ctx: &NodeContext) -> Result<(), Error> {
+        LocalBlock::seal_block(ctx, false).await
+    }
+
+    // with `only_if_full`, the count is checked again under the lock, since
+    // concurrent handlers may have sealed the transactions already
+    async fn seal_block(ctx: &NodeContext, only_if_full: bool) -> Result<(), Error> {
+        if SIGNING.swap(true, Ordering::SeqCst) {
+            if only_if_full {
+                return Ok(());
+            }
+            return Err(Error::new(ErrorKind::WouldBlock, "A block is already being signed"));
+        }
+        let _signing = SigningGuard;
+
+        let mut block = {
+            let _sealing = SEALING.lock().await;
+            if only_if_full && mempool::len().await < BLOCK_SIZE {
+                return Ok(());
+            }
+            merkle::reset_local_trie().await;
+            let items = mempool::select(BLOCK_SIZE).await.into_iter()
+                .map(|transaction| (transaction.signature.to_vec(), serde_json::to_vec(&transaction).unwrap()))
+                .collect();
+            merkle::insert_batch(items).await;
+            Block::create_from_local_trie(ctx).await?
+        };
+
+        // the round waits on the other members, the lock is released meanwhile
+        // so the node keeps accepting transactions and blocks
+        block.sign_by_federation(ctx).await?;
+
+        {
+            let _sealing = SEALING.lock().await;
+            // a block received during the round may have taken the height
+            block.verify_extends_tip()?;
+            block.save_to_blockchain()?;
+            mempool::on_new_block(&block).await;
+            merkle::reset_local_trie().await;
+        }
+
+        block.send_block(ctx, None).await
+    }
+}
+
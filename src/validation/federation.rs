@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+
+use lazy_static::lazy_static;
+use serde::{Serialize, Deserialize};
+use serde_big_array::BigArray;
+use tokio::sync::Mutex;
+
+use crate::{storage::keyvalue, signature::{self, keystore, signer::{KeystoreSigner, Signer}}, communication::{neighbors::Neighbors, messages::{Packet, Message}}, transport, NodeContext};
+
+use super::block::Block;
+
+// m-of-n multisig over the federation members. Every validator holding a
+// share keeps it as the FEDERATION_IDENTITY of its keystore, and a block is
+// signed for the federation once `threshold` members have signed it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Federation {
+    pub threshold: usize,
+    pub members: Vec<Vec<u8>>,
+}
+
+// signature of a single member, identified by its index in `members`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FederationShare {
+    pub member: usize,
+    #[serde(with = "BigArray")]
+    pub signature: [u8; 64],
+}
+
+impl Federation {
+    pub fn new(threshold: usize, members: Vec<Vec<u8>>) -> Result<Self, Error> {
+        if threshold == 0 || threshold > members.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Threshold must be between 1 and the number of members"));
+        }
+        for (i, member) in members.iter().enumerate() {
+            if !signature::is_valid_public_key(member) {
+                return Err(Error::new(ErrorKind::InvalidInput, "Invalid member public key"));
+            }
+            if members[..i].contains(member) {
+                return Err(Error::new(ErrorKind::InvalidInput, "Duplicated member public key"));
+            }
+        }
+        Ok(Federation { threshold, members })
+    }
+
+    // parses "<threshold>:<pk hex>,<pk hex>,..."
+    pub fn from_arg(arg: &str) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, "Expected <threshold>:<public key>,<public key>,...");
+        let (threshold, members) = arg.split_once(':').ok_or_else(invalid)?;
+        let threshold = threshold.parse().map_err(|_| invalid())?;
+        let members = members.split(',')
+            .map(|member| hex::decode(member.trim()).map_err(|_| invalid()))
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+        Federation::new(threshold, members)
+    }
+
+    pub fn member_index(&self, public_key: &[u8]) -> Option<usize> {
+        self.members.iter().position(|member| member == public_key)
+    }
+
+    // shares signed by distinct members over `msg`, invalid ones are dropped
+    pub fn valid_shares(&self, msg: &[u8], shares: Vec<FederationShare>) -> Vec<FederationShare> {
+        let mut valid: Vec<FederationShare> = vec![];
+        for share in shares {
+            let member = match self.members.get(share.member) {
+                Some(member) => member,
+                None => continue,
+            };
+            if valid.iter().any(|v| v.member == share.member) {
+                continue;
+            }
+            if signature::verify_signature(msg, member, &share.signature).is_ok() {
+                valid.push(share);
+            }
+        }
+        valid
+    }
+
+    pub fn verify(&self, msg: &[u8], shares: &[FederationShare]) -> Result<(), Error> {
+        if self.valid_shares(msg, shares.to_vec()).len() < self.threshold {
+            return Err(Error::new(ErrorKind::InvalidData, "Not enough valid federation signatures"));
+        }
+        Ok(())
+    }
+}
+
+pub fn chain_federation() -> Option<Federation> {
+    keyvalue::get(b"federation").unwrap()
+        .map(|federation| serde_json::from_slice(&federation).unwrap())
+}
+
+pub fn set_chain_federation(federation: &Federation) {
+    keyvalue::insert(b"federation", &serde_json::to_vec(federation).unwrap()).unwrap();
+}
+
+// share of this node, when its keystore holds a member key
+pub fn local_share(federation: &Federation, msg: &[u8]) -> Option<FederationShare> {
+    let share_signer = KeystoreSigner::new(keystore::FEDERATION_IDENTITY);
+    let member = federation.member_index(&share_signer.public_key().ok()?)?;
+    let signature = share_signer.sign(msg).ok()?;
+    Some(FederationShare { member, signature })
+}
+
+lazy_static! {
+    // block header -> shares received during its signing round
+    static ref ROUNDS: Mutex<HashMap<Vec<u8>, Vec<FederationShare>>> = Mutex::new(HashMap::new());
+    // serializes checking and recording the blocks signed by this node
+    static ref SIGNED: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
+
+// records the block this node signs on top of `previous_block_header`. A member
+// signs a single block per height, so the federation can't sign competing blocks.
+// A block never committed may be replaced, as when its round failed and the
+// issuer sealed the height again
+pub fn record_signed(previous_block_header: &[u8], header: &[u8]) -> Result<(), Error> {
+    let _signed = SIGNED.lock().unwrap();
+    let key = format!("federation_signed:{}", hex::encode(previous_block_header));
+    match keyvalue::get(key.as_bytes())? {
+        Some(signed) if signed != header && Block::is_saved(&signed)? => {
+            Err(Error::new(ErrorKind::PermissionDenied, "Another block was already signed at this height"))
+        },
+        _ => keyvalue::insert(key.as_bytes(), header),
+    }
+}
+
+// called with the shares sent back by the members asked in `sign_round`
+pub async fn record_share(header: &[u8], share: FederationShare) {
+    if let Some(shares) = ROUNDS.lock().await.get_mut(header) {
+        shares.push(share);
+    }
+}
+
+// interactive signing round for a new block: the local share, if any, plus the
+// shares of the neighbors asked with MessageType8, until the threshold is met.
+// Fails when the threshold can't be reached
+pub async fn sign_round(ctx: &NodeContext, block: &Block, federation: &Federation) -> Result<Vec<FederationShare>, Error> {
+    let payload = block.signed_payload();
+    let mut shares: Vec<FederationShare> = local_share(federation, &payload).into_iter().collect();
+    let signed_locally = !shares.is_empty();
+
+    if shares.len() < federation.threshold {
+        ROUNDS.lock().await.insert(block.header.clone(), vec![]);
+        let request = serde_json::to_string(&Packet::Message(Message::generate_with_block(ctx, 8, block.clone())?))?;
+        for (addr, _) in Neighbors::restore().neighbors {
+            if let Err(e) = transport::send(ctx, addr.clone(), request.clone()).await {
+                println!("Erro ao solicitar assinatura federada de {}: {}", addr, e);
+            }
+            let received = ROUNDS.lock().await.get(&block.header).cloned().unwrap_or_default();
+            if federation.valid_shares(&payload, [shares.clone(), received].concat()).len() >= federation.threshold {
+                break;
+            }
+        }
+        let received = ROUNDS.lock().await.remove(&block.header).unwrap_or_default();
+        shares.extend(received);
+    }
+
+    let mut shares = federation.valid_shares(&payload, shares);
+    if shares.len() < federation.threshold {
+        return Err(Error::new(ErrorKind::PermissionDenied,
+            format!("Not enough federation signatures: {}/{}", shares.len(), federation.threshold)));
+    }
+    shares.truncate(federation.threshold);
+    // the height is only taken once the block can be committed
+    if signed_locally {
+        record_signed(&block.previous_block_header, &block.header)?;
+    }
+    Ok(shares)
+}
@@ -0,0 +1,52 @@
+use std::io::{Error, ErrorKind};
+
+use crate::communication::transactions::{Transaction, TransactionData};
+
+// value minted to the blockchain creator by the genesis Type6 transaction
+pub const GENESIS_SUPPLY: i64 = 1000000;
+
+// every started chunk of this many payload bytes costs one extra fee unit
+const FEE_BYTES_PER_UNIT: usize = 256;
+
+fn base_fee(data: &TransactionData) -> i64 {
+    match data {
+        TransactionData::Type1(_) => 1,
+        TransactionData::Type2(_) => 1,
+        TransactionData::Type6(_) => 0,
+        TransactionData::Type7(_) => 1,
+    }
+}
+
+// minimum fee accepted by validators, derived from the transaction type and
+// from the size of its serialized payload
+pub fn minimum_fee(data: &TransactionData) -> i64 {
+    match data {
+        TransactionData::Type6(_) => 0,
+        _ => {
+            let payload_size = serde_json::to_vec(data).unwrap().len();
+            let size_units = payload_size.div_ceil(FEE_BYTES_PER_UNIT);
+            base_fee(data) + size_units as i64
+        }
+    }
+}
+
+// balance variation applied to the sender address, computed by every node
+// instead of being trusted from the sender. None when the amounts overflow
+pub fn balance_variation(transaction: &Transaction) -> Option<i64> {
+    match &transaction.data {
+        TransactionData::Type6(_) => Some(GENESIS_SUPPLY),
+        TransactionData::Type7(data) => data.balance_variation
+            .checked_add(transaction.fee)
+            .and_then(i64::checked_neg),
+        _ => transaction.fee.checked_neg(),
+    }
+}
+
+pub fn verify(transaction: &Transaction) -> Result<(), Error> {
+    let minimum = minimum_fee(&transaction.data);
+    if transaction.fee < minimum {
+        return Err(Error::new(ErrorKind::InvalidData,
+            format!("Fee too low: {} (minimum {})", transaction.fee, minimum)));
+    }
+    Ok(())
+}
@@ -1,2 +1,4 @@
 pub mod block;
-mod address;
\ No newline at end of file
+pub mod federation;
+pub mod fee;
+pub mod address;
\ No newline at end of file